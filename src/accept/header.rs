@@ -4,6 +4,8 @@ use std::str::from_utf8;
 use tokio::io::AsyncReadExt;
 use tokio::time::Duration;
 
+use crate::accept::proxy::proxy_reader;
+use crate::accept::token::TokenVerifier;
 use crate::error::bad_data;
 use crate::error::ConnResult;
 use crate::error::ConnectionError;
@@ -23,8 +25,16 @@ pub struct ConnectionHeader {
     pub type_: ConnectionType,
     pub id: u64,
     pub name: String,
+    pub offset: usize,          // Position in the replay stream to start sending from, for readers.
+    pub player_id: Option<u64>, // Set if the connection sent a valid token.
+    pub tier: Option<String>,   // Delay tier to read with, for readers. Needs a valid token.
 }
 
+// Optional header extensions come after the name as "/key=value" pieces, e.g.
+// "G/1/name/offset=1234\0". We only recognize known keys, so names with slashes in legacy headers
+// keep their meaning.
+const EXTENSION_KEYS: &[&str] = &["offset", "token", "tier"];
+
 pub mod header_reader {
     use super::*;
 
@@ -61,25 +71,85 @@ pub mod header_reader {
         Ok((id, name))
     }
 
-    async fn read_connection_header(conn: &mut Connection) -> ConnResult<ConnectionHeader> {
+    fn split_extensions(mut name: &str) -> (&str, Vec<(&str, &str)>) {
+        let mut extensions = Vec::new();
+        while let Some((rest, ext)) = name.rsplit_once('/') {
+            match ext.split_once('=') {
+                Some((key, value)) if EXTENSION_KEYS.contains(&key) => extensions.push((key, value)),
+                _ => break,
+            }
+            name = rest;
+        }
+        (name, extensions)
+    }
+
+    // Returns the token, if any. It's verified once the whole header is read.
+    fn apply_extensions(header: &mut ConnectionHeader, extensions: Vec<(&str, &str)>) -> ConnResult<Option<String>> {
+        let mut token = None;
+        for (key, value) in extensions.into_iter() {
+            match key {
+                "offset" => {
+                    if header.type_ != ConnectionType::Reader {
+                        return Err(bad_data("Only readers can start from an offset"));
+                    }
+                    header.offset = value
+                        .parse::<usize>()
+                        .map_err(|_| bad_data("Failed to parse stream offset"))?;
+                }
+                "token" => token = Some(value.to_owned()),
+                "tier" => {
+                    if header.type_ != ConnectionType::Reader {
+                        return Err(bad_data("Only readers can pick a delay tier"));
+                    }
+                    header.tier = Some(value.to_owned());
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(token)
+    }
+
+    async fn read_connection_header(conn: &mut Connection) -> ConnResult<(ConnectionHeader, Option<String>)> {
         let type_ = read_type(conn).await.map_err(|e| match e {
             ConnectionError::IO(e) if e.kind() == ErrorKind::UnexpectedEof => ConnectionError::NoData,
             e => e,
         })?;
-        let (id, name) = read_game_data(conn).await?;
-        Ok(ConnectionHeader { type_, id, name })
+        let (id, full_name) = read_game_data(conn).await?;
+        let (name, extensions) = split_extensions(&full_name);
+        let mut header = ConnectionHeader {
+            type_,
+            id,
+            name: name.into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        };
+        let token = apply_extensions(&mut header, extensions)?;
+        Ok((header, token))
     }
 
     /* Cancellable. */
-    pub async fn read_and_set_connection_header(conn: &mut Connection) -> ConnResult<()> {
-        let header = read_connection_header(conn).await?;
+    pub async fn read_and_set_connection_header(conn: &mut Connection, tokens: &TokenVerifier) -> ConnResult<()> {
+        let (mut header, token) = read_connection_header(conn).await?;
+        header.player_id = tokens.verify(&header, token.as_deref())?;
         conn.set_header(header);
         Ok(())
     }
 }
 
-pub async fn read_initial_header(conn: &mut Connection, until: Duration) -> ConnResult<()> {
-    match timeout(header_reader::read_and_set_connection_header(conn), until).await {
+pub async fn read_initial_header(
+    conn: &mut Connection,
+    until: Duration,
+    proxy_protocol: bool,
+    tokens: &TokenVerifier,
+) -> ConnResult<()> {
+    let read_headers = async {
+        if proxy_protocol {
+            proxy_reader::read_and_set_proxy_header(conn).await?;
+        }
+        header_reader::read_and_set_connection_header(conn, tokens).await
+    };
+    match timeout(read_headers, until).await {
         Some(res) => res,
         None => Err(bad_data("Timed out while accepting connection")),
     }
@@ -103,11 +173,15 @@ mod test {
     async fn test_connection_header_type() {
         setup_logging();
         let mut c = conn_from_read_data(b"P/1/foo\0");
-        read_and_set_connection_header(&mut c).await.unwrap();
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
         assert!(c.get_header().type_ == ConnectionType::Writer);
 
         c = conn_from_read_data(b"G/1/foo\0");
-        read_and_set_connection_header(&mut c).await.unwrap();
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
         assert!(c.get_header().type_ == ConnectionType::Reader);
     }
 
@@ -115,7 +189,10 @@ mod test {
     async fn test_connection_header_invalid_type() {
         setup_logging();
         let mut c = conn_from_read_data(b"U/1/foo\0");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -124,7 +201,10 @@ mod test {
         for short_data in vec![b"" as &[u8], b"P"] {
             setup_logging();
             let mut c = conn_from_read_data(short_data);
-            let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+            let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+                .await
+                .err()
+                .unwrap();
             assert!(matches!(err, ConnectionError::NoData));
         }
     }
@@ -133,7 +213,9 @@ mod test {
     async fn test_connection_header_replay_info() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/1/foo\0");
-        read_and_set_connection_header(&mut c).await.unwrap();
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
         let h = c.get_header();
         assert!(h.id == 1);
         assert!(h.name == "foo");
@@ -143,7 +225,10 @@ mod test {
     async fn test_connection_header_replay_uid_not_int() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/bar/foo\0");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -151,7 +236,10 @@ mod test {
     async fn test_connection_header_replay_info_no_null_end() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/1/foo");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -159,7 +247,10 @@ mod test {
     async fn test_connection_header_replay_info_no_delimiter() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/1\0");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -167,18 +258,137 @@ mod test {
     async fn test_connection_header_replay_info_more_slashes() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/1/name/with/slash\0");
-        read_and_set_connection_header(&mut c).await.unwrap();
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
         let h = c.get_header();
         assert!(h.id == 1);
         assert!(h.name == "name/with/slash");
     }
 
+    #[tokio::test]
+    async fn test_connection_header_offset() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"G/1/foo/offset=1234\0");
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        let h = c.get_header();
+        assert!(h.name == "foo");
+        assert!(h.offset == 1234);
+
+        let mut c = conn_from_read_data(b"G/1/foo\0");
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        assert!(c.get_header().offset == 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_offset_name_with_slashes() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"G/1/name/with/slash/offset=10\0");
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        let h = c.get_header();
+        assert!(h.name == "name/with/slash");
+        assert!(h.offset == 10);
+
+        // Unknown keys are part of the name.
+        let mut c = conn_from_read_data(b"G/1/name/foo=10\0");
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        let h = c.get_header();
+        assert!(h.name == "name/foo=10");
+        assert!(h.offset == 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_invalid_offset() {
+        setup_logging();
+        for data in [
+            b"G/1/foo/offset=bar\0" as &[u8],
+            b"G/1/foo/offset=-1\0",
+            b"P/1/foo/offset=10\0",
+        ] {
+            let mut c = conn_from_read_data(data);
+            let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_token() {
+        setup_logging();
+        let tokens = TokenVerifier::new("secret", crate::config::TokenPolicy::Writers);
+        let header = ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        };
+        let token = tokens.issue_token(&header, 42, u64::MAX);
+        let data = format!("P/1/foo/token={}\0", token);
+        let mut c = conn_from_read_data(data.into_bytes().leak());
+        read_and_set_connection_header(&mut c, &tokens).await.unwrap();
+        let h = c.get_header();
+        assert!(h.name == "foo");
+        assert!(h.player_id == Some(42));
+
+        // Writers need a token, readers don't.
+        let mut c = conn_from_read_data(b"P/1/foo\0");
+        let err = read_and_set_connection_header(&mut c, &tokens).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::InvalidToken(..)));
+        let mut c = conn_from_read_data(b"G/1/foo\0");
+        read_and_set_connection_header(&mut c, &tokens).await.unwrap();
+        assert!(c.get_header().player_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connection_header_tier() {
+        setup_logging();
+        let tokens = TokenVerifier::new("secret", crate::config::TokenPolicy::Optional);
+        let header = ConnectionHeader {
+            type_: ConnectionType::Reader,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: Some("casters".into()),
+        };
+        let token = tokens.issue_token(&header, 42, u64::MAX);
+        let data = format!("G/1/foo/tier=casters/token={}\0", token);
+        let mut c = conn_from_read_data(data.into_bytes().leak());
+        read_and_set_connection_header(&mut c, &tokens).await.unwrap();
+        let h = c.get_header();
+        assert!(h.name == "foo");
+        assert!(h.tier.as_deref() == Some("casters"));
+        assert!(h.player_id == Some(42));
+
+        let mut c = conn_from_read_data(b"G/1/foo/tier=casters\0");
+        let err = read_and_set_connection_header(&mut c, &tokens).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::InvalidToken(..)));
+        let mut c = conn_from_read_data(b"P/1/foo/tier=casters\0");
+        let err = read_and_set_connection_header(&mut c, &tokens).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
     #[tokio::test]
     async fn test_connection_header_replay_info_invalid_unicode() {
         setup_logging();
         // Lonely start character is invalid unicode
         let mut c = conn_from_read_data(b"G/1/foo \xc0 bar\0");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -192,7 +402,10 @@ mod test {
         }
         data.extend(b"\0");
         let mut c = conn_from_read_data(data.leak());
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 
@@ -200,7 +413,10 @@ mod test {
     async fn test_connection_header_replay_info_negative_id() {
         setup_logging();
         let mut c = conn_from_read_data(b"G/-1/foo\0");
-        let err = read_and_set_connection_header(&mut c).await.err().unwrap();
+        let err = read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .err()
+            .unwrap();
         assert!(matches!(err, ConnectionError::BadData(..)));
     }
 }
@@ -1,2 +1,5 @@
 pub mod header;
 pub mod producer;
+pub mod proxy;
+pub mod token;
+pub mod writer_auth;
@@ -0,0 +1,266 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::from_utf8;
+
+use tokio::io::AsyncReadExt;
+
+use crate::error::bad_data;
+use crate::error::ConnResult;
+use crate::error::ConnectionError;
+use crate::server::connection::read_until_exact;
+use crate::server::connection::Connection;
+
+// Support for PROXY protocol, versions 1 and 2. See
+// https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt for the spec.
+//
+// When we're behind a proxy, every connection starts with a PROXY preamble that tells us who the
+// real peer is. We read it before the connection header and remember the peer address.
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: u64 = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+pub mod proxy_reader {
+    use super::*;
+
+    // Both versions' preambles are at least 12 bytes long, so we can always read that much.
+    async fn read_signature(conn: &mut Connection) -> ConnResult<[u8; 12]> {
+        let mut buf: [u8; 12] = [0; 12];
+        conn.read_exact(&mut buf).await.map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => ConnectionError::NoData,
+            _ => e.into(),
+        })?;
+        Ok(buf)
+    }
+
+    fn parse_v1_address(ip: &str, port: &str) -> ConnResult<SocketAddr> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| bad_data("Failed to parse PROXY v1 source address"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| bad_data("Failed to parse PROXY v1 source port"))?;
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    async fn read_v1(conn: &mut Connection, start: &[u8]) -> ConnResult<Option<SocketAddr>> {
+        let mut line = start.to_vec();
+        read_until_exact(&mut conn.take(V1_MAX_LEN - start.len() as u64), b'\n', &mut line)
+            .await
+            .map_err(|_| bad_data("PROXY v1 header is incomplete"))?;
+        if !line.ends_with(b"\r\n") {
+            return Err(bad_data("PROXY v1 header does not end with CRLF"));
+        }
+        let line = from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+            .map_err(|_| bad_data("PROXY v1 header is not valid text"))?;
+
+        let pieces: Vec<&str> = line.split(' ').collect();
+        match pieces[..] {
+            ["UNKNOWN", ..] => Ok(None),
+            ["TCP4", src, _dst, src_port, _dst_port] | ["TCP6", src, _dst, src_port, _dst_port] => {
+                parse_v1_address(src, src_port).map(Some)
+            }
+            _ => Err(bad_data(format!("Invalid PROXY v1 header: {}", line))),
+        }
+    }
+
+    async fn read_v2(conn: &mut Connection) -> ConnResult<Option<SocketAddr>> {
+        let version_and_command = conn.read_u8().await?;
+        let family_and_protocol = conn.read_u8().await?;
+        let len = conn.read_u16().await?;
+        let mut addresses = vec![0; len as usize];
+        conn.read_exact(&mut addresses).await?;
+
+        if version_and_command >> 4 != 2 {
+            return Err(bad_data(format!(
+                "Unsupported PROXY v2 version: {}",
+                version_and_command >> 4
+            )));
+        }
+        match version_and_command & 0xF {
+            0x0 => return Ok(None), // LOCAL, e.g. proxy health checks
+            0x1 => (),              // PROXY
+            c => return Err(bad_data(format!("Invalid PROXY v2 command: {}", c))),
+        }
+
+        let a = &addresses[..];
+        match family_and_protocol >> 4 {
+            0x1 if a.len() >= 12 => {
+                let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+                let port = u16::from_be_bytes([a[8], a[9]]);
+                Ok(Some(SocketAddr::new(ip.into(), port)))
+            }
+            0x2 if a.len() >= 36 => {
+                let mut ip: [u8; 16] = [0; 16];
+                ip.copy_from_slice(&a[0..16]);
+                let port = u16::from_be_bytes([a[32], a[33]]);
+                Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+            }
+            0x1 | 0x2 => Err(bad_data("PROXY v2 address block is too short")),
+            _ => Ok(None), // Unspecified or unix socket, nothing useful for us
+        }
+    }
+
+    async fn read_proxy_header(conn: &mut Connection) -> ConnResult<Option<SocketAddr>> {
+        let signature = read_signature(conn).await?;
+        if &signature == V2_SIGNATURE {
+            read_v2(conn).await
+        } else if signature.starts_with(V1_PREFIX) {
+            read_v1(conn, &signature).await
+        } else {
+            Err(bad_data("Connection does not start with a PROXY header"))
+        }
+    }
+
+    /* Cancellable. */
+    pub async fn read_and_set_proxy_header(conn: &mut Connection) -> ConnResult<()> {
+        if let Some(addr) = read_proxy_header(conn).await? {
+            conn.set_peer_addr(addr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::proxy_reader::read_and_set_proxy_header;
+    use super::*;
+    use crate::accept::header::header_reader::read_and_set_connection_header;
+    use crate::accept::token::TokenVerifier;
+    use crate::util::test::setup_logging;
+    use std::io::Cursor;
+    use tokio::io::BufReader;
+
+    fn conn_from_read_data(data: Vec<u8>) -> Connection {
+        let r = Box::new(BufReader::new(Cursor::new(data)));
+        Connection::new_from(r, Box::new(tokio::io::sink()))
+    }
+
+    fn v2_header(version_and_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(version_and_command);
+        data.push(family);
+        data.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        data.extend_from_slice(addresses);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_tcp4() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 15000\r\nG/1/foo\0".to_vec());
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("192.168.0.1:56324".parse().unwrap()));
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        assert_eq!(c.get_header().name, "foo");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_tcp6() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 15000\r\n".to_vec());
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_unknown() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"PROXY UNKNOWN\r\nG/1/foo\0".to_vec());
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), None);
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_invalid() {
+        setup_logging();
+        for data in [
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n" as &[u8],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 15000\n",
+            b"PROXY TCP4 banana 192.168.0.11 56324 15000\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 99999 15000\r\n",
+            b"PROXY FOO BAR\r\n",
+        ] {
+            let mut c = conn_from_read_data(data.to_vec());
+            let err = read_and_set_proxy_header(&mut c).await.err().unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v1_too_long() {
+        setup_logging();
+        let mut data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 15000".to_vec();
+        data.extend(vec![b' '; 100]);
+        data.extend(b"\r\n");
+        let mut c = conn_from_read_data(data);
+        let err = read_and_set_proxy_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_inet() {
+        setup_logging();
+        let mut data = v2_header(0x21, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x3A, 0x98]);
+        data.extend(b"P/1/foo\0");
+        let mut c = conn_from_read_data(data);
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("10.0.0.1:8080".parse().unwrap()));
+        read_and_set_connection_header(&mut c, &TokenVerifier::default())
+            .await
+            .unwrap();
+        assert_eq!(c.get_header().name, "foo");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_inet6() {
+        setup_logging();
+        let mut addresses = vec![0; 36];
+        addresses[15] = 1;
+        addresses[31] = 2;
+        addresses[32] = 0x1F;
+        addresses[33] = 0x90;
+        let mut c = conn_from_read_data(v2_header(0x21, 0x21, &addresses));
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), Some("[::1]:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_local() {
+        setup_logging();
+        let mut c = conn_from_read_data(v2_header(0x20, 0x00, &[]));
+        read_and_set_proxy_header(&mut c).await.unwrap();
+        assert_eq!(c.get_peer_addr(), None);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_v2_invalid() {
+        setup_logging();
+        for data in [
+            v2_header(0x11, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x3A, 0x98]),
+            v2_header(0x23, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x3A, 0x98]),
+            v2_header(0x21, 0x11, &[10, 0, 0, 1]),
+        ] {
+            let mut c = conn_from_read_data(data);
+            let err = read_and_set_proxy_header(&mut c).await.err().unwrap();
+            assert!(matches!(err, ConnectionError::BadData(..)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_header_missing() {
+        setup_logging();
+        let mut c = conn_from_read_data(b"G/1/foo\0 and some more".to_vec());
+        let err = read_and_set_proxy_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::BadData(..)));
+
+        let mut c = conn_from_read_data(b"G/1/foo\0".to_vec());
+        let err = read_and_set_proxy_header(&mut c).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::NoData));
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::accept::header::{ConnectionHeader, ConnectionType};
+use crate::config::{ServerSettings, TokenPolicy};
+use crate::error::{ConnResult, ConnectionError};
+
+// Tokens let a connection prove which player it belongs to, so that names can't be spoofed. They
+// are issued by whoever knows the secret (e.g. the lobby server) and sent in a header extension:
+//
+// "token=<player id>.<expiry>.<signature>"
+//
+// Expiry is a UNIX timestamp in seconds. Signature is a hex-encoded HMAC-SHA256 of
+// "<type>/<game id>/<player id>/<expiry>", where type is "P" or "G" like in the header itself.
+// Readers that pick a delay tier need a token, and its signature covers the tier as well:
+// "<type>/<game id>/<player id>/<expiry>/<tier>".
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn invalid_token(what: &str) -> ConnectionError {
+    ConnectionError::InvalidToken(what.into())
+}
+
+#[derive(Default)]
+pub struct TokenVerifier {
+    secret: Vec<u8>,
+    policy: TokenPolicy,
+}
+
+impl TokenVerifier {
+    pub fn new(secret: &str, policy: TokenPolicy) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+            policy,
+        }
+    }
+
+    pub fn from_config(config: &ServerSettings) -> Self {
+        Self::new(&config.token_secret, config.token_policy)
+    }
+
+    fn token_required(&self, type_: &ConnectionType) -> bool {
+        match self.policy {
+            TokenPolicy::Optional => false,
+            TokenPolicy::Writers => *type_ == ConnectionType::Writer,
+            TokenPolicy::Readers => *type_ == ConnectionType::Reader,
+            TokenPolicy::All => true,
+        }
+    }
+
+    fn signature(&self, header: &ConnectionHeader, player_id: u64, expiry: u64) -> HmacSha256 {
+        let type_ = match header.type_ {
+            ConnectionType::Writer => "P",
+            ConnectionType::Reader => "G",
+        };
+        let mut message = format!("{}/{}/{}/{}", type_, header.id, player_id, expiry);
+        if let Some(tier) = header.tier.as_ref() {
+            message = format!("{}/{}", message, tier);
+        }
+        hmac_sha256(&self.secret, message.as_bytes())
+    }
+
+    #[cfg(test)]
+    pub fn issue_token(&self, header: &ConnectionHeader, player_id: u64, expiry: u64) -> String {
+        let signature = hex::encode(self.signature(header, player_id, expiry).finalize().into_bytes());
+        format!("{}.{}.{}", player_id, expiry, signature)
+    }
+
+    // Returns the ID of the player the token was issued to, if there was a token.
+    pub fn verify(&self, header: &ConnectionHeader, token: Option<&str>) -> ConnResult<Option<u64>> {
+        self.verify_at(header, token, unix_time_now())
+    }
+
+    fn verify_at(&self, header: &ConnectionHeader, token: Option<&str>, now: u64) -> ConnResult<Option<u64>> {
+        let token = match token {
+            Some(t) => t,
+            None if self.token_required(&header.type_) => return Err(invalid_token("Token is missing")),
+            None if header.tier.is_some() => return Err(invalid_token("Delay tier needs a token")),
+            None => return Ok(None),
+        };
+        // Without a secret there's nothing to check against. Treat the connection as a legacy one.
+        if self.secret.is_empty() {
+            if header.tier.is_some() {
+                return Err(invalid_token("Cannot verify delay tier without a token secret"));
+            }
+            return Ok(None);
+        }
+
+        let pieces: Vec<&str> = token.split('.').collect();
+        if pieces.len() != 3 {
+            return Err(invalid_token("Malformed token"));
+        }
+        let player_id = pieces[0]
+            .parse::<u64>()
+            .map_err(|_| invalid_token("Failed to parse token player ID"))?;
+        let expiry = pieces[1]
+            .parse::<u64>()
+            .map_err(|_| invalid_token("Failed to parse token expiry"))?;
+        let signature = hex::decode(pieces[2]).map_err(|_| invalid_token("Failed to decode token signature"))?;
+
+        // Compares in constant time, so we don't give away how much of the signature was right.
+        self.signature(header, player_id, expiry)
+            .verify(&signature)
+            .map_err(|_| invalid_token("Token signature does not match"))?;
+        if expiry < now {
+            return Err(invalid_token("Token expired"));
+        }
+        Ok(Some(player_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(type_: ConnectionType, id: u64) -> ConnectionHeader {
+        ConnectionHeader {
+            type_,
+            id,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test vectors from RFC 4231.
+        assert_eq!(
+            hex::encode(
+                hmac_sha256(b"Jefe", b"what do ya want for nothing?")
+                    .finalize()
+                    .into_bytes()
+            ),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(
+                hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")
+                    .finalize()
+                    .into_bytes()
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_token_accepted() {
+        let verifier = TokenVerifier::new("secret", TokenPolicy::Writers);
+        let h = header(ConnectionType::Writer, 1);
+        let token = verifier.issue_token(&h, 42, 1000);
+        assert_eq!(verifier.verify_at(&h, Some(&token), 999).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_token_rejected() {
+        let verifier = TokenVerifier::new("secret", TokenPolicy::Optional);
+        let writer = header(ConnectionType::Writer, 1);
+        let token = verifier.issue_token(&writer, 42, 1000);
+        let other_key = TokenVerifier::new("other", TokenPolicy::Optional).issue_token(&writer, 42, 1000);
+        let other_id = verifier.issue_token(&header(ConnectionType::Writer, 2), 42, 1000);
+        let other_type = verifier.issue_token(&header(ConnectionType::Reader, 1), 42, 1000);
+        let other_player = token.replacen("42", "43", 1);
+
+        for bad_token in [
+            &other_key,
+            &other_id,
+            &other_type,
+            &other_player,
+            "42.1000",
+            "42.1000.xyz",
+            "foo.1000.00",
+        ] {
+            let err = verifier.verify_at(&writer, Some(bad_token), 999).err().unwrap();
+            assert!(matches!(err, ConnectionError::InvalidToken(..)));
+        }
+        let err = verifier.verify_at(&writer, Some(&token), 1001).err().unwrap();
+        assert!(matches!(err, ConnectionError::InvalidToken(..)));
+    }
+
+    #[test]
+    fn test_token_covers_delay_tier() {
+        let verifier = TokenVerifier::new("secret", TokenPolicy::Optional);
+        let mut casters = header(ConnectionType::Reader, 1);
+        casters.tier = Some("casters".into());
+        let token = verifier.issue_token(&casters, 42, 1000);
+        assert_eq!(verifier.verify_at(&casters, Some(&token), 999).unwrap(), Some(42));
+
+        let mut referees = casters.clone();
+        referees.tier = Some("referees".into());
+        let public = header(ConnectionType::Reader, 1);
+        let public_token = verifier.issue_token(&public, 42, 1000);
+        for (h, token) in [
+            (&referees, Some(&token[..])),
+            (&public, Some(&token[..])),
+            (&casters, Some(&public_token[..])),
+            (&casters, None),
+        ] {
+            let err = verifier.verify_at(h, token, 999).err().unwrap();
+            assert!(matches!(err, ConnectionError::InvalidToken(..)));
+        }
+        // Without a secret, we can't tell who can use a tier.
+        let err = TokenVerifier::default()
+            .verify_at(&casters, Some(&token), 999)
+            .err()
+            .unwrap();
+        assert!(matches!(err, ConnectionError::InvalidToken(..)));
+    }
+
+    #[test]
+    fn test_token_policy() {
+        let writer = header(ConnectionType::Writer, 1);
+        let reader = header(ConnectionType::Reader, 1);
+        let check = |policy, h: &ConnectionHeader| TokenVerifier::new("secret", policy).verify_at(h, None, 0).is_ok();
+
+        assert!(check(TokenPolicy::Optional, &writer));
+        assert!(check(TokenPolicy::Optional, &reader));
+        assert!(!check(TokenPolicy::Writers, &writer));
+        assert!(check(TokenPolicy::Writers, &reader));
+        assert!(check(TokenPolicy::Readers, &writer));
+        assert!(!check(TokenPolicy::Readers, &reader));
+        assert!(!check(TokenPolicy::All, &writer));
+        assert!(!check(TokenPolicy::All, &reader));
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use crate::accept::header::{ConnectionHeader, ConnectionType};
+use crate::database::database::Database;
+use crate::error::{ConnResult, ConnectionError};
+
+// Checks that writers are who they say they are, or at least that they claim to be someone who
+// plays the game. We only accept writers whose name is a login of a player in a running game.
+pub struct WriterAuthenticator {
+    db: Arc<Database>,
+}
+
+impl WriterAuthenticator {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self { db }
+    }
+
+    async fn is_player_in_running_game(&self, id: u64, name: &str) -> bool {
+        match self.db.is_game_running(id).await {
+            Ok(true) => (),
+            Ok(false) => return false,
+            Err(e) => {
+                log::warn!("Failed to check if game {} is running: {}", id, e);
+                return false;
+            }
+        }
+        match self.db.get_team_players(id).await {
+            Ok(players) => players.iter().any(|p| p.login == name),
+            Err(e) => {
+                log::warn!("Failed to fetch players of game {}: {}", id, e);
+                false
+            }
+        }
+    }
+
+    /* Cancellable. */
+    pub async fn authenticate(&self, header: &ConnectionHeader) -> ConnResult<()> {
+        if header.type_ != ConnectionType::Writer {
+            return Ok(());
+        }
+        if self.is_player_in_running_game(header.id, &header.name).await {
+            Ok(())
+        } else {
+            Err(ConnectionError::UnauthorizedWriter)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::database::test::mock_database;
+    use crate::util::test::setup_logging;
+
+    fn header(type_: ConnectionType, name: &str) -> ConnectionHeader {
+        ConnectionHeader {
+            type_,
+            id: 1,
+            name: name.into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        }
+    }
+
+    fn authenticator(running: bool) -> WriterAuthenticator {
+        let mut db = mock_database();
+        faux::when!(db.is_game_running).then(move |_id| Ok(running));
+        WriterAuthenticator::new(Arc::new(db))
+    }
+
+    #[tokio::test]
+    async fn test_writer_auth_accepts_players() {
+        setup_logging();
+        let auth = authenticator(true);
+        let h = header(ConnectionType::Writer, "user2");
+        auth.authenticate(&h).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writer_auth_rejects_strangers() {
+        setup_logging();
+        let auth = authenticator(true);
+        let h = header(ConnectionType::Writer, "not_a_player");
+        let err = auth.authenticate(&h).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::UnauthorizedWriter));
+    }
+
+    #[tokio::test]
+    async fn test_writer_auth_rejects_games_not_running() {
+        setup_logging();
+        let auth = authenticator(false);
+        let h = header(ConnectionType::Writer, "user2");
+        let err = auth.authenticate(&h).await.err().unwrap();
+        assert!(matches!(err, ConnectionError::UnauthorizedWriter));
+    }
+
+    #[tokio::test]
+    async fn test_writer_auth_ignores_readers() {
+        setup_logging();
+        let auth = authenticator(false);
+        let h = header(ConnectionType::Reader, "not_a_player");
+        auth.authenticate(&h).await.unwrap();
+    }
+}
@@ -28,6 +28,16 @@ mod float_to_duration {
     }
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPolicy {
+    #[default]
+    Optional,
+    Writers,
+    Readers,
+    All,
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct ServerSettings {
     pub port: u16,
@@ -35,6 +45,14 @@ pub struct ServerSettings {
     pub worker_threads: u32,
     #[serde(with = "float_to_duration")]
     pub connection_accept_timeout_s: Duration,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub authenticate_writers: bool,
+    #[serde(default)]
+    pub token_policy: TokenPolicy,
+    #[serde(default)]
+    pub token_secret: String,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -51,6 +69,95 @@ pub struct DatabaseSettings {
 pub struct StorageSettings {
     pub vault_path: String,
     pub compression_level: u32,
+    #[serde(default)]
+    pub divergences_in_json_header: bool,
+    #[serde(default)]
+    pub capture_diverged_streams: bool,
+    #[serde(default = "default_max_diverged_streams")]
+    pub max_diverged_streams: usize,
+    #[serde(default)]
+    pub existing_replay_policy: ExistingReplayPolicy,
+    #[serde(with = "float_to_duration", default = "default_pending_retry_interval")]
+    pub pending_retry_interval_s: Duration,
+    #[serde(with = "float_to_duration", default = "default_pending_retry_max_interval")]
+    pub pending_retry_max_interval_s: Duration,
+    #[serde(default = "default_pending_max_failures")]
+    pub pending_max_failures: u32,
+    #[serde(with = "float_to_duration", default = "default_live_spool_interval")]
+    pub live_spool_interval_s: Duration,
+    #[serde(default)]
+    pub json_header_format: JsonHeaderFormat,
+    #[serde(default)]
+    pub checksums_in_database: bool,
+    #[serde(default)]
+    pub game_summary: bool,
+}
+
+fn default_max_diverged_streams() -> usize {
+    1000
+}
+
+fn default_pending_retry_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_pending_retry_max_interval() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_pending_max_failures() -> u32 {
+    10
+}
+
+fn default_live_spool_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+// What to do when saving a replay with the same ID as one that's already saved.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExistingReplayPolicy {
+    #[default]
+    Keep,
+    Overwrite,
+    Larger,
+    Alongside,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonHeaderFormat {
+    #[default]
+    V2,
+    V3,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayMode {
+    #[default]
+    WallClock,
+    GameTicks,
+}
+
+fn default_delay_ticks() -> u32 {
+    3000
+}
+
+// A named delay for readers that are allowed to see the game sooner, e.g. casters.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct DelayTierSettings {
+    pub name: String,
+    #[serde(with = "float_to_duration")]
+    pub delay_s: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategyKind {
+    #[default]
+    Quorum,
+    LongestWriter,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -61,10 +168,76 @@ pub struct ReplaySettings {
     pub time_with_zero_writers_to_end_replay_s: Duration,
     #[serde(with = "float_to_duration")]
     pub delay_s: Duration,
+    #[serde(default)]
+    pub delay_mode: DelayMode,
+    #[serde(default = "default_delay_ticks")]
+    pub delay_ticks: u32,
+    #[serde(default)]
+    pub delay_tiers: Vec<DelayTierSettings>,
     #[serde(with = "float_to_duration")]
     pub update_interval_s: Duration,
+    #[serde(default)]
+    pub merge_strategy: MergeStrategyKind,
     pub merge_quorum_size: usize,
     pub stream_comparison_distance_b: usize,
+    #[serde(default)]
+    pub memory_budget_per_replay_b: usize,
+    #[serde(default)]
+    pub memory_budget_b: usize,
+    #[serde(default)]
+    pub seek_index_interval_ticks: u32,
+}
+
+// Where to send events about saved replays. Each target is disabled if left empty.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct NotifySettings {
+    #[serde(default)]
+    pub webhook_url: String,
+    #[serde(default)]
+    pub amqp_url: String,
+    #[serde(default)]
+    pub amqp_exchange: String,
+    #[serde(default = "default_amqp_routing_key")]
+    pub amqp_routing_key: String,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default = "default_notify_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(with = "float_to_duration", default = "default_notify_retry_interval")]
+    pub retry_interval_s: Duration,
+    #[serde(with = "float_to_duration", default = "default_notify_timeout")]
+    pub timeout_s: Duration,
+}
+
+fn default_amqp_routing_key() -> String {
+    "replay.saved".into()
+}
+
+fn default_notify_max_attempts() -> u32 {
+    3
+}
+
+fn default_notify_retry_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_notify_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            webhook_url: String::new(),
+            amqp_url: String::new(),
+            amqp_exchange: String::new(),
+            amqp_routing_key: default_amqp_routing_key(),
+            command: Vec::new(),
+            max_attempts: default_notify_max_attempts(),
+            retry_interval_s: default_notify_retry_interval(),
+            timeout_s: default_notify_timeout(),
+        }
+    }
 }
 
 pub type Settings = Arc<InnerSettings>;
@@ -75,6 +248,8 @@ pub struct InnerSettings {
     pub database: DatabaseSettings,
     pub storage: StorageSettings,
     pub replay: ReplaySettings,
+    #[serde(default)]
+    pub notify: NotifySettings,
 }
 
 impl InnerSettings {
@@ -95,7 +270,13 @@ impl InnerSettings {
         let mut c = Config::new();
         c.set("database.password", db_password)?;
         c.merge(File::with_name(&config_file[..]))?;
-        c.try_into()
+        let settings: Self = c.try_into()?;
+        if settings.server.token_policy != TokenPolicy::Optional && settings.server.token_secret.is_empty() {
+            return Err(ConfigError::Message(
+                "Tokens are required, but token secret is not set".into(),
+            ));
+        }
+        Ok(settings)
     }
 }
 
@@ -112,6 +293,10 @@ pub mod test {
                 prometheus_port: 8001,
                 worker_threads: 8,
                 connection_accept_timeout_s: Duration::from_secs(7200),
+                proxy_protocol: false,
+                authenticate_writers: false,
+                token_policy: TokenPolicy::Optional,
+                token_secret: "".into(),
             },
             database: DatabaseSettings {
                 pool_size: 8,
@@ -124,15 +309,34 @@ pub mod test {
             storage: StorageSettings {
                 vault_path: "/tmp/foo".into(),
                 compression_level: 10,
+                divergences_in_json_header: false,
+                capture_diverged_streams: false,
+                max_diverged_streams: 1000,
+                existing_replay_policy: ExistingReplayPolicy::Keep,
+                pending_retry_interval_s: Duration::from_secs(10),
+                pending_retry_max_interval_s: Duration::from_secs(600),
+                pending_max_failures: 10,
+                live_spool_interval_s: Duration::from_secs(10),
+                json_header_format: JsonHeaderFormat::V2,
+                checksums_in_database: false,
+                game_summary: false,
             },
             replay: ReplaySettings {
                 forced_timeout_s: Duration::from_secs(3600 * 6),
                 time_with_zero_writers_to_end_replay_s: Duration::from_secs(10),
                 delay_s: Duration::from_secs(60 * 5),
+                delay_mode: DelayMode::WallClock,
+                delay_ticks: 3000,
+                delay_tiers: Vec::new(),
                 update_interval_s: Duration::from_secs(1),
+                merge_strategy: MergeStrategyKind::Quorum,
                 merge_quorum_size: 2,
                 stream_comparison_distance_b: 4096,
+                memory_budget_per_replay_b: 0,
+                memory_budget_b: 0,
+                seek_index_interval_ticks: 0,
             },
+            notify: NotifySettings::default(),
         }
     }
 
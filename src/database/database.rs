@@ -16,6 +16,14 @@ pub struct TeamPlayerRow {
     pub team: i8,
 }
 
+#[derive(sqlx::FromRow, Debug, PartialEq, Eq)]
+pub struct GamePlayerRow {
+    pub player_id: u64,
+    pub login: String,
+    pub team: i8,
+    pub army: i64, // Start spot.
+}
+
 #[derive(sqlx::FromRow, Debug, PartialEq, Eq)]
 pub struct GameStatRow {
     pub start_time: OffsetDateTime,
@@ -81,6 +89,26 @@ impl Database {
         .await?)
     }
 
+    pub async fn get_game_players(&self, id: u64) -> Result<Vec<GamePlayerRow>, SaveError> {
+        Ok(sqlx::query_as!(
+            GamePlayerRow,
+            "
+            SELECT
+                CAST(`game_player_stats`.`playerId` AS UNSIGNED) AS player_id,
+                `login`.`login` AS login,
+                `game_player_stats`.`team` AS team,
+                CAST(`game_player_stats`.`place` AS SIGNED) AS army
+            FROM `game_player_stats`
+            INNER JOIN `login`
+              ON `login`.id = `game_player_stats`.`playerId`
+            WHERE `game_player_stats`.`gameId` = ? AND `game_player_stats`.`AI` = 0
+            ",
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
     pub async fn get_game_stat_row(&self, id: u64) -> Result<GameStatRow, SaveError> {
         // TODO is table_map obsolete? Gotta ask.
         Ok(sqlx::query_as!(
@@ -123,6 +151,22 @@ impl Database {
         .count)
     }
 
+    // A game is running if it started and has no end time yet.
+    pub async fn is_game_running(&self, id: u64) -> Result<bool, SaveError> {
+        let count = sqlx::query_as!(
+            PlayerCount,
+            "
+            SELECT COUNT(*) AS count FROM `game_stats`
+            WHERE `game_stats`.`id` = ? AND `game_stats`.`endTime` IS NULL
+            ",
+            id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .count;
+        Ok(count > 0)
+    }
+
     pub async fn get_mod_version_list(&self, game_mod: &str) -> Result<Vec<ModVersions>, SaveError> {
         // We have to build table name dynamically, that's just how the DB is.
         // Since we know what existing tables look like, we do very restrictive validation.
@@ -187,6 +231,29 @@ impl Database {
         res?;
         Ok(())
     }
+
+    // Needs replay_body_sha256 and replay_file_sha256 columns in game_stats, so it's optional.
+    pub async fn update_replay_checksums(
+        &self,
+        id: u64,
+        body_sha256: &str,
+        file_sha256: &str,
+    ) -> Result<(), SaveError> {
+        sqlx::query!(
+            "
+            UPDATE `game_stats` SET
+                `game_stats`.`replay_body_sha256` = ?,
+                `game_stats`.`replay_file_sha256` = ?
+            WHERE `game_stats`.`id` = ?
+            ",
+            body_sha256,
+            file_sha256,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -276,6 +343,23 @@ pub mod test {
         assert_eq!(players_to_map(players), players_to_map(expected_players));
     }
 
+    #[cfg_attr(not(feature = "local_db_tests"), ignore)]
+    #[tokio::test]
+    async fn test_db_game_players() {
+        let db = get_db();
+        let mut players = db.get_game_players(1010).await.unwrap();
+        players.sort_by_key(|p| p.army);
+        assert_eq!(
+            players,
+            vec![GamePlayerRow {
+                player_id: 1,
+                login: "user1".into(),
+                team: 1,
+                army: 1,
+            }]
+        );
+    }
+
     #[cfg_attr(not(feature = "local_db_tests"), ignore)]
     #[tokio::test]
     async fn test_db_players_with_ai() {
@@ -294,6 +378,15 @@ pub mod test {
         assert_eq!(players_to_map(players), players_to_map(expected_players));
     }
 
+    #[cfg_attr(not(feature = "local_db_tests"), ignore)]
+    #[tokio::test]
+    async fn test_db_game_running() {
+        let db = get_db();
+        assert!(db.is_game_running(1050).await.unwrap());
+        assert!(!db.is_game_running(1000).await.unwrap());
+        assert!(!db.is_game_running(9999).await.unwrap());
+    }
+
     #[cfg_attr(not(feature = "local_db_tests"), ignore)]
     #[tokio::test]
     async fn test_db_game_on_old_map_version() {
@@ -432,7 +525,7 @@ pub mod test {
         db.update_game_stats(1000, None, false).await.unwrap();
         // TODO fetch from db. Above at least verifies that sql is valid.
     }
-    fn default_game_stats() -> GameStatRow {
+    pub fn default_game_stats() -> GameStatRow {
         GameStatRow {
             start_time: dt(date!(2010 - 01 - 01), time!(00:00:00)),
             end_time: Some(dt(date!(2010 - 01 - 01), time!(01:00:00))),
@@ -467,6 +560,16 @@ pub mod test {
                 },
             ])
         });
+        faux::when!(mock_db.get_game_players).then(|_id| {
+            Ok((1..=4)
+                .map(|i| GamePlayerRow {
+                    player_id: i,
+                    login: format!("user{}", i),
+                    team: if i <= 2 { 1 } else { 2 },
+                    army: i as i64,
+                })
+                .collect())
+        });
         faux::when!(mock_db.get_player_count).then(|_id| Ok(4));
         faux::when!(mock_db.get_mod_version_list).then(|_| {
             Ok(vec![
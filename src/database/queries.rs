@@ -1,5 +1,5 @@
 use sqlx::types::time::OffsetDateTime;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::error::SaveError;
 
@@ -18,12 +18,20 @@ pub struct GameStats {
 }
 pub type ModVersions = HashMap<String, i32>;
 
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct GamePlayer {
+    pub id: u64,
+    pub login: String,
+    pub team: i8,
+    pub army: i64,
+}
+
 pub struct Queries {
-    db: Database,
+    db: Arc<Database>,
 }
 
 impl Queries {
-    pub fn new(db: Database) -> Self {
+    pub fn new(db: Arc<Database>) -> Self {
         Self { db }
     }
 
@@ -37,6 +45,24 @@ impl Queries {
         Ok(res)
     }
 
+    // Ordered by army.
+    pub async fn get_players_in_game(&self, id: u64) -> Result<Vec<GamePlayer>, SaveError> {
+        let mut players: Vec<GamePlayer> = self
+            .db
+            .get_game_players(id)
+            .await?
+            .into_iter()
+            .map(|p| GamePlayer {
+                id: p.player_id,
+                login: p.login,
+                team: p.team,
+                army: p.army,
+            })
+            .collect();
+        players.sort_by_key(|p| p.army);
+        Ok(players)
+    }
+
     fn unmangle_map_name(name: Option<String>) -> String {
         // Mapname looks like this: maps/<stuff>.zip
         // Previous two servers extracted the stuff with path.splitext(path.basename(...)).
@@ -85,6 +111,15 @@ impl Queries {
     ) -> Result<(), SaveError> {
         self.db.update_game_stats(id, replay_ticks, replay_available).await
     }
+
+    pub async fn update_replay_checksums(
+        &self,
+        id: u64,
+        body_sha256: &str,
+        file_sha256: &str,
+    ) -> Result<(), SaveError> {
+        self.db.update_replay_checksums(id, body_sha256, file_sha256).await
+    }
 }
 
 #[cfg(test)]
@@ -99,7 +134,7 @@ mod test {
 
     #[tokio::test]
     async fn test_teams_in_game() {
-        let q = Queries::new(mock_database());
+        let q = Queries::new(Arc::new(mock_database()));
         let teams = q.get_teams_in_game(1).await.unwrap();
 
         assert!(teams.len() == 2);
@@ -113,7 +148,7 @@ mod test {
 
     #[tokio::test]
     async fn test_usual_game_stats() {
-        let q = Queries::new(mock_database());
+        let q = Queries::new(Arc::new(mock_database()));
         let stats = q.get_game_stats(1).await.unwrap();
 
         // Check just a few interesting bits.
@@ -139,7 +174,7 @@ mod test {
         faux::when!(mdb.get_game_stat_row).then(move |_| Ok(null_stats()));
         faux::when!(mdb.get_player_count).then(|_| Ok(4));
 
-        let q = Queries::new(mdb);
+        let q = Queries::new(Arc::new(mdb));
         let stats = q.get_game_stats(1).await.unwrap();
 
         assert_eq!(stats.mapname, "None");
@@ -150,7 +185,7 @@ mod test {
 
     #[tokio::test]
     async fn test_game_mod_versions() {
-        let q = Queries::new(mock_database());
+        let q = Queries::new(Arc::new(mock_database()));
         let mods = q.get_mod_versions("foo").await.unwrap();
         assert_eq!(mods.len(), 3);
         assert_eq!(mods["50".into()], 3000);
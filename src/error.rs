@@ -8,6 +8,10 @@ pub enum ConnectionError {
     IO(#[from] std::io::Error),
     #[error("Could not assign connection to replay")]
     CannotAssignToReplay,
+    #[error("Writer is not a player in a running game")]
+    UnauthorizedWriter,
+    #[error("Invalid authentication token: {0}")]
+    InvalidToken(String),
 }
 
 // Little shortcut for less typing,
@@ -38,3 +42,24 @@ pub enum SaveError {
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
 }
+
+impl SaveError {
+    // Retrying won't help if the game is not in the database.
+    pub fn is_game_missing(&self) -> bool {
+        matches!(self, SaveError::DatabaseError(sqlx::Error::RowNotFound))
+    }
+
+    // Other queries will fail too until the database is back.
+    pub fn is_database_unreachable(&self) -> bool {
+        matches!(
+            self,
+            SaveError::DatabaseError(
+                sqlx::Error::Io(_)
+                    | sqlx::Error::Tls(_)
+                    | sqlx::Error::PoolTimedOut
+                    | sqlx::Error::PoolClosed
+                    | sqlx::Error::WorkerCrashed
+            )
+        )
+    }
+}
@@ -2,6 +2,7 @@ pub mod accept;
 pub mod config;
 pub mod database;
 pub mod metrics;
+pub mod notify;
 pub mod replay;
 pub mod server;
 pub mod util;
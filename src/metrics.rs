@@ -34,6 +34,39 @@ lazy_static! {
         "Total replays successfully saved to disk."
     )
     .unwrap();
+    pub static ref REPLAY_FILE_SAVE_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "replayserver_replay_file_save_outcomes_total",
+        "What happened to replay files we saved, depending on whether one with the same ID existed.",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref PENDING_REPLAYS: IntGauge = register_int_gauge!(
+        "replayserver_pending_replays_count",
+        "Count of replays waiting for the database to be saved."
+    )
+    .unwrap();
+    pub static ref MERGED_DATA_IN_MEMORY: IntGauge = register_int_gauge!(
+        "replayserver_merged_data_in_memory_bytes",
+        "Merged replay data kept in memory, counted only if there's a memory budget."
+    )
+    .unwrap();
+    pub static ref SPILLED_DATA: IntCounter = register_int_counter!(
+        "replayserver_spilled_data_bytes_total",
+        "Merged replay data spilled to disk to stay within the memory budget."
+    )
+    .unwrap();
+    pub static ref DIVERGED_WRITERS: IntCounterVec = register_int_counter_vec!(
+        "replayserver_diverged_writers_total",
+        "How many writers sent a header or data that disagreed with the merged replay.",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref SAVED_REPLAY_NOTIFICATIONS: IntCounterVec = register_int_counter_vec!(
+        "replayserver_saved_replay_notifications_total",
+        "Attempts to tell other services that a replay was saved, by target and result.",
+        &["target", "result"]
+    )
+    .unwrap();
 }
 
 pub fn inc_served_conns<T>(res: &ConnResult<T>) {
@@ -44,6 +77,8 @@ pub fn inc_served_conns<T>(res: &ConnResult<T>) {
             ConnectionError::BadData(..) => "Bad data",
             ConnectionError::IO { .. } => "I/O error",
             ConnectionError::CannotAssignToReplay => "No replay matched",
+            ConnectionError::UnauthorizedWriter => "Unauthorized writer",
+            ConnectionError::InvalidToken(..) => "Invalid token",
         },
     };
     SERVED_CONNS.with_label_values(&[label]).inc();
@@ -0,0 +1,67 @@
+use std::{io::Error, process::Stdio};
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+// Runs a command with the event on its stdin. The command is killed if we give up on it.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl NotifyCommand {
+    // Command needs to have at least the program.
+    pub fn new(mut command: Vec<String>) -> Self {
+        let program = command.remove(0);
+        Self { program, args: command }
+    }
+
+    pub async fn deliver(&self, event: &[u8]) -> std::io::Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(event).await?;
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(Error::other(format!("Command exited with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sh(script: &str) -> NotifyCommand {
+        NotifyCommand::new(vec!["sh".into(), "-c".into(), script.into()])
+    }
+
+    #[tokio::test]
+    async fn test_command_gets_event() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let out = tmp_dir.path().join("event.json");
+        let command = sh(&format!("cat > {}", out.display()));
+        command.deliver(b"{\"foo\":1}").await.unwrap();
+        assert_eq!(std::fs::read(out).unwrap(), b"{\"foo\":1}");
+    }
+
+    #[tokio::test]
+    async fn test_command_failure() {
+        sh("cat > /dev/null; exit 3")
+            .deliver(b"{}")
+            .await
+            .expect_err("Command should have failed");
+        NotifyCommand::new(vec!["/nonexistent/command".into()])
+            .deliver(b"{}")
+            .await
+            .expect_err("Command should not exist");
+    }
+}
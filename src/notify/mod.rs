@@ -0,0 +1,309 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{config::NotifySettings, metrics, replay::end_reason::EndReason, util::timeout::timeout};
+
+pub mod amqp;
+pub mod command;
+pub mod webhook;
+
+use amqp::AmqpPublisher;
+use command::NotifyCommand;
+use webhook::Webhook;
+
+// Tells other services that a replay was saved, so they don't have to poll the database. Events
+// go to every configured target, each retried a few times. If a target still fails, it misses
+// the event, we don't keep undelivered events around.
+//
+// Delivery runs in its own task, so a slow target doesn't hold up saving replays. We only let
+// so many deliveries run at once, past that notifying waits for one of them to finish.
+
+const MAX_DELIVERIES_IN_FLIGHT: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename = "replay_saved")]
+pub struct ReplaySavedEvent {
+    pub game_id: u64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub ticks: Option<u32>,
+    pub body_sha256: String,
+    pub file_sha256: String,
+    pub complete: bool,
+    pub state: EndReason,
+}
+
+pub enum NotifyTarget {
+    Webhook(Webhook),
+    Amqp(AmqpPublisher),
+    Command(NotifyCommand),
+}
+
+impl NotifyTarget {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Webhook(..) => "Webhook",
+            Self::Amqp(..) => "AMQP",
+            Self::Command(..) => "Command",
+        }
+    }
+
+    async fn deliver(&self, event: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Webhook(w) => w.deliver(event).await,
+            Self::Amqp(a) => a.deliver(event).await,
+            Self::Command(c) => c.deliver(event).await,
+        }
+    }
+}
+
+struct Deliveries {
+    targets: Vec<NotifyTarget>,
+    max_attempts: u32,
+    retry_interval: Duration,
+    attempt_timeout: Duration,
+}
+
+pub struct Notifier {
+    deliveries: Arc<Deliveries>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Notifier {
+    // Targets with invalid settings are logged and left out, we don't want to stop saving
+    // replays because of them.
+    pub fn new(config: &NotifySettings) -> Self {
+        let mut targets = Vec::new();
+        if !config.webhook_url.is_empty() {
+            match Webhook::new(&config.webhook_url) {
+                Ok(w) => targets.push(NotifyTarget::Webhook(w)),
+                Err(e) => log::error!("Invalid webhook URL, not sending events to it: {}", e),
+            }
+        }
+        if !config.amqp_url.is_empty() {
+            match AmqpPublisher::new(&config.amqp_url, &config.amqp_exchange, &config.amqp_routing_key) {
+                Ok(a) => targets.push(NotifyTarget::Amqp(a)),
+                Err(e) => log::error!("Invalid AMQP URL, not sending events to it: {}", e),
+            }
+        }
+        if !config.command.is_empty() {
+            targets.push(NotifyTarget::Command(NotifyCommand::new(config.command.clone())));
+        }
+        let deliveries = Deliveries {
+            targets,
+            max_attempts: std::cmp::max(config.max_attempts, 1),
+            retry_interval: config.retry_interval_s,
+            attempt_timeout: config.timeout_s,
+        };
+        Self {
+            deliveries: Arc::new(deliveries),
+            in_flight: Arc::new(Semaphore::new(MAX_DELIVERIES_IN_FLIGHT)),
+        }
+    }
+
+    pub async fn notify(&self, event: &ReplaySavedEvent) {
+        if self.deliveries.targets.is_empty() {
+            return;
+        }
+        let data = match serde_json::to_vec(event) {
+            Ok(d) => d,
+            Err(e) => {
+                log::warn!("Failed to serialize event for replay {}: {}", event.game_id, e);
+                return;
+            }
+        };
+        // We never close the semaphore.
+        let permit = self.in_flight.clone().acquire_owned().await.unwrap();
+        let deliveries = self.deliveries.clone();
+        let game_id = event.game_id;
+        tokio::spawn(async move {
+            deliveries.deliver(&data, game_id).await;
+            drop(permit);
+        });
+    }
+
+    #[cfg(test)]
+    pub async fn wait_for_deliveries(&self) {
+        let _ = self
+            .in_flight
+            .acquire_many(MAX_DELIVERIES_IN_FLIGHT as u32)
+            .await
+            .unwrap();
+    }
+}
+
+impl Deliveries {
+    async fn deliver_to(&self, target: &NotifyTarget, event: &[u8], game_id: u64) {
+        for attempt in 1..=self.max_attempts {
+            let res = match timeout(target.deliver(event), self.attempt_timeout).await {
+                Some(r) => r,
+                None => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")),
+            };
+            let e = match res {
+                Ok(()) => {
+                    metrics::SAVED_REPLAY_NOTIFICATIONS
+                        .with_label_values(&[target.label(), "Delivered"])
+                        .inc();
+                    return;
+                }
+                Err(e) => e,
+            };
+            if attempt == self.max_attempts {
+                log::warn!(
+                    "Failed to notify {} that replay {} was saved, giving up: {}",
+                    target.label(),
+                    game_id,
+                    e
+                );
+                metrics::SAVED_REPLAY_NOTIFICATIONS
+                    .with_label_values(&[target.label(), "Gave up"])
+                    .inc();
+                return;
+            }
+            log::info!(
+                "Failed to notify {} that replay {} was saved, will retry: {}",
+                target.label(),
+                game_id,
+                e
+            );
+            metrics::SAVED_REPLAY_NOTIFICATIONS
+                .with_label_values(&[target.label(), "Failed"])
+                .inc();
+            tokio::time::sleep(self.retry_interval).await;
+        }
+    }
+
+    async fn deliver(&self, event: &[u8], game_id: u64) {
+        join_all(self.targets.iter().map(|t| self.deliver_to(t, event, game_id))).await;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::{mpsc, oneshot};
+
+    pub fn test_event() -> ReplaySavedEvent {
+        ReplaySavedEvent {
+            game_id: 1,
+            path: PathBuf::from("/tmp/foo/0/0/0/0/1.fafreplay"),
+            size: 100,
+            ticks: Some(10),
+            body_sha256: "00".into(),
+            file_sha256: "11".into(),
+            complete: false,
+            state: EndReason::TimedOut,
+        }
+    }
+
+    // Answers the first `failures` requests with a 500, the rest with a 200. Returns the bodies
+    // of all requests.
+    pub async fn webhook_stand_in(failures: usize) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let (body_tx, mut body_rx) = mpsc::unbounded_channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let make_service = make_service_fn(move |_| {
+            let body_tx = body_tx.clone();
+            let requests = requests.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let body_tx = body_tx.clone();
+                    let requests = requests.clone();
+                    async move {
+                        assert_eq!(req.method(), Method::POST);
+                        assert_eq!(req.uri().path(), "/hook");
+                        assert_eq!(req.headers()["content-type"], "application/json");
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let status = if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        } else {
+                            StatusCode::OK
+                        };
+                        let _ = body_tx.send(String::from_utf8_lossy(&body).to_string());
+                        Response::builder().status(status).body(Body::empty())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = stop_rx.await;
+        }));
+        let handle = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            while bodies.len() <= failures {
+                bodies.push(body_rx.recv().await.unwrap());
+            }
+            let _ = stop_tx.send(());
+            bodies
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let json = serde_json::to_value(test_event()).unwrap();
+        assert_eq!(json["event"], "replay_saved");
+        assert_eq!(json["game_id"], 1);
+        assert_eq!(json["path"], "/tmp/foo/0/0/0/0/1.fafreplay");
+        assert_eq!(json["state"], "TIMED_OUT");
+        let event: ReplaySavedEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(event, test_event());
+    }
+
+    #[tokio::test]
+    async fn test_event_delivery_is_retried() {
+        let (url, stand_in) = webhook_stand_in(2).await;
+        let notifier = Notifier::new(&NotifySettings {
+            webhook_url: url,
+            retry_interval_s: Duration::from_millis(10),
+            ..Default::default()
+        });
+        notifier.notify(&test_event()).await;
+        let bodies = stand_in.await.unwrap();
+        assert_eq!(bodies.len(), 3);
+        let event: ReplaySavedEvent = serde_json::from_str(&bodies[2]).unwrap();
+        assert_eq!(event, test_event());
+    }
+
+    #[tokio::test]
+    async fn test_notify_does_not_wait_for_delivery() {
+        let notifier = Notifier::new(&NotifySettings {
+            command: vec!["sleep".into(), "10".into()],
+            timeout_s: Duration::from_secs(20),
+            ..Default::default()
+        });
+        let notified = timeout(notifier.notify(&test_event()), Duration::from_secs(1)).await;
+        assert!(notified.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_event_delivery_gives_up() {
+        let (url, stand_in) = webhook_stand_in(5).await;
+        let notifier = Notifier::new(&NotifySettings {
+            webhook_url: url,
+            max_attempts: 2,
+            retry_interval_s: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let before = metrics::SAVED_REPLAY_NOTIFICATIONS
+            .with_label_values(&["Webhook", "Gave up"])
+            .get();
+        notifier.notify(&test_event()).await;
+        notifier.wait_for_deliveries().await;
+        stand_in.abort();
+        assert!(
+            metrics::SAVED_REPLAY_NOTIFICATIONS
+                .with_label_values(&["Webhook", "Gave up"])
+                .get()
+                > before
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::{Error, ErrorKind};
+
+use reqwest::{header::CONTENT_TYPE, Client, Url};
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: Client,
+    url: Url,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> std::io::Result<Self> {
+        let url = Url::parse(url).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", url, e)))?;
+        if !["http", "https"].contains(&url.scheme()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not an http(s) URL", url),
+            ));
+        }
+        let client = Client::builder()
+            .user_agent(concat!("faf-replay-server/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(Error::other)?;
+        Ok(Self { client, url })
+    }
+
+    pub async fn deliver(&self, event: &[u8]) -> std::io::Result<()> {
+        self.client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(event.to_vec())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(Error::other)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_webhook_url_validation() {
+        Webhook::new("http://localhost:8080/replays/saved").unwrap();
+        Webhook::new("https://example.com").unwrap();
+        Webhook::new("ftp://example.com").expect_err("Scheme is wrong");
+        Webhook::new("http://example.com:port/").expect_err("Port is invalid");
+        Webhook::new("not a url").expect_err("URL is invalid");
+    }
+}
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+// Why a replay stopped receiving data. Only replays that ended on their own are complete, the rest
+// are missing some data at the end. Saved as "state" in the JSON header.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EndReason {
+    #[default]
+    Ended,
+    TimedOut,  // Hit forced_timeout_s.
+    Shutdown,  // Cut by server shutdown.
+    Stalemate, // Writers left while the merge was waiting for them to agree.
+    Crashed,   // Recovered from a live copy after a crash.
+}
+
+impl EndReason {
+    pub fn is_complete(&self) -> bool {
+        *self == Self::Ended
+    }
+}
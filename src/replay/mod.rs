@@ -1,3 +1,4 @@
+pub mod end_reason;
 pub mod receive;
 mod replay;
 mod replays;
@@ -5,6 +6,7 @@ pub mod runner;
 pub mod save;
 pub mod send;
 mod streams;
+pub mod timeline;
 
 pub use crate::replay::replay::Replay;
 pub use crate::replay::replays::Replays;
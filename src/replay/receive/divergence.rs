@@ -0,0 +1,178 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use faf_replay_parser::scfa;
+use futures::{future::LocalBoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metrics,
+    replay::streams::MergedReplay,
+    replay::timeline::{Timeline, TimelineEvent},
+};
+
+// Records of writers that diverged from the canonical replay. A writer with a different header or
+// different data either desynced or has a tampered client, and the tick at which the data stopped
+// matching can help moderators tell which one it was. Writers that simply ended early (e.g. the
+// player left) diverge too, but there's nothing interesting about them, so they're only put on
+// the timeline.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergenceReason {
+    Header,
+    Data,
+    EndedEarly,
+}
+
+impl DivergenceReason {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Header => "Header",
+            Self::Data => "Data",
+            Self::EndedEarly => "Ended early",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DivergenceRecord {
+    pub writer: u64,
+    pub name: String,
+    pub reason: DivergenceReason,
+    pub offset: Option<usize>, // Of the first byte of replay body that differed.
+    pub tick: Option<u32>,     // Tick of the canonical replay at that offset.
+}
+
+impl DivergenceRecord {
+    pub fn is_suspicious(&self) -> bool {
+        self.reason != DivergenceReason::EndedEarly
+    }
+}
+
+type TickAt = LocalBoxFuture<'static, Option<u32>>;
+
+// Parsing spilled data blocks, so then it's done on a blocking thread.
+fn tick_at(canon: &MergedReplay, offset: usize) -> TickAt {
+    let parse = canon
+        .get_data()
+        .read_with(offset, |mut body| scfa::parser::parse_body_ticks(&mut body).ok());
+    parse.map(|tick| tick.ok().flatten()).boxed_local()
+}
+
+pub struct DivergenceLog {
+    names: RefCell<HashMap<u64, String>>,
+    timeline: Timeline,
+    pending_ticks: RefCell<Vec<(u64, usize, TickAt)>>,
+}
+
+impl DivergenceLog {
+    pub fn new(timeline: Timeline) -> Self {
+        Self {
+            names: RefCell::new(HashMap::new()),
+            timeline,
+            pending_ticks: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn set_writer_name(&self, writer: u64, name: String) {
+        self.names.borrow_mut().insert(writer, name);
+    }
+
+    pub fn report(&self, writer: u64, reason: DivergenceReason, offset: Option<usize>, canon: &MergedReplay) {
+        let name = self.names.borrow().get(&writer).cloned().unwrap_or_default();
+        let tick = match (reason, offset) {
+            (DivergenceReason::Data, Some(o)) => {
+                let mut tick = tick_at(canon, o);
+                let found = (&mut tick).now_or_never();
+                if found.is_none() {
+                    self.pending_ticks.borrow_mut().push((writer, o, tick));
+                }
+                found.flatten()
+            }
+            _ => None,
+        };
+        let record = DivergenceRecord {
+            writer,
+            name,
+            reason,
+            offset,
+            tick,
+        };
+        if record.is_suspicious() {
+            log::warn!(
+                "Writer {} ({}) diverged from canonical replay, reason: {}, offset: {:?}, tick: {:?}",
+                record.writer,
+                record.name,
+                reason.label(),
+                record.offset,
+                record.tick
+            );
+            metrics::DIVERGED_WRITERS.with_label_values(&[reason.label()]).inc();
+        } else {
+            log::debug!("Writer {} ({}) ended early", record.writer, record.name);
+        }
+        self.timeline.record(TimelineEvent::WriterDiverged(record));
+    }
+
+    // Ticks we're still looking for are added to the timeline once found.
+    pub async fn wait_for_ticks(&self) {
+        let pending = std::mem::take(&mut *self.pending_ticks.borrow_mut());
+        for (writer, offset, tick) in pending {
+            if let Some(t) = tick.await {
+                self.timeline.set_divergence_tick(writer, offset, t);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::streams::{replay_with_budget, MemoryBudget, WriterReplay};
+    use crate::util::test::get_file;
+
+    #[test]
+    fn test_divergence_report_finds_tick() {
+        let body = get_file("example_body");
+        let mut source = WriterReplay::new();
+        source.add_data(&body);
+        let mut canon = MergedReplay::new();
+        canon.add_data(&source, body.len());
+
+        let timeline = Timeline::new();
+        let log = DivergenceLog::new(timeline.clone());
+        log.set_writer_name(1, "foo".into());
+        log.report(1, DivergenceReason::Data, Some(body.len()), &canon);
+        log.report(1, DivergenceReason::Data, Some(0), &canon);
+        log.report(2, DivergenceReason::Header, None, &canon);
+
+        let records = timeline.divergences();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].name, "foo");
+        assert_eq!(records[0].tick, scfa::parser::parse_body_ticks(&mut &body[..]).ok());
+        assert!(records[0].tick.unwrap() > 0);
+        assert_eq!(records[1].tick, Some(0));
+        assert_eq!(records[2].name, "");
+        assert_eq!(records[2].tick, None);
+    }
+
+    #[tokio::test]
+    async fn test_divergence_tick_in_spilled_data_is_found() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let body = get_file("example_body");
+        let budget = MemoryBudget::new(Some(1), None, tmp_dir.path().into());
+        let canon = replay_with_budget(budget, b"header", &body).await;
+        assert!(canon.get_data().spilled_len() > 0);
+
+        let timeline = Timeline::new();
+        let log = DivergenceLog::new(timeline.clone());
+        log.report(1, DivergenceReason::Data, Some(body.len()), &canon);
+        log.report(2, DivergenceReason::Data, Some(1000), &canon);
+        log.wait_for_ticks().await;
+
+        let records = timeline.divergences();
+        assert_eq!(records[0].tick, scfa::parser::parse_body_ticks(&mut &body[..]).ok());
+        assert!(records[0].tick.unwrap() > 0);
+        assert_eq!(records[1].tick, scfa::parser::parse_body_ticks(&mut &body[..1000]).ok());
+    }
+}
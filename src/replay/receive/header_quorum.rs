@@ -0,0 +1,139 @@
+use crate::replay::streams::ReplayHeader;
+
+// Picks the canonical replay header. We collect headers from writers, group the ones that agree
+// and pick the biggest group. Headers are compared with Lua tables parsed, since key order differs
+// between writers. Headers we can't parse are compared byte-for-byte.
+//
+// * Once a group has target_quorum_size members, its header is picked.
+// * Otherwise we pick when the merge strategy tells us it can't wait any longer. The biggest group
+//   wins, ties go to the group whose header came first.
+// * After we picked, every new header is checked against the canonical one.
+
+struct HeaderGroup {
+    header: ReplayHeader,
+    replays: Vec<u64>,
+}
+
+impl HeaderGroup {
+    fn agrees_with(&self, header: &ReplayHeader) -> bool {
+        match (&self.header.parsed, &header.parsed) {
+            (Some(p1), Some(p2)) => p1 == p2,
+            (None, None) => self.header.data == header.data,
+            _ => false,
+        }
+    }
+}
+
+pub struct HeaderQuorum {
+    target_quorum_size: usize,
+    groups: Vec<HeaderGroup>,
+    canonical_group: Option<usize>,
+}
+
+impl HeaderQuorum {
+    pub fn new(target_quorum_size: usize) -> Self {
+        Self {
+            target_quorum_size,
+            groups: Vec::new(),
+            canonical_group: None,
+        }
+    }
+
+    pub fn is_decided(&self) -> bool {
+        self.canonical_group.is_some()
+    }
+
+    // Returns false if a canonical header was already picked and this one disagrees with it.
+    pub fn add_header(&mut self, id: u64, header: ReplayHeader) -> bool {
+        let group = match self.groups.iter().position(|g| g.agrees_with(&header)) {
+            Some(idx) => idx,
+            None => {
+                self.groups.push(HeaderGroup {
+                    header,
+                    replays: Vec::new(),
+                });
+                self.groups.len() - 1
+            }
+        };
+        self.groups[group].replays.push(id);
+        self.canonical_group.is_none_or(|c| c == group)
+    }
+
+    pub fn has_quorum(&self) -> bool {
+        self.groups.iter().any(|g| g.replays.len() >= self.target_quorum_size)
+    }
+
+    // Returns the canonical header and replays that disagree with it, or None if there are no
+    // headers yet.
+    pub fn decide(&mut self) -> Option<(ReplayHeader, Vec<u64>)> {
+        debug_assert!(!self.is_decided());
+        let best = self
+            .groups
+            .iter()
+            .enumerate()
+            .max_by_key(|(idx, g)| (g.replays.len(), std::cmp::Reverse(*idx)))?
+            .0;
+        self.canonical_group = Some(best);
+        let rejected = self
+            .groups
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != best)
+            .flat_map(|(_, g)| g.replays.iter().copied())
+            .collect();
+        Some((self.groups[best].header.clone(), rejected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::get_file;
+
+    fn header(data: &[u8]) -> ReplayHeader {
+        ReplayHeader::new(data.into())
+    }
+
+    #[test]
+    fn test_header_quorum_picks_majority() {
+        let mut q = HeaderQuorum::new(3);
+        assert!(q.add_header(0, header(&[1])));
+        assert!(q.add_header(1, header(&[2])));
+        assert!(q.add_header(2, header(&[2])));
+        assert!(!q.has_quorum());
+        let (h, rejected) = q.decide().unwrap();
+        assert_eq!(h.data, vec![2]);
+        assert_eq!(rejected, vec![0]);
+
+        assert!(q.add_header(3, header(&[2])));
+        assert!(!q.add_header(4, header(&[1])));
+    }
+
+    #[test]
+    fn test_header_quorum_tie_goes_to_earliest() {
+        let mut q = HeaderQuorum::new(2);
+        assert!(q.decide().is_none());
+        q.add_header(0, header(&[1]));
+        q.add_header(1, header(&[2]));
+        let (h, rejected) = q.decide().unwrap();
+        assert_eq!(h.data, vec![1]);
+        assert_eq!(rejected, vec![1]);
+    }
+
+    #[test]
+    fn test_header_quorum_compares_parsed_headers() {
+        let mut q = HeaderQuorum::new(2);
+        let example = get_file("example_header");
+        let mut different = example.clone();
+        let last = different.len() - 1;
+        different[last] ^= 1; // Random seed
+        q.add_header(0, header(&example));
+        q.add_header(1, header(&different));
+        assert!(!q.has_quorum());
+        q.add_header(2, header(&example));
+        assert!(q.has_quorum());
+        let (h, rejected) = q.decide().unwrap();
+        assert_eq!(h.data, example);
+        assert_eq!(rejected, vec![1]);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    replay::streams::MReplayRef, replay::streams::MergedReplay, replay::streams::WReplayRef,
+    util::buf_traits::DiscontiguousBuf,
+};
+
+use super::{divergence::DivergenceLog, merge_strategy::MergeStrategy, replay_state::ReplayState};
+
+// A much simpler merge strategy than the quorum one. It doesn't look for replays that agree with
+// each other, it just follows whichever replay sent the most data.
+//
+// We use R, C, matching and diverging as defined in merge_strategy.rs and replay_state.rs.
+// * The strategy has a leader L, which is a replay in R or none.
+// * Whenever a replay r that matches C is longer than C, r's data is appended to C and r becomes
+//   the leader. Thus C is always a prefix of one of replays, and the first replay to send a
+//   piece of data decides what it is.
+// * C's delayed position follows L's delayed position. It never goes back when the leader
+//   changes.
+// * When L ends, the longest remaining replay that matches C becomes the leader.
+// * At finish(), C's delayed position is moved to the end of its data.
+//
+// Since replays that diverge from C are never used again, C ends up equal to the longest replay
+// that agreed with C all the way. It doesn't protect us from a single misbehaving replay like the
+// quorum strategy does, but it's useful as a baseline to compare the quorum strategy against.
+
+pub struct LongestWriterMergeStrategy {
+    token: u64,
+    stream_cmp_distance: usize,
+    replays: HashMap<u64, ReplayState>,
+    leader: Option<u64>,
+    canonical_stream: MReplayRef,
+    divergences: Rc<DivergenceLog>,
+}
+
+impl LongestWriterMergeStrategy {
+    pub fn new(stream_cmp_distance: usize, divergences: Rc<DivergenceLog>) -> Self {
+        Self {
+            token: 0,
+            stream_cmp_distance,
+            replays: HashMap::new(),
+            leader: None,
+            canonical_stream: Rc::new(RefCell::new(MergedReplay::new())),
+            divergences,
+        }
+    }
+
+    fn get_replay(&self, token: u64) -> &ReplayState {
+        self.replays.get(&token).unwrap()
+    }
+
+    fn get_mut_replay(&mut self, token: u64) -> &mut ReplayState {
+        self.replays.get_mut(&token).unwrap()
+    }
+
+    fn merged_data_len(&self) -> usize {
+        self.canonical_stream.borrow().get_data().len()
+    }
+
+    fn follow_replay(&mut self, id: u64) {
+        {
+            let mut canon_replay = self.canonical_stream.borrow_mut();
+            let source_replay = self.get_replay(id).replay.borrow();
+            let to = source_replay.get_data().len();
+            canon_replay.add_data(&source_replay, to);
+        }
+        self.get_mut_replay(id).explicitly_set_matching();
+        for r in self.replays.values_mut() {
+            r.discard_unneeded_data();
+        }
+        self.leader = Some(id);
+    }
+
+    fn update_delayed_position(&mut self) {
+        let leader = match self.leader {
+            Some(id) => id,
+            None => return,
+        };
+        let position = std::cmp::min(self.get_replay(leader).delayed_data_len(), self.merged_data_len());
+        if position > self.canonical_stream.borrow().delayed_data_len() {
+            self.canonical_stream.borrow_mut().advance_delayed_data(position);
+        }
+    }
+
+    fn pick_new_leader(&mut self, except: u64) -> Option<u64> {
+        self.replays
+            .iter_mut()
+            .filter_map(|(id, r)| (*id != except && !r.diverges_from_canon()).then(|| (*id, r.data_len())))
+            .max_by_key(|(_, len)| *len)
+            .map(|(id, _)| id)
+    }
+}
+
+impl MergeStrategy for LongestWriterMergeStrategy {
+    fn replay_added(&mut self, w: WReplayRef) -> u64 {
+        let token = self.token;
+        let replay = ReplayState::new(
+            token,
+            w,
+            self.canonical_stream.clone(),
+            self.stream_cmp_distance,
+            self.divergences.clone(),
+        );
+        self.replays.insert(token, replay);
+        self.token += 1;
+        token
+    }
+
+    fn replay_removed(&mut self, id: u64) {
+        if self.leader != Some(id) {
+            return;
+        }
+        self.leader = self.pick_new_leader(id);
+        if let Some(new_leader) = self.leader {
+            self.replay_data_updated(new_leader);
+        }
+    }
+
+    fn replay_header_added(&mut self, id: u64) {
+        // Accept the first header we get. No header quorum here, we trust writers anyway.
+        let header = self.get_replay(id).replay.borrow_mut().take_header();
+        let mut canonical_stream = self.canonical_stream.borrow_mut();
+        if canonical_stream.get_header().is_none() {
+            canonical_stream.add_header(header);
+        }
+    }
+
+    fn replay_data_updated(&mut self, id: u64) {
+        if self.get_mut_replay(id).diverges_from_canon() {
+            return;
+        }
+        if self.get_replay(id).data_len() > self.merged_data_len() {
+            self.follow_replay(id);
+        }
+        if self.leader == Some(id) {
+            self.update_delayed_position();
+        }
+    }
+
+    fn finish(&mut self) {
+        let data_len = self.merged_data_len();
+        let mut canonical_stream = self.canonical_stream.borrow_mut();
+        if canonical_stream.delayed_data_len() < data_len {
+            canonical_stream.advance_delayed_data(data_len);
+        }
+        canonical_stream.finish();
+    }
+
+    fn get_merged_replay(&self) -> MReplayRef {
+        self.canonical_stream.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LongestWriterMergeStrategy;
+    use crate::replay::{receive::divergence::DivergenceLog, timeline::Timeline};
+    use crate::util::buf_traits::ReadAtExt;
+    use crate::{
+        replay::receive::merge_strategy::MergeStrategy, replay::streams::MemoryBudget, replay::streams::ReplayHeader,
+        replay::streams::WriterReplay,
+    };
+    use std::{cell::RefCell, io::Read, rc::Rc, time::Duration};
+
+    fn strat() -> LongestWriterMergeStrategy {
+        LongestWriterMergeStrategy::new(4096, Rc::new(DivergenceLog::new(Timeline::new())))
+    }
+
+    fn merged_data(strat: &LongestWriterMergeStrategy) -> Vec<u8> {
+        let mut data = Vec::new();
+        strat
+            .get_merged_replay()
+            .borrow()
+            .get_data()
+            .reader()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_longest_writer_follows_longest_replay() {
+        let mut strat = strat();
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        let token1 = strat.replay_added(stream1.clone());
+        let token2 = strat.replay_added(stream2.clone());
+        strat.replay_header_added(token1);
+        strat.replay_header_added(token2);
+
+        stream1.borrow_mut().add_data(&[1, 2, 3, 4]);
+        strat.replay_data_updated(token1);
+        stream2.borrow_mut().add_data(&[1, 2, 3, 4, 5, 6]);
+        strat.replay_data_updated(token2);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+
+        stream1.borrow_mut().set_delayed_data_len(4);
+        strat.replay_data_updated(token1);
+        assert_eq!(strat.get_merged_replay().borrow().delayed_data_len(), 0);
+        stream2.borrow_mut().set_delayed_data_len(5);
+        strat.replay_data_updated(token2);
+        assert_eq!(strat.get_merged_replay().borrow().delayed_data_len(), 5);
+
+        stream1.borrow_mut().finish();
+        stream2.borrow_mut().finish();
+        strat.replay_removed(token1);
+        strat.replay_removed(token2);
+        strat.finish();
+
+        let merged = strat.get_merged_replay();
+        assert!(merged.borrow().is_finished());
+        assert_eq!(merged.borrow().delayed_data_len(), 6);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_longest_writer_ignores_diverged_replays() {
+        let mut strat = strat();
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        let token1 = strat.replay_added(stream1.clone());
+        let token2 = strat.replay_added(stream2.clone());
+        strat.replay_header_added(token1);
+        strat.replay_header_added(token2);
+
+        stream1.borrow_mut().add_data(&[1, 2, 3, 4]);
+        strat.replay_data_updated(token1);
+        stream2.borrow_mut().add_data(&[1, 2, 5, 6, 7, 8, 9]);
+        strat.replay_data_updated(token2);
+        stream1.borrow_mut().add_data(&[5, 6]);
+        strat.replay_data_updated(token1);
+
+        stream1.borrow_mut().finish();
+        strat.replay_removed(token1);
+        stream2.borrow_mut().finish();
+        strat.replay_removed(token2);
+        strat.finish();
+
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_longest_writer_switches_leader_when_leader_ends() {
+        let mut strat = strat();
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        let token1 = strat.replay_added(stream1.clone());
+        let token2 = strat.replay_added(stream2.clone());
+        strat.replay_header_added(token1);
+        strat.replay_header_added(token2);
+
+        stream1.borrow_mut().add_data(&[1, 2, 3, 4]);
+        strat.replay_data_updated(token1);
+        stream2.borrow_mut().add_data(&[1, 2, 3, 4]);
+        stream2.borrow_mut().set_delayed_data_len(2);
+        strat.replay_data_updated(token2);
+
+        stream1.borrow_mut().set_delayed_data_len(4);
+        strat.replay_data_updated(token1);
+        stream1.borrow_mut().finish();
+        strat.replay_removed(token1);
+        assert_eq!(strat.get_merged_replay().borrow().delayed_data_len(), 4);
+
+        stream2.borrow_mut().add_data(&[5, 6]);
+        stream2.borrow_mut().set_delayed_data_len(5);
+        strat.replay_data_updated(token2);
+        assert_eq!(merged_data(&strat), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(strat.get_merged_replay().borrow().delayed_data_len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_longest_writer_checks_lagging_replay_once_it_catches_up() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..4096 * 10).map(|i| (i % 251) as u8).collect();
+        let mut strat = strat();
+        let budget = MemoryBudget::new(Some(1), None, tmp_dir.path().into()).with_unspilled_tail(4096);
+        strat.get_merged_replay().borrow_mut().set_memory_budget(budget);
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        let token1 = strat.replay_added(stream1.clone());
+        let token2 = strat.replay_added(stream2.clone());
+        strat.replay_header_added(token1);
+        strat.replay_header_added(token2);
+
+        // Let spills finish between updates.
+        for chunk in data.chunks(4096) {
+            stream1.borrow_mut().add_data(chunk);
+            strat.replay_data_updated(token1);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let spilled = strat.get_merged_replay().borrow().get_data().spilled_len();
+        assert!(spilled > 4096);
+
+        // Comparing with spilled data would block, so it waits until the replay catches up.
+        stream2.borrow_mut().add_data(&data[..4096]);
+        strat.replay_data_updated(token2);
+        stream2.borrow_mut().add_data(&data[4096..data.len() - 1]);
+        stream2.borrow_mut().add_data(&[255, 1, 2]);
+        strat.replay_data_updated(token2);
+        assert_eq!(merged_data(&strat), data);
+
+        stream1.borrow_mut().finish();
+        strat.replay_removed(token1);
+        stream2.borrow_mut().finish();
+        strat.replay_removed(token2);
+        strat.finish();
+        assert_eq!(merged_data(&strat), data);
+    }
+}
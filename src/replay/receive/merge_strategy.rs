@@ -36,4 +36,8 @@ pub trait MergeStrategy {
     fn replay_data_updated(&mut self, id: u64);
     fn finish(&mut self);
     fn get_merged_replay(&self) -> MReplayRef;
+    // Whether replays ended before agreeing on what C should be, so we had to pick one anyway.
+    fn lost_writers_in_stalemate(&self) -> bool {
+        false
+    }
 }
@@ -1,62 +1,181 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::Settings,
+    config::{MergeStrategyKind, Settings},
     error::ConnResult,
+    replay::save::{CaptureSettings, DivergedStream, StreamCapture},
     replay::streams::MReplayRef,
-    replay::streams::{read_data, read_header, WriterReplay},
+    replay::streams::{read_data, read_header, MemoryBudget, SeekIndex, WriterReplay},
+    replay::timeline::{Timeline, TimelineEvent},
     server::connection::Connection,
+    util::buf_traits::DiscontiguousBuf,
     util::timeout::{cancellable, until},
 };
 
-use super::{merge_strategy::MergeStrategy, quorum_merge_strategy::QuorumMergeStrategy, replay_delay::StreamDelay};
+use super::{
+    divergence::DivergenceLog,
+    longest_writer_merge_strategy::LongestWriterMergeStrategy,
+    merge_strategy::MergeStrategy,
+    quorum_merge_strategy::QuorumMergeStrategy,
+    replay_delay::{StreamDelay, TierDelay},
+};
 
 pub struct ReplayMerger {
     shutdown_token: CancellationToken,
-    // Will be a boxed trait if ever needed.
-    merge_strategy: RefCell<QuorumMergeStrategy>,
+    merge_strategy: RefCell<Box<dyn MergeStrategy>>,
     stream_delay: StreamDelay,
+    tier_delay: TierDelay,
+    timeline: Timeline,
+    divergences: Rc<DivergenceLog>,
+    capture_settings: Option<CaptureSettings>, // Set if we capture writer streams.
+    captures: RefCell<HashMap<u64, StreamCapture>>,
 }
 
 impl ReplayMerger {
-    pub fn new(shutdown_token: CancellationToken, config: Settings) -> Self {
-        let stream_delay = StreamDelay::new(config.replay.delay_s, config.replay.update_interval_s);
-        let merge_strategy = RefCell::new(QuorumMergeStrategy::new(
-            config.replay.merge_quorum_size,
-            config.replay.stream_comparison_distance_b,
-        ));
+    pub fn new(shutdown_token: CancellationToken, config: Settings, timeline: Timeline) -> Self {
+        let stream_delay = StreamDelay::from_config(&config);
+        let tier_delay = TierDelay::from_config(&config);
+        let divergences = Rc::new(DivergenceLog::new(timeline.clone()));
+        let merge_strategy = RefCell::new(Self::new_merge_strategy(&config, timeline.clone(), divergences.clone()));
+        if let Some(budget) = MemoryBudget::from_config(&config) {
+            let merged = merge_strategy.borrow().get_merged_replay();
+            merged.borrow_mut().set_memory_budget(budget);
+        }
+        tier_delay.prepare(&merge_strategy.borrow().get_merged_replay());
+        if config.replay.seek_index_interval_ticks > 0 {
+            let merged = merge_strategy.borrow().get_merged_replay();
+            merged
+                .borrow_mut()
+                .set_seek_index(SeekIndex::new(config.replay.seek_index_interval_ticks));
+        }
+        let capture_settings = CaptureSettings::from_config(&config);
         Self {
             shutdown_token,
             merge_strategy,
             stream_delay,
+            tier_delay,
+            timeline,
+            divergences,
+            capture_settings,
+            captures: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn new_merge_strategy(
+        config: &Settings,
+        timeline: Timeline,
+        divergences: Rc<DivergenceLog>,
+    ) -> Box<dyn MergeStrategy> {
+        match config.replay.merge_strategy {
+            MergeStrategyKind::Quorum => Box::new(QuorumMergeStrategy::new(
+                config.replay.merge_quorum_size,
+                config.replay.stream_comparison_distance_b,
+                timeline,
+                divergences,
+            )),
+            MergeStrategyKind::LongestWriter => Box::new(LongestWriterMergeStrategy::new(
+                config.replay.stream_comparison_distance_b,
+                divergences,
+            )),
         }
     }
 
     pub async fn handle_connection(&self, c: &mut Connection) {
         let replay = Rc::new(RefCell::new(WriterReplay::new()));
+        self.stream_delay.prepare(&replay);
         let token = self.merge_strategy.borrow_mut().replay_added(replay.clone());
+        let name = c.get_header().name;
+        self.divergences.set_writer_name(token, name.clone());
+        self.timeline.record(TimelineEvent::WriterConnected {
+            writer: token,
+            name: name.clone(),
+        });
+
+        let mut capture = self.start_capture().await;
 
         let read_from_connection = async {
             read_header(replay.clone(), c).await?;
+            self.timeline.record(TimelineEvent::HeaderReceived { writer: token });
+            let mut sink = tokio::io::sink();
+            let copy_to: &mut (dyn AsyncWrite + Unpin) = match capture.as_mut() {
+                Some(cap) => {
+                    let header = replay.borrow().get_header().unwrap().data.clone();
+                    cap.write_all(&header).await?;
+                    cap
+                }
+                None => &mut sink,
+            };
             self.merge_strategy.borrow_mut().replay_header_added(token);
             until(
                 self.stream_delay.track(&replay, &self.merge_strategy, token),
-                read_data(replay.clone(), c),
+                read_data(replay.clone(), c, copy_to),
             )
             .await;
             ConnResult::Ok(())
         };
         cancellable(read_from_connection, &self.shutdown_token).await;
+        if let Some(cap) = capture {
+            self.captures.borrow_mut().insert(token, cap);
+        }
 
         self.stream_delay.set_to_end(&replay, &self.merge_strategy, token);
         replay.borrow_mut().finish();
         self.merge_strategy.borrow_mut().replay_removed(token);
+        let data_len = replay.borrow().get_data().len();
+        self.timeline.record(TimelineEvent::WriterDisconnected {
+            writer: token,
+            name,
+            data_len,
+        });
     }
 
-    pub fn finalize(&self) {
+    // Run until finalize().
+    pub async fn track_delay_tiers(&self) {
+        self.tier_delay.track(&self.get_merged_replay()).await
+    }
+
+    pub async fn finalize(&self) {
         self.merge_strategy.borrow_mut().finish();
+        self.divergences.wait_for_ticks().await;
+        let merged = self.get_merged_replay();
+        let merged = merged.borrow();
+        self.timeline.record(TimelineEvent::MergeFinished {
+            header_len: merged.header_len(),
+            data_len: merged.get_data().len(),
+        });
+    }
+
+    pub fn lost_writers_in_stalemate(&self) -> bool {
+        self.merge_strategy.borrow().lost_writers_in_stalemate()
+    }
+
+    async fn start_capture(&self) -> Option<StreamCapture> {
+        self.capture_settings
+            .as_ref()?
+            .start_capture()
+            .await
+            .map_err(|e| log::warn!("Failed to start capturing writer stream: {}", e))
+            .ok()
+    }
+
+    // Writers can diverge after they end, so we can only pick these after finalize().
+    pub async fn take_diverged_streams(&self) -> Vec<DivergedStream> {
+        let mut captures = std::mem::take(&mut *self.captures.borrow_mut());
+        let mut streams = Vec::new();
+        for d in self.timeline.divergences().into_iter().filter(|d| d.is_suspicious()) {
+            let capture = match captures.remove(&d.writer) {
+                Some(c) => c,
+                None => continue,
+            };
+            match capture.finish(d).await {
+                Ok(s) => streams.push(s),
+                Err(e) => log::warn!("Failed to compress diverged writer stream: {}", e),
+            }
+        }
+        streams
     }
 
     pub fn get_merged_replay(&self) -> MReplayRef {
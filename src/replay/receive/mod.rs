@@ -1,5 +1,9 @@
+pub mod divergence;
+mod header_quorum;
+mod longest_writer_merge_strategy;
 mod merge_strategy;
 mod merger;
 mod quorum_merge_strategy;
 mod replay_delay;
+mod replay_state;
 pub use self::merger::ReplayMerger;
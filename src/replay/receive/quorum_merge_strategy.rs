@@ -1,11 +1,20 @@
 use std::{cell::RefCell, collections::HashMap, collections::HashSet, rc::Rc};
 
 use crate::{
-    replay::streams::MReplayRef, replay::streams::MergedReplay, replay::streams::WReplayRef,
-    util::buf_traits::DiscontiguousBuf, util::buf_traits::DiscontiguousBufExt,
+    replay::streams::MReplayRef,
+    replay::streams::MergedReplay,
+    replay::streams::WReplayRef,
+    replay::timeline::{Timeline, TimelineEvent},
+    util::buf_traits::DiscontiguousBuf,
+    util::buf_traits::DiscontiguousBufExt,
 };
 
-use super::merge_strategy::MergeStrategy;
+use super::{
+    divergence::{DivergenceLog, DivergenceReason},
+    header_quorum::HeaderQuorum,
+    merge_strategy::MergeStrategy,
+    replay_state::ReplayState,
+};
 
 // This merge strategy tries to merge replays in such a way that at least N replays agree on the
 // merged data. To do that, it selects a subset of N replays called a quorum and compares their
@@ -19,144 +28,12 @@ use super::merge_strategy::MergeStrategy;
 // * Parameters:
 //   * stream_cmp_distance, in bytes,
 //   * target_quorum_size, in count.
-
-// For a replay r in R, we define:
-// * r matches C iff C's data is a (non-strict) prefix of r's data.
-// * r in R diverges from C iff either:
-//   * Common prefix of r and C is not equal to either r or C,
-//   * r has less data than C and r is finished.
 //
-// Intuitively:
-//  * r matching C means that its data "agrees" with all canonical data.
-//  * r diverging from C means that either r's data doesn't match C's at some point or r ended
-//    early, so we can't use it for further merging.
-// * If neither is true, then r has the same data as C, but has less of it and is not finished.
+// Headers are picked by a quorum as well, see header_quorum.rs. Replays with a header that
+// disagrees with the canonical header diverge from C.
 //
-// In order to avoid matching a lot of data, we make a following "shortcut" assumption:
-// * As long as, every time we check, r and C's data is equal at a suffix of stream_cmp_distance
-//   bytes of C, r does not diverge from C.
-// In other words, whenever we check if r diverges from C, we can check just the last
-// stream_cmp_distance bytes.
-//
-// We keep the state of a replay r in R in the struct below:
-
-struct ReplayState {
-    replay: WReplayRef,         // Writer replay, updated from connection in another task.
-    canon_replay: MReplayRef,   // Canonical replay C.
-    stream_cmp_distance: usize, // As defined above.
-
-    // Fields used to lazily check relation of r towards C.
-    data_matching_canon: usize,
-    diverges: bool,
-}
-
-// And we check its diverge status (along with a helper functions for comparing streams and other
-// stuff) here. Proving correctness is not hard and left to the reader.
-
-// TODO test this in isolation.
-impl ReplayState {
-    fn new(replay: WReplayRef, canon_replay: MReplayRef, stream_cmp_distance: usize) -> Self {
-        Self {
-            replay,
-            canon_replay,
-            stream_cmp_distance,
-            data_matching_canon: 0,
-            diverges: false,
-        }
-    }
-    fn data_len(&self) -> usize {
-        self.replay.borrow().get_data().len()
-    }
-
-    fn canon_len(&self) -> usize {
-        self.canon_replay.borrow().get_data().len()
-    }
-
-    fn common_len(&self) -> usize {
-        std::cmp::min(self.canon_len(), self.data_len())
-    }
-
-    fn delayed_data_len(&self) -> usize {
-        self.replay.borrow().get_delayed_data_len()
-    }
-
-    fn is_finished(&self) -> bool {
-        self.replay.borrow().is_finished()
-    }
-
-    fn common_prefix_from(&self, other: &ReplayState, start: usize) -> usize {
-        self.replay
-            .borrow()
-            .get_data()
-            .common_prefix_from(other.replay.borrow().get_data(), start)
-    }
-
-    fn diverges_from_canon(&mut self) -> bool {
-        self.match_with_canon_stream();
-        self.diverges
-    }
-
-    fn canon_match_start(&self) -> usize {
-        debug_assert!(self.data_matching_canon <= self.common_len());
-        let optimized_match_start = self.common_len().saturating_sub(self.stream_cmp_distance);
-        std::cmp::max(self.data_matching_canon, optimized_match_start)
-    }
-
-    fn set_diverged(&mut self) {
-        self.replay.borrow_mut().discard_all();
-        self.diverges = true;
-    }
-
-    fn match_with_canon_stream(&mut self) {
-        if self.diverges {
-            return;
-        }
-        if self.data_len() < self.canon_len() && self.is_finished() {
-            self.set_diverged();
-            return;
-        }
-        if self.data_matching_canon == self.common_len() {
-            return;
-        }
-
-        let match_start = self.canon_match_start(); // this borrows
-        self.data_matching_canon = self
-            .replay
-            .borrow()
-            .get_data()
-            .common_prefix_from(self.canon_replay.borrow().get_data(), match_start);
-
-        if self.data_matching_canon != self.common_len() {
-            self.set_diverged();
-        }
-
-        self.discard_unneeded_data();
-    }
-
-    // To save memory, we discard data before canon_match_start(), or all data if we diverged.
-    // It is an error to access replay data from before canonical replay's length, and it's an
-    // error to access any data of a diverged replay.
-    fn discard_unneeded_data(&mut self) {
-        // If we diverged, we already discarded everything
-        if !self.diverges {
-            let match_start = self.canon_match_start(); // this borrows
-            self.replay.borrow_mut().discard(match_start);
-        }
-    }
-
-    // For when we *know* the replay matches.
-    fn explicitly_set_matching(&mut self) {
-        debug_assert!(!self.diverges);
-        debug_assert!(self.data_len() >= self.canon_len());
-        self.data_matching_canon = self.canon_len();
-        self.discard_unneeded_data();
-    }
-
-    // For when we *know* the replay does not match.
-    fn explicitly_set_diverged(&mut self) {
-        self.set_diverged();
-    }
-}
+// For a replay r in R, we use the definitions of r matching and diverging from C, along with the
+// shortcut for checking them, from replay_state.rs.
 
 // Now, the actual merge strategy.
 //
@@ -165,26 +42,45 @@ struct SharedState {
     token: u64,
     stream_cmp_distance: usize,
     delayed_data_started: bool,
+    lost_writers_in_stalemate: bool,
     target_quorum_size: usize,
     replays: HashMap<u64, ReplayState>,
+    headers: HeaderQuorum,
     canonical_stream: MReplayRef,
+    timeline: Timeline,
+    divergences: Rc<DivergenceLog>,
 }
 
 impl SharedState {
-    fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
+    fn new(
+        target_quorum_size: usize,
+        stream_cmp_distance: usize,
+        timeline: Timeline,
+        divergences: Rc<DivergenceLog>,
+    ) -> Self {
         Self {
             token: 0,
             stream_cmp_distance,
             delayed_data_started: false,
+            lost_writers_in_stalemate: false,
             target_quorum_size,
             replays: HashMap::new(),
+            headers: HeaderQuorum::new(target_quorum_size),
             canonical_stream: Rc::new(RefCell::new(MergedReplay::new())),
+            timeline,
+            divergences,
         }
     }
 
     fn add_replay(&mut self, r: WReplayRef) -> u64 {
         let token = self.token;
-        let replay = ReplayState::new(r, self.canonical_stream.clone(), self.stream_cmp_distance);
+        let replay = ReplayState::new(
+            token,
+            r,
+            self.canonical_stream.clone(),
+            self.stream_cmp_distance,
+            self.divergences.clone(),
+        );
         self.replays.insert(token, replay);
         self.token += 1;
         token
@@ -217,6 +113,36 @@ impl SharedState {
         }
     }
 
+    // Returns false if the header disagrees with the canonical header.
+    fn add_header(&mut self, id: u64) -> bool {
+        let header = self.get_replay(id).replay.borrow_mut().take_header();
+        self.headers.add_header(id, header)
+    }
+
+    // Returns replays that disagree with the canonical header.
+    fn decide_header(&mut self) -> Vec<u64> {
+        match self.headers.decide() {
+            None => Vec::new(),
+            Some((header, rejected)) => {
+                if let Some(p) = header.parsed.as_ref() {
+                    let players: Vec<&str> = p.players.iter().map(|p| &p.name[..]).collect();
+                    log::debug!("Picked replay header for map {}, players {:?}", p.map_path, players);
+                }
+                self.canonical_stream.borrow_mut().add_header(header);
+                rejected
+            }
+        }
+    }
+
+    fn reject_header(&mut self, id: u64) {
+        log::warn!(
+            "Writer replay {} sent a header that disagrees with the canonical one, ignoring it",
+            id
+        );
+        self.get_mut_replay(id)
+            .explicitly_set_diverged(DivergenceReason::Header, None);
+    }
+
     fn update_merged_delayed_data_len(&mut self, mut hint: usize) {
         hint = std::cmp::min(hint, self.merged_data_len());
         if hint <= self.merged_delayed_data_len() {
@@ -257,6 +183,9 @@ pub struct MergeStalemateState {
 //   * Stalemate stays unresolved as long as no delayed positions have ever been set. (This is a
 //     hack to prevent too eager stalemate resolution at the start, when replays are still being
 //     added.)
+//   * Stalemate stays unresolved until the canonical header is picked. It is picked once the
+//     header quorum is reached or, at the latest, once delayed positions are first set. Replays
+//     with a different header are removed from Cand and Res.
 //   * If one of entries in Cand has at least target_quorum_size entries, the stalemate can be
 //     resolved.
 //   * Otherwise stalemate can resolved if set Res is empty and map Cand is not empty.
@@ -268,9 +197,14 @@ pub struct MergeStalemateState {
 //   * Notice that all replays in G agree with C and all replays outside G and Res diverge from C.
 
 impl MergeStalemateState {
-    fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
+    fn new(
+        target_quorum_size: usize,
+        stream_cmp_distance: usize,
+        timeline: Timeline,
+        divergences: Rc<DivergenceLog>,
+    ) -> Self {
         Self {
-            s: SharedState::new(target_quorum_size, stream_cmp_distance),
+            s: SharedState::new(target_quorum_size, stream_cmp_distance, timeline, divergences),
             candidates: HashMap::new(),
             reserve: HashSet::new(),
         }
@@ -332,6 +266,34 @@ impl MergeStalemateState {
             return;
         }
         self.s.delayed_data_started = true;
+        // We can't wait for headers any longer.
+        if !self.s.headers.is_decided() {
+            self.decide_header();
+        }
+    }
+
+    fn replay_header_added(&mut self, id: u64) {
+        if !self.s.add_header(id) {
+            self.reject_header(id);
+        }
+        if !self.s.headers.is_decided() && self.s.headers.has_quorum() {
+            self.decide_header();
+        }
+    }
+
+    fn decide_header(&mut self) {
+        for id in self.s.decide_header() {
+            self.reject_header(id);
+        }
+    }
+
+    fn reject_header(&mut self, id: u64) {
+        self.s.reject_header(id);
+        self.reserve.remove(&id);
+        for cands in self.candidates.values_mut() {
+            cands.retain(|c| *c != id);
+        }
+        self.candidates.retain(|_, cands| !cands.is_empty());
     }
 
     fn replay_ended(&mut self, id: u64) {
@@ -346,6 +308,10 @@ impl MergeStalemateState {
             // Wait as long as we can at the start
             return false;
         }
+        if !self.s.headers.is_decided() {
+            // Can't merge data before we have a header
+            return false;
+        }
         if self.candidates.is_empty() {
             // We have no candidates yet
             return false;
@@ -358,6 +324,16 @@ impl MergeStalemateState {
         self.candidates.values().map(|c| c.len()).max().unwrap() >= self.s.target_quorum_size
     }
 
+    // Candidates disagreed and none of them reached a quorum before all of them ended.
+    fn lost_writers_before_quorum(&self, good_replays: &[u64]) -> bool {
+        !self.candidates.is_empty()
+            && good_replays.len() < self.s.target_quorum_size
+            && good_replays
+                .iter()
+                .chain(self.candidates.values().flatten())
+                .all(|id| self.s.get_replay(*id).is_finished())
+    }
+
     fn exit_stalemate(mut self) -> MergeQuorumState {
         debug_assert!(!self.candidates.is_empty());
 
@@ -372,6 +348,9 @@ impl MergeStalemateState {
             .2;
 
         let good_replays = self.candidates.remove(&best_byte).unwrap();
+        if self.lost_writers_before_quorum(&good_replays) {
+            self.s.lost_writers_in_stalemate = true;
+        }
 
         // Advance replay by 1 byte
         let good_replay = *good_replays.get(0).unwrap();
@@ -384,7 +363,9 @@ impl MergeStalemateState {
         // Discard all the rest, they diverge
         for (_, v) in self.candidates.iter() {
             for id in v.iter() {
-                self.s.get_mut_replay(*id).explicitly_set_diverged();
+                self.s
+                    .get_mut_replay(*id)
+                    .explicitly_set_diverged(DivergenceReason::Data, Some(byte_pos));
             }
         }
         MergeQuorumState::from_stalemate(self.s, good_replays, self.reserve)
@@ -476,6 +457,16 @@ impl MergeQuorumState {
         // pass, we don't care
     }
 
+    fn replay_header_added(&mut self, id: u64) {
+        // We picked the header before leaving the first stalemate. The replay only sent its header,
+        // so it can only be in reserve.
+        if !self.s.add_header(id) {
+            debug_assert!(!self.quorum.contains(&id));
+            self.s.reject_header(id);
+            self.reserve.remove(&id);
+        }
+    }
+
     fn has_to_enter_stalemate(&self) -> bool {
         self.s.merged_delayed_data_len() >= self.s.merged_data_len()
     }
@@ -544,8 +535,18 @@ macro_rules! both {
 }
 
 impl QuorumMergeStrategy {
-    pub fn new(target_quorum_size: usize, stream_cmp_distance: usize) -> Self {
-        Self::Stalemate(MergeStalemateState::new(target_quorum_size, stream_cmp_distance))
+    pub fn new(
+        target_quorum_size: usize,
+        stream_cmp_distance: usize,
+        timeline: Timeline,
+        divergences: Rc<DivergenceLog>,
+    ) -> Self {
+        Self::Stalemate(MergeStalemateState::new(
+            target_quorum_size,
+            stream_cmp_distance,
+            timeline,
+            divergences,
+        ))
     }
 
     fn should_change_state(&self) -> bool {
@@ -564,7 +565,13 @@ impl QuorumMergeStrategy {
                 Self::Quorum(s) => Self::Stalemate(s.enter_stalemate()),
                 Self::Stalemate(s) => Self::Quorum(s.exit_stalemate()),
                 Self::Swapping => panic!("Programmer error - we're swapping state right now!"),
-            }
+            };
+            let canonical_len = both!(self, s => s.s.merged_data_len());
+            let event = match self {
+                Self::Quorum(..) => TimelineEvent::QuorumReached { canonical_len },
+                _ => TimelineEvent::StalemateEntered { canonical_len },
+            };
+            both!(self, s => s.s.timeline.record(event));
         }
     }
 }
@@ -582,15 +589,8 @@ impl MergeStrategy for QuorumMergeStrategy {
     }
 
     fn replay_header_added(&mut self, id: u64) {
-        // Accept the first header we get.
-        // As a bonus, this guarantees that canonical stream will be in data stage once we start
-        // merging data.
-        let replay = both!(self, s => s.s.get_replay(id));
-        let header = replay.replay.borrow_mut().take_header();
-        let mut canonical_stream = both!(self, s => s.s.canonical_stream.borrow_mut());
-        if canonical_stream.get_header().is_none() {
-            canonical_stream.add_header(header);
-        }
+        both!(self, s => s.replay_header_added(id));
+        self.work_state_until_stable();
     }
 
     fn replay_data_updated(&mut self, id: u64) {
@@ -602,6 +602,10 @@ impl MergeStrategy for QuorumMergeStrategy {
         both!(self, s => s.s.canonical_stream.clone())
     }
 
+    fn lost_writers_in_stalemate(&self) -> bool {
+        both!(self, s => s.s.lost_writers_in_stalemate)
+    }
+
     fn finish(&mut self) {
         // We know that delayed position for all replays is at the end of their data.
         // If we were in a quorum state, then delayed position of merged replay would equal its
@@ -613,6 +617,10 @@ impl MergeStrategy for QuorumMergeStrategy {
             Self::Swapping => panic!("Programmer error - we're swapping state right now!"),
             Self::Quorum(..) => panic!("Expected to finish merge strategy in a stalemate"),
             Self::Stalemate(s) => {
+                // If no replay sent any data, we might not have picked a header yet.
+                if !s.s.headers.is_decided() {
+                    s.decide_header();
+                }
                 // Not in a quorum. Delayed replay position must be equal to its data len.
                 let data_len = s.s.merged_data_len();
                 let position = s.s.merged_delayed_data_len();
@@ -697,6 +705,7 @@ mod tests {
     use rand::Rng;
 
     use super::QuorumMergeStrategy;
+    use crate::replay::{receive::divergence::DivergenceLog, timeline::Timeline};
     use crate::util::{buf_traits::ReadAtExt, test::setup_logging};
     use crate::{
         replay::receive::merge_strategy::MergeStrategy, replay::streams::ReplayHeader, replay::streams::WriterReplay,
@@ -704,8 +713,14 @@ mod tests {
     };
     use std::{cell::RefCell, io::Read, rc::Rc};
 
+    fn strat_with_quorum(target_quorum_size: usize, stream_cmp_distance: usize) -> QuorumMergeStrategy {
+        let timeline = Timeline::new();
+        let divergences = Rc::new(DivergenceLog::new(timeline.clone()));
+        QuorumMergeStrategy::new(target_quorum_size, stream_cmp_distance, timeline, divergences)
+    }
+
     fn strat() -> QuorumMergeStrategy {
-        QuorumMergeStrategy::new(2, 4096)
+        strat_with_quorum(2, 4096)
     }
 
     #[test]
@@ -728,7 +743,7 @@ mod tests {
         let mut strat = strat();
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
         let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
-        stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
 
         let token1 = strat.replay_added(stream1.clone());
         let token2 = strat.replay_added(stream2.clone());
@@ -749,7 +764,7 @@ mod tests {
     fn test_strategy_gets_all_data_of_one() {
         let mut strat = strat();
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
-        stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
 
         let token1 = strat.replay_added(stream1.clone());
         strat.replay_header_added(token1);
@@ -785,8 +800,8 @@ mod tests {
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
         let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
 
-        stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
-        stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
         let token1 = strat.replay_added(stream1.clone());
         let token2 = strat.replay_added(stream2.clone());
         strat.replay_header_added(token1);
@@ -839,8 +854,8 @@ mod tests {
         let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
         let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
 
-        stream1.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
-        stream2.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
         let token1 = strat.replay_added(stream1.clone());
         let token2 = strat.replay_added(stream2.clone());
         strat.replay_header_added(token1);
@@ -870,10 +885,52 @@ mod tests {
         assert_eq!(out_buf, &[1, 2, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn test_strategy_ignores_replays_with_different_header() {
+        let mut strat = strat();
+        let stream1 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream2 = Rc::new(RefCell::new(WriterReplay::new()));
+        let stream3 = Rc::new(RefCell::new(WriterReplay::new()));
+
+        stream1.borrow_mut().add_header(ReplayHeader::new(vec![6, 6, 6]));
+        stream2.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        stream3.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
+        let token1 = strat.replay_added(stream1.clone());
+        let token2 = strat.replay_added(stream2.clone());
+        let token3 = strat.replay_added(stream3.clone());
+        strat.replay_header_added(token1);
+        strat.replay_header_added(token2);
+        strat.replay_header_added(token3);
+
+        stream1.borrow_mut().add_data(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        stream1.borrow_mut().set_delayed_data_len(8);
+        strat.replay_data_updated(token1);
+        for (s, t) in [(&stream2, token2), (&stream3, token3)] {
+            s.borrow_mut().add_data(&[1, 2, 3, 4]);
+            s.borrow_mut().set_delayed_data_len(4);
+            strat.replay_data_updated(t);
+        }
+
+        for (s, t) in [(&stream1, token1), (&stream2, token2), (&stream3, token3)] {
+            s.borrow_mut().finish();
+            strat.replay_removed(t);
+        }
+        strat.finish();
+
+        let out_stream_ref = strat.get_merged_replay();
+        let out_stream = out_stream_ref.borrow();
+        assert_eq!(out_stream.get_header().unwrap().data, vec![1, 3, 3, 7]);
+        let out_data = out_stream.get_data();
+        assert_eq!(out_data.len(), 4);
+        let out_buf: &mut [u8] = &mut [0; 4];
+        out_data.reader().read_exact(out_buf).unwrap();
+        assert_eq!(out_buf, &[1, 2, 3, 4]);
+    }
+
     // FIXME tweak so we can test small comparison cutoffs.
     fn simple_fuzzing_round() {
         let mut rng = rand::thread_rng();
-        let mut strat = QuorumMergeStrategy::new(2, 512);
+        let mut strat = strat_with_quorum(2, 512);
         let count = 8;
         let chunk = 4;
         let replay_len = 400;
@@ -886,7 +943,7 @@ mod tests {
         // Add all streams.
         for _ in 0..count {
             let stream = Rc::new(RefCell::new(WriterReplay::new()));
-            stream.borrow_mut().add_header(ReplayHeader { data: vec![1, 3, 3, 7] });
+            stream.borrow_mut().add_header(ReplayHeader::new(vec![1, 3, 3, 7]));
             let token = strat.replay_added(stream.clone());
             strat.replay_header_added(token);
             let data: Vec<u8> = Vec::new();
@@ -1,6 +1,7 @@
 use std::{cell::RefCell, collections::VecDeque};
 
-use crate::replay::streams::WReplayRef;
+use crate::config::{DelayMode, Settings};
+use crate::replay::streams::{MReplayRef, TickPoint, WReplayRef};
 use crate::util::buf_traits::DiscontiguousBuf;
 
 use tokio::time::Duration;
@@ -8,7 +9,6 @@ use tokio::time::Duration;
 use super::merge_strategy::MergeStrategy;
 
 pub struct PositionHistory {
-    sleep_s: Duration,
     history_size: usize,
     queue: VecDeque<usize>,
 }
@@ -17,7 +17,6 @@ impl PositionHistory {
     pub fn new(delay_s: Duration, sleep_s: Duration) -> Self {
         let history_size = Self::history_size(delay_s, sleep_s);
         Self {
-            sleep_s,
             history_size,
             queue: VecDeque::new(),
         }
@@ -41,40 +40,120 @@ impl PositionHistory {
         }
         *self.queue.front().unwrap()
     }
+}
+
+// Generous bound on how fast a game can run, in ticks per second of wall clock time. Normal sim
+// speed is 10.
+const MAX_TICKS_PER_SECOND: f64 = 100.0;
+
+// Keeps the delayed position delay_ticks game ticks behind the newest tick, so that pauses and
+// sim speed changes don't change how much of the game the delay hides. The delayed position is
+// always right after a tick advance.
+//
+// Writers are not trusted, so ticks are counted no faster than MAX_TICKS_PER_SECOND. Otherwise a
+// writer could skip the delay by sending lots of tick advances at once.
+pub struct TickHistory {
+    delay_ticks: u32,
+    max_ticks_per_push: u32,
+    queue: VecDeque<TickPoint>,
+    newest: u32,
+    delayed: usize,
+}
+
+impl TickHistory {
+    pub fn new(delay_ticks: u32, sleep_s: Duration) -> Self {
+        let max_ticks_per_push = (MAX_TICKS_PER_SECOND * sleep_s.as_secs_f64()).ceil() as u32;
+        Self {
+            delay_ticks,
+            max_ticks_per_push: max_ticks_per_push.max(1),
+            queue: VecDeque::new(),
+            newest: 0,
+            delayed: 0,
+        }
+    }
+
+    /* Push new tick advances and receive a position from delay_ticks ticks back. */
+    pub fn push_and_get_delayed(&mut self, points: VecDeque<TickPoint>) -> usize {
+        self.queue.extend(points);
+        let newest = match self.queue.back() {
+            Some(p) => p.tick,
+            None => return self.delayed,
+        };
+        self.newest = newest.min(self.newest.saturating_add(self.max_ticks_per_push));
+        while let Some(p) = self.queue.front() {
+            if p.tick.saturating_add(self.delay_ticks) > self.newest {
+                break;
+            }
+            self.delayed = p.offset;
+            self.queue.pop_front();
+        }
+        self.delayed
+    }
+}
+
+enum DelayHistory {
+    WallClock(PositionHistory),
+    GameTicks(TickHistory),
+}
 
-    pub async fn wait_cycle(&self) {
-        tokio::time::sleep(self.sleep_s).await;
+impl DelayHistory {
+    fn push_and_get_delayed(&mut self, replay: &WReplayRef) -> usize {
+        match self {
+            Self::WallClock(h) => h.push_and_get_delayed(replay.borrow().get_data().len()),
+            Self::GameTicks(h) => h.push_and_get_delayed(replay.borrow_mut().take_tick_points()),
+        }
     }
 }
 
 pub struct StreamDelay {
+    mode: DelayMode,
     delay_s: Duration,
+    delay_ticks: u32,
     sleep_s: Duration,
 }
 
 impl StreamDelay {
-    pub fn new(delay_s: Duration, sleep_s: Duration) -> Self {
-        Self { delay_s, sleep_s }
+    pub fn from_config(config: &Settings) -> Self {
+        Self {
+            mode: config.replay.delay_mode,
+            delay_s: config.replay.delay_s,
+            delay_ticks: config.replay.delay_ticks,
+            sleep_s: config.replay.update_interval_s,
+        }
     }
 
-    pub async fn track(&self, replay: &WReplayRef, strategy: &RefCell<impl MergeStrategy>, token: u64) {
-        let mut pos_queue = PositionHistory::new(self.delay_s, self.sleep_s);
+    // Call before the replay gets any data.
+    pub fn prepare(&self, replay: &WReplayRef) {
+        if self.mode == DelayMode::GameTicks {
+            replay.borrow_mut().track_ticks();
+        }
+    }
+
+    fn new_history(&self) -> DelayHistory {
+        match self.mode {
+            DelayMode::WallClock => DelayHistory::WallClock(PositionHistory::new(self.delay_s, self.sleep_s)),
+            DelayMode::GameTicks => DelayHistory::GameTicks(TickHistory::new(self.delay_ticks, self.sleep_s)),
+        }
+    }
+
+    pub async fn track(&self, replay: &WReplayRef, strategy: &RefCell<Box<dyn MergeStrategy>>, token: u64) {
+        let mut history = self.new_history();
         let mut prev_current = 0;
         let mut prev_delayed = 0;
         loop {
             let current = replay.borrow().get_data().len();
-            let delayed = pos_queue.push_and_get_delayed(current);
+            let delayed = history.push_and_get_delayed(replay);
             replay.borrow_mut().set_delayed_data_len(delayed);
             if (current, delayed) != (prev_current, prev_delayed) {
                 strategy.borrow_mut().replay_data_updated(token);
             }
             prev_current = current;
             prev_delayed = delayed;
-            pos_queue.wait_cycle().await;
+            tokio::time::sleep(self.sleep_s).await;
         }
     }
 
-    pub fn set_to_end(&self, replay: &WReplayRef, strategy: &RefCell<impl MergeStrategy>, token: u64) {
+    pub fn set_to_end(&self, replay: &WReplayRef, strategy: &RefCell<Box<dyn MergeStrategy>>, token: u64) {
         let final_len = replay.borrow_mut().get_data().len();
         let last_delayed_len = replay.borrow_mut().get_delayed_data_len();
         if final_len > last_delayed_len {
@@ -83,3 +162,117 @@ impl StreamDelay {
         }
     }
 }
+
+// Moves delay tiers' delayed positions in the merged replay. Merged data is only as far behind
+// live as merging needs, so we delay it further by each tier's delay.
+pub struct TierDelay {
+    tiers: Vec<(String, Duration)>,
+    sleep_s: Duration,
+}
+
+impl TierDelay {
+    pub fn from_config(config: &Settings) -> Self {
+        Self {
+            tiers: config
+                .replay
+                .delay_tiers
+                .iter()
+                .map(|t| (t.name.clone(), t.delay_s))
+                .collect(),
+            sleep_s: config.replay.update_interval_s,
+        }
+    }
+
+    // Call before the replay gets any data.
+    pub fn prepare(&self, replay: &MReplayRef) {
+        for (name, _) in self.tiers.iter() {
+            replay.borrow_mut().add_delay_tier(name.clone());
+        }
+    }
+
+    // Never returns, stop it once the replay is finished.
+    pub async fn track(&self, replay: &MReplayRef) {
+        if self.tiers.is_empty() {
+            return futures::future::pending().await;
+        }
+        let mut histories: Vec<PositionHistory> = self
+            .tiers
+            .iter()
+            .map(|(_, delay_s)| PositionHistory::new(*delay_s, self.sleep_s))
+            .collect();
+        loop {
+            let current = replay.borrow().get_data().len();
+            for ((name, _), history) in self.tiers.iter().zip(histories.iter_mut()) {
+                let delayed = history.push_and_get_delayed(current);
+                replay.borrow_mut().advance_tier_delayed_data(name, delayed);
+            }
+            tokio::time::sleep(self.sleep_s).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::streams::{WriterReplay, MAX_ADVANCE_TICKS};
+    use crate::util::test::get_file;
+    use faf_replay_parser::scfa::parser::parse_body_ticks;
+    use std::rc::Rc;
+
+    fn points(ticks: &[u32]) -> VecDeque<TickPoint> {
+        ticks
+            .iter()
+            .map(|&tick| TickPoint {
+                tick,
+                offset: tick as usize * 10,
+                source: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tick_delay_follows_game_ticks() {
+        let mut history = TickHistory::new(5, Duration::from_secs(1));
+        assert_eq!(history.push_and_get_delayed(points(&[1, 2, 3])), 0);
+        assert_eq!(history.push_and_get_delayed(points(&[4, 5, 6, 7])), 20);
+        // Game is paused, no ticks.
+        assert_eq!(history.push_and_get_delayed(points(&[])), 20);
+        assert_eq!(history.push_and_get_delayed(points(&[])), 20);
+        assert_eq!(
+            history.push_and_get_delayed(points(&[8, 9, 10, 11, 12, 13, 14, 15])),
+            100
+        );
+    }
+
+    #[test]
+    fn test_tick_delay_of_writer_replay() {
+        let body = get_file("example_body");
+        let replay = Rc::new(RefCell::new(WriterReplay::new()));
+        replay.borrow_mut().track_ticks();
+        let mut history = DelayHistory::GameTicks(TickHistory::new(100, Duration::from_secs(1)));
+        for chunk in body.chunks(1000) {
+            replay.borrow_mut().add_data(chunk);
+            let delayed = history.push_and_get_delayed(&replay);
+            let current_ticks = parse_body_ticks(&mut &body[..replay.borrow().get_data().len()]).unwrap();
+            let delayed_ticks = parse_body_ticks(&mut &body[..delayed]).unwrap();
+            assert_eq!(delayed_ticks, current_ticks.saturating_sub(100));
+        }
+    }
+
+    #[test]
+    fn test_tick_delay_is_not_skipped_by_fast_ticks() {
+        let replay = Rc::new(RefCell::new(WriterReplay::new()));
+        replay.borrow_mut().track_ticks();
+        let mut history = DelayHistory::GameTicks(TickHistory::new(3000, Duration::from_secs(1)));
+        let advance = [&[0x00, 0x07, 0x00][..], &MAX_ADVANCE_TICKS.to_le_bytes()].concat();
+        for second in 1..100 {
+            // 10000 ticks a second, while we count at most 100.
+            for _ in 0..100 {
+                replay.borrow_mut().add_data(&advance);
+            }
+            let delayed_ticks = (second * 100usize).saturating_sub(3000);
+            let delayed_advances = delayed_ticks / MAX_ADVANCE_TICKS as usize;
+            assert_eq!(history.push_and_get_delayed(&replay), delayed_advances * advance.len());
+        }
+    }
+}
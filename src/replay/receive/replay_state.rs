@@ -0,0 +1,169 @@
+use std::rc::Rc;
+
+use crate::{
+    replay::streams::MReplayRef, replay::streams::WReplayRef, util::buf_traits::DiscontiguousBuf,
+    util::buf_traits::DiscontiguousBufExt,
+};
+
+use super::divergence::{DivergenceLog, DivergenceReason};
+
+// Bookkeeping of a single writer replay's relation to the canonical replay, shared by merge
+// strategies. We use R and C as defined in merge_strategy.rs, ignoring headers, and a parameter
+// stream_cmp_distance, in bytes.
+//
+// For a replay r in R, we define:
+// * r matches C iff C's data is a (non-strict) prefix of r's data.
+// * r in R diverges from C iff either:
+//   * Common prefix of r and C is not equal to either r or C,
+//   * r has less data than C and r is finished.
+//
+// Intuitively:
+//  * r matching C means that its data "agrees" with all canonical data.
+//  * r diverging from C means that either r's data doesn't match C's at some point or r ended
+//    early, so we can't use it for further merging.
+// * If neither is true, then r has the same data as C, but has less of it and is not finished.
+//
+// In order to avoid matching a lot of data, we make a following "shortcut" assumption:
+// * As long as, every time we check, r and C's data is equal at a suffix of stream_cmp_distance
+//   bytes of C, r does not diverge from C.
+// In other words, whenever we check if r diverges from C, we can check just the last
+// stream_cmp_distance bytes.
+//
+// We keep the state of a replay r in R in the struct below:
+
+pub struct ReplayState {
+    id: u64,                    // ID given by the merge strategy.
+    pub replay: WReplayRef,     // Writer replay, updated from connection in another task.
+    canon_replay: MReplayRef,   // Canonical replay C.
+    stream_cmp_distance: usize, // As defined above.
+    divergences: Rc<DivergenceLog>,
+
+    // Fields used to lazily check relation of r towards C.
+    data_matching_canon: usize,
+    diverges: bool,
+}
+
+// And we check its diverge status (along with a helper functions for comparing streams and other
+// stuff) here. Proving correctness is not hard and left to the reader.
+
+// TODO test this in isolation.
+impl ReplayState {
+    pub fn new(
+        id: u64,
+        replay: WReplayRef,
+        canon_replay: MReplayRef,
+        stream_cmp_distance: usize,
+        divergences: Rc<DivergenceLog>,
+    ) -> Self {
+        Self {
+            id,
+            replay,
+            canon_replay,
+            stream_cmp_distance,
+            divergences,
+            data_matching_canon: 0,
+            diverges: false,
+        }
+    }
+    pub fn data_len(&self) -> usize {
+        self.replay.borrow().get_data().len()
+    }
+
+    fn canon_len(&self) -> usize {
+        self.canon_replay.borrow().get_data().len()
+    }
+
+    fn common_len(&self) -> usize {
+        std::cmp::min(self.canon_len(), self.data_len())
+    }
+
+    pub fn delayed_data_len(&self) -> usize {
+        self.replay.borrow().get_delayed_data_len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.replay.borrow().is_finished()
+    }
+
+    pub fn common_prefix_from(&self, other: &ReplayState, start: usize) -> usize {
+        self.replay
+            .borrow()
+            .get_data()
+            .common_prefix_from(other.replay.borrow().get_data(), start)
+    }
+
+    pub fn diverges_from_canon(&mut self) -> bool {
+        self.match_with_canon_stream();
+        self.diverges
+    }
+
+    fn canon_match_start(&self) -> usize {
+        debug_assert!(self.data_matching_canon <= self.common_len());
+        let optimized_match_start = self.common_len().saturating_sub(self.stream_cmp_distance);
+        std::cmp::max(self.data_matching_canon, optimized_match_start)
+    }
+
+    fn set_diverged(&mut self, reason: DivergenceReason, offset: Option<usize>) {
+        self.divergences
+            .report(self.id, reason, offset, &self.canon_replay.borrow());
+        self.replay.borrow_mut().discard_all();
+        self.diverges = true;
+    }
+
+    fn match_with_canon_stream(&mut self) {
+        if self.diverges {
+            return;
+        }
+        if self.data_len() < self.canon_len() && self.is_finished() {
+            self.set_diverged(DivergenceReason::EndedEarly, Some(self.data_len()));
+            return;
+        }
+        if self.data_matching_canon == self.common_len() {
+            return;
+        }
+
+        let match_start = self.canon_match_start(); // this borrows
+                                                    // Reading spilled canon data would block. The last stream_cmp_distance bytes of C are
+                                                    // never spilled, so only replays lagging behind C get here. Like merge strategies do
+                                                    // with replays shorter than C, we check them once they catch up.
+        if match_start < self.canon_replay.borrow().get_data().spilled_len() {
+            self.discard_unneeded_data();
+            return;
+        }
+        self.data_matching_canon = self
+            .canon_replay
+            .borrow()
+            .get_data()
+            .common_prefix_from(self.replay.borrow().get_data(), match_start);
+
+        if self.data_matching_canon != self.common_len() {
+            self.set_diverged(DivergenceReason::Data, Some(self.data_matching_canon));
+        }
+
+        self.discard_unneeded_data();
+    }
+
+    // To save memory, we discard data before canon_match_start(), or all data if we diverged.
+    // It is an error to access replay data from before canonical replay's length, and it's an
+    // error to access any data of a diverged replay.
+    pub fn discard_unneeded_data(&mut self) {
+        // If we diverged, we already discarded everything
+        if !self.diverges {
+            let match_start = self.canon_match_start(); // this borrows
+            self.replay.borrow_mut().discard(match_start);
+        }
+    }
+
+    // For when we *know* the replay matches.
+    pub fn explicitly_set_matching(&mut self) {
+        debug_assert!(!self.diverges);
+        debug_assert!(self.data_len() >= self.canon_len());
+        self.data_matching_canon = self.canon_len();
+        self.discard_unneeded_data();
+    }
+
+    // For when we *know* the replay does not match.
+    pub fn explicitly_set_diverged(&mut self, reason: DivergenceReason, offset: Option<usize>) {
+        self.set_diverged(reason, offset);
+    }
+}
@@ -4,7 +4,13 @@ use tokio::join;
 use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
 
-use super::{receive::ReplayMerger, save::ReplaySaver, send::ReplaySender};
+use super::end_reason::EndReason;
+use super::timeline::{Timeline, TimelineEvent};
+use super::{
+    receive::ReplayMerger,
+    save::ReplaySaver,
+    send::{ReplaySender, SavedReplaySender},
+};
 use crate::error::ConnectionError;
 use crate::{
     accept::header::ConnectionType,
@@ -12,20 +18,28 @@ use crate::{
     error::ConnResult,
     metrics,
     server::connection::Connection,
-    util::{empty_counter::EmptyCounter, timeout::cancellable},
+    util::{
+        empty_counter::EmptyCounter,
+        timeout::{cancellable, until},
+    },
 };
 
 pub struct Replay {
     id: u64,
     merger: ReplayMerger,
     sender: ReplaySender,
+    saved_sender: SavedReplaySender,
     saver: ReplaySaver,
+    shutdown_token: CancellationToken,
     replay_timeout_token: CancellationToken,
+    timed_out: Cell<bool>,
     writer_connection_count: EmptyCounter,
     reader_connection_count: EmptyCounter,
     time_with_zero_writers_to_end_replay: Duration,
     forced_timeout: Duration,
     should_stop_accepting_connections: Cell<bool>,
+    timeline: Timeline,
+    live_spool: bool,
 }
 
 impl Display for Replay {
@@ -41,30 +55,40 @@ impl Replay {
         let should_stop_accepting_connections = Cell::new(false);
         let time_with_zero_writers_to_end_replay = config.replay.time_with_zero_writers_to_end_replay_s;
         let forced_timeout = config.replay.forced_timeout_s;
+        let live_spool = !config.storage.live_spool_interval_s.is_zero();
         let replay_timeout_token = shutdown_token.child_token();
+        let timeline = Timeline::new();
 
-        let merger = ReplayMerger::new(replay_timeout_token.clone(), config);
+        let merger = ReplayMerger::new(replay_timeout_token.clone(), config, timeline.clone());
         let merged_replay = merger.get_merged_replay();
         let sender = ReplaySender::new(merged_replay, replay_timeout_token.clone());
+        let saved_sender = SavedReplaySender::new(saver.clone(), shutdown_token.clone());
 
         Self {
             id,
             merger,
             sender,
+            saved_sender,
             saver,
+            shutdown_token,
             replay_timeout_token,
+            timed_out: Cell::new(false),
             writer_connection_count,
             reader_connection_count,
             time_with_zero_writers_to_end_replay,
             forced_timeout,
             should_stop_accepting_connections,
+            timeline,
+            live_spool,
         }
     }
 
     async fn timeout(&self) {
         let cancellation = async {
             tokio::time::sleep(self.forced_timeout).await;
+            self.timed_out.set(true);
             self.replay_timeout_token.cancel();
+            self.timeline.record(TimelineEvent::ForcedTimeout);
             log::info!("{} timed out", self);
         };
 
@@ -80,16 +104,63 @@ impl Replay {
         cancellable(wait, &self.replay_timeout_token).await;
     }
 
+    async fn merge_until_finished(&self) {
+        let wait_for_writers = async {
+            self.wait_until_there_were_no_writers_for_a_while().await;
+            self.should_stop_accepting_connections.set(true);
+            log::debug!("{} stopped accepting connections", self);
+            self.writer_connection_count.wait_until_empty().await;
+        };
+        until(wait_for_writers, self.merger.track_delay_tiers()).await;
+        self.merger.finalize().await;
+        log::debug!("{} finished merging data", self);
+    }
+
+    fn end_reason(&self) -> EndReason {
+        if self.timed_out.get() {
+            EndReason::TimedOut
+        } else if self.shutdown_token.is_cancelled() {
+            EndReason::Shutdown
+        } else if self.merger.lost_writers_in_stalemate() {
+            EndReason::Stalemate
+        } else {
+            EndReason::Ended
+        }
+    }
+
     async fn regular_lifetime(&self) {
         log::info!("{} started", self);
         metrics::RUNNING_REPLAYS.inc();
-        self.wait_until_there_were_no_writers_for_a_while().await;
-        self.should_stop_accepting_connections.set(true);
-        log::debug!("{} stopped accepting connections", self);
-        self.writer_connection_count.wait_until_empty().await;
-        self.merger.finalize();
-        log::debug!("{} finished merging data", self);
-        self.saver.save_replay(self.merger.get_merged_replay(), self.id).await;
+        let spool = async {
+            if self.live_spool {
+                self.saver
+                    .spool_live_replay(self.merger.get_merged_replay(), self.id)
+                    .await
+            } else {
+                None
+            }
+        };
+        let (_, spooled) = join! {
+            self.merge_until_finished(),
+            spool,
+        };
+        let end_reason = self.end_reason();
+        if !end_reason.is_complete() {
+            log::info!("{} did not end on its own, reason: {:?}", self, end_reason);
+        }
+        self.saver
+            .save_replay(
+                self.merger.get_merged_replay(),
+                self.id,
+                self.timeline.clone(),
+                end_reason,
+                spooled,
+            )
+            .await;
+        let diverged_streams = self.merger.take_diverged_streams().await;
+        if !diverged_streams.is_empty() {
+            self.saver.save_diverged_streams(diverged_streams, self.id).await;
+        }
         self.reader_connection_count.wait_until_empty().await;
         log::info!("{} ended", self);
         // Cancel to return from timeout
@@ -105,23 +176,40 @@ impl Replay {
         };
     }
 
+    async fn handle_reader(&self, c: &mut Connection) {
+        let name = c.get_header().name;
+        self.timeline
+            .record(TimelineEvent::ReaderConnected { name: name.clone() });
+        self.reader_connection_count.inc();
+        self.sender.handle_connection(c).await;
+        self.reader_connection_count.dec();
+        self.timeline.record(TimelineEvent::ReaderDisconnected { name });
+    }
+
+    // The replay is still around while it's saved and its readers finish. Once it's saved, new
+    // readers get the saved file, so they don't keep it around for longer.
+    async fn handle_late_reader(&self, c: &mut Connection) {
+        match self.saver.open_saved_replay(self.id).await {
+            Ok(replay) => self.saved_sender.send_saved_replay(replay, c).await,
+            Err(_) => self.handle_reader(c).await,
+        }
+    }
+
     pub async fn handle_connection(&self, mut c: Connection) -> ConnResult<()> {
         log::debug!("{} started handling {}", self, c);
-        if self.should_stop_accepting_connections.get() {
-            log::info!("{} dropped {} because its write phase is over", self, c);
-            return Err(ConnectionError::CannotAssignToReplay);
-        }
+        let write_phase_is_over = self.should_stop_accepting_connections.get();
         match c.get_header().type_ {
+            ConnectionType::Writer if write_phase_is_over => {
+                log::info!("{} dropped {} because its write phase is over", self, c);
+                return Err(ConnectionError::CannotAssignToReplay);
+            }
             ConnectionType::Writer => {
                 self.writer_connection_count.inc();
                 self.merger.handle_connection(&mut c).await;
                 self.writer_connection_count.dec();
             }
-            ConnectionType::Reader => {
-                self.reader_connection_count.inc();
-                self.sender.handle_connection(&mut c).await;
-                self.reader_connection_count.dec();
-            }
+            ConnectionType::Reader if write_phase_is_over => self.handle_late_reader(&mut c).await,
+            ConnectionType::Reader => self.handle_reader(&mut c).await,
         }
         log::debug!("{} finished handling {}", self, c);
         Ok(())
@@ -149,8 +237,13 @@ mod test {
         setup_logging();
         tokio::time::pause();
 
+        let end_reason = Arc::new(std::sync::Mutex::new(None));
+        let saved_reason = end_reason.clone();
         let mut mock_saver = InnerReplaySaver::faux();
-        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
+        faux::when!(mock_saver.save_replay).then(move |(_, _, _, reason, _)| {
+            *saved_reason.lock().unwrap() = Some(reason);
+        });
 
         let token = CancellationToken::new();
         let mut config = default_config();
@@ -161,6 +254,9 @@ mod test {
             type_: ConnectionType::Writer,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         };
         c.set_header(c_header);
 
@@ -184,6 +280,138 @@ mod test {
         };
 
         join! { run_replay, check_result };
+        assert_eq!(*end_reason.lock().unwrap(), Some(EndReason::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn test_replay_cut_by_shutdown() {
+        setup_logging();
+        tokio::time::pause();
+
+        let end_reason = Arc::new(std::sync::Mutex::new(None));
+        let saved_reason = end_reason.clone();
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
+        faux::when!(mock_saver.save_replay).then(move |(_, _, _, reason, _)| {
+            *saved_reason.lock().unwrap() = Some(reason);
+        });
+
+        let token = CancellationToken::new();
+        let (mut c, _r, _w) = test_connection();
+        c.set_header(ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+
+        let replay = Replay::new(1, token.clone(), Arc::new(default_config()), Arc::new(mock_saver));
+        let (_, res, _) = join! {
+            replay.lifetime(),
+            replay.handle_connection(c),
+            async {
+                sleep_s(10).await;
+                token.cancel();
+            },
+        };
+        res.unwrap();
+        assert_eq!(*end_reason.lock().unwrap(), Some(EndReason::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn test_replay_without_live_spool() {
+        setup_logging();
+        tokio::time::pause();
+
+        let saved = Arc::new(std::sync::Mutex::new(None));
+        let saved_spooled = saved.clone();
+        // No spool_live_replay, faux panics if it's called.
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.save_replay).then(move |(_, _, _, _, spooled)| {
+            *saved_spooled.lock().unwrap() = Some(spooled);
+        });
+
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.storage.live_spool_interval_s = Duration::ZERO;
+        let (mut c, _r, _w) = test_connection();
+        c.set_header(ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+
+        let replay = Replay::new(1, token.clone(), Arc::new(config), Arc::new(mock_saver));
+        let (_, res, _) = join! {
+            replay.lifetime(),
+            replay.handle_connection(c),
+            async {
+                sleep_s(10).await;
+                token.cancel();
+            },
+        };
+        res.unwrap();
+        assert_eq!(*saved.lock().unwrap(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn test_replay_saves_timeline() {
+        setup_logging();
+        tokio::time::pause();
+
+        let saved = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let saved_events = saved.clone();
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
+        faux::when!(mock_saver.save_replay).then(move |(_, _, timeline, _, _)| {
+            *saved_events.lock().unwrap() = timeline.events();
+        });
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.replay.forced_timeout_s = Duration::from_secs(3600);
+
+        let (mut c, _r, _w) = test_connection();
+        c.set_header(ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+
+        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+        (join! {
+            replay.lifetime(),
+            replay.handle_connection(c),
+        })
+        .1
+        .unwrap();
+
+        assert_eq!(
+            *saved.lock().unwrap(),
+            vec![
+                TimelineEvent::WriterConnected {
+                    writer: 0,
+                    name: "foo".into()
+                },
+                TimelineEvent::ForcedTimeout,
+                TimelineEvent::WriterDisconnected {
+                    writer: 0,
+                    name: "foo".into(),
+                    data_len: 0
+                },
+                TimelineEvent::MergeFinished {
+                    header_len: 0,
+                    data_len: 0
+                },
+            ]
+        );
     }
 
     #[tokio::test]
@@ -192,6 +420,7 @@ mod test {
         tokio::time::pause();
 
         let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
         faux::when!(mock_saver.save_replay).then(|_| ());
         let token = CancellationToken::new();
         let config = default_config();
@@ -202,11 +431,17 @@ mod test {
             type_: ConnectionType::Writer,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
         c_read.set_header(ConnectionHeader {
             type_: ConnectionType::Reader,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
 
         let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
@@ -240,13 +475,103 @@ mod test {
         compare_bufs(example_replay_file, received_replay_file);
     }
 
+    #[tokio::test]
+    async fn test_replay_delay_tier_reader() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.replay.merge_strategy = crate::config::MergeStrategyKind::LongestWriter;
+        config.replay.delay_tiers = vec![crate::config::DelayTierSettings {
+            name: "casters".into(),
+            delay_s: Duration::from_secs(10),
+        }];
+
+        let (mut c_write, _r, mut writer) = test_connection();
+        let (mut c_caster, mut caster, _w1) = test_connection();
+        let (mut c_public, mut public, _w2) = test_connection();
+        c_write.set_header(ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+        c_caster.set_header(ConnectionHeader {
+            type_: ConnectionType::Reader,
+            id: 1,
+            name: "caster".into(),
+            offset: 0,
+            player_id: Some(42),
+            tier: Some("casters".into()),
+        });
+        c_public.set_header(ConnectionHeader {
+            type_: ConnectionType::Reader,
+            id: 1,
+            name: "public".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+
+        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+        let run_replay = async {
+            let (_, writer, caster, public) = join! {
+                replay.lifetime(),
+                replay.handle_connection(c_write),
+                replay.handle_connection(c_caster),
+                replay.handle_connection(c_public),
+            };
+            writer.unwrap();
+            caster.unwrap();
+            public.unwrap();
+        };
+
+        // Writer keeps the connection open for 100 seconds. The public delay is 5 minutes.
+        let example_replay_file = get_file("example");
+        let replay_writing = async {
+            sleep_s(1).await;
+            writer.write_all(&example_replay_file).await.unwrap();
+            sleep_s(100).await;
+            drop(writer);
+        };
+        let caster_got_replay_at = Cell::new(None);
+        let caster_reading = async {
+            let mut received = vec![0; example_replay_file.len()];
+            caster.read_exact(&mut received).await.unwrap();
+            caster_got_replay_at.set(Some(tokio::time::Instant::now()));
+            compare_bufs(&example_replay_file, received);
+        };
+        let public_got_replay_at = Cell::new(None);
+        let public_reading = async {
+            let mut received = Vec::new();
+            public.read_to_end(&mut received).await.unwrap();
+            public_got_replay_at.set(Some(tokio::time::Instant::now()));
+            compare_bufs(&example_replay_file, received);
+        };
+
+        let start = tokio::time::Instant::now();
+        join! { run_replay, replay_writing, caster_reading, public_reading };
+        let caster_time = caster_got_replay_at.get().unwrap() - start;
+        let public_time = public_got_replay_at.get().unwrap() - start;
+        assert!(caster_time >= Duration::from_secs(11) && caster_time < Duration::from_secs(20));
+        assert!(public_time >= Duration::from_secs(101));
+    }
+
     #[tokio::test]
     async fn test_replay_stops_accepting_connections() {
         setup_logging();
         tokio::time::pause();
 
         let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
         faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.open_saved_replay).then(|_| Err(std::io::ErrorKind::NotFound.into()));
         let token = CancellationToken::new();
         let mut config = default_config();
         config.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(2);
@@ -260,21 +585,33 @@ mod test {
             type_: ConnectionType::Writer,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
         c2.set_header(ConnectionHeader {
             type_: ConnectionType::Reader,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
         c3.set_header(ConnectionHeader {
             type_: ConnectionType::Writer,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
         c4.set_header(ConnectionHeader {
             type_: ConnectionType::Reader,
             id: 1,
             name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
         });
 
         let example_replay_file = get_file("example");
@@ -290,7 +627,7 @@ mod test {
         // 7 - c1 writes all replay data
         // 9 - Replay stops accepting new connections
         // 15 - Writer (c3) arrives, is discarded
-        // 25 - Reader (c4) arrives, is discarded
+        // 25 - Reader (c4) arrives, is sent the replay even though it wasn't saved
         // 35 - Reader (c2) ends
         let events = async {
             join! {
@@ -331,22 +668,23 @@ mod test {
                 },
                 async {
                     sleep_s(25).await;
-                    let res = replay.handle_connection(c4).await;
-                    assert!(matches!(res.unwrap_err(), ConnectionError::CannotAssignToReplay));
+                    let mut late = Vec::new();
+                    let (res, _) = join! {
+                        replay.handle_connection(c4),
+                        r4.read_to_end(&mut late),
+                    };
+                    assert!(matches!(res, Ok(..)));
+                    compare_bufs(&example_replay_file, late);
                 },
                 async {
                     sleep_s(26).await;
                     assert_eq!(replay.writer_connection_count.count(), 0);
-                    assert_eq!(replay.reader_connection_count.count(), 1);
                     assert!(!replay_is_over.get());
                 },
                 async {
                     sleep_s(30).await;
                     let mut v = Vec::new();
                     r2.read_to_end(&mut v).await.unwrap();
-                    let mut rejected = Vec::new();
-                    r4.read_to_end(&mut rejected).await.unwrap();
-                    assert!(rejected.is_empty());
                     drop(w2);
                     drop(w3);
                     drop(w4);
@@ -365,4 +703,84 @@ mod test {
             events,
         };
     }
+
+    #[tokio::test]
+    async fn test_late_reader_gets_saved_replay() {
+        setup_logging();
+        tokio::time::pause();
+
+        let mut mock_saver = InnerReplaySaver::faux();
+        faux::when!(mock_saver.spool_live_replay).then(|_| None);
+        faux::when!(mock_saver.save_replay).then(|_| ());
+        faux::when!(mock_saver.open_saved_replay).then(|id| {
+            assert_eq!(id, 1);
+            Ok(Box::new(std::io::Cursor::new(b"saved replay".to_vec())))
+        });
+        let token = CancellationToken::new();
+        let mut config = default_config();
+        config.replay.time_with_zero_writers_to_end_replay_s = Duration::from_secs(2);
+        let replay = Replay::new(1, token, Arc::new(config), Arc::new(mock_saver));
+
+        let (mut c1, _r1, mut w1) = test_connection();
+        let (mut c2, mut r2, w2) = test_connection();
+        let (mut c3, mut r3, _w3) = test_connection();
+        c1.set_header(ConnectionHeader {
+            type_: ConnectionType::Writer,
+            id: 1,
+            name: "foo".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+        c2.set_header(ConnectionHeader {
+            type_: ConnectionType::Reader,
+            id: 1,
+            name: "bar".into(),
+            offset: 0,
+            player_id: None,
+            tier: None,
+        });
+        c3.set_header(ConnectionHeader {
+            type_: ConnectionType::Reader,
+            id: 1,
+            name: "baz".into(),
+            offset: 6,
+            player_id: None,
+            tier: None,
+        });
+
+        // Reader c2 reads slowly, so it keeps the replay around after it's saved.
+        let example_replay_file = get_file("example");
+        let c2_done = Cell::new(false);
+        let events = async {
+            join! {
+                async { replay.handle_connection(c1).await.unwrap() },
+                async {
+                    replay.handle_connection(c2).await.unwrap();
+                    c2_done.set(true);
+                },
+                async {
+                    w1.write_all(&example_replay_file).await.unwrap();
+                    drop(w1);
+                },
+                async {
+                    let mut buf = [0; 1000];
+                    while r2.read(&mut buf).await.unwrap() > 0 {
+                        sleep_s(1).await;
+                    }
+                    drop(w2);
+                },
+                async {
+                    sleep_s(10).await;
+                    replay.handle_connection(c3).await.unwrap();
+                    let mut late = Vec::new();
+                    r3.read_to_end(&mut late).await.unwrap();
+                    assert_eq!(late, b"replay");
+                    assert_eq!(replay.reader_connection_count.count(), 1);
+                    assert!(!c2_done.get());
+                },
+            }
+        };
+        join! { replay.lifetime(), events };
+    }
 }
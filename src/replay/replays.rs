@@ -5,27 +5,30 @@ use futures::{Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
 use weak_table::WeakValueHashMap;
 
-use super::{save::ReplaySaver, Replay};
-use crate::error::ConnectionError;
+use super::{save::ReplaySaver, send::SavedReplaySender, Replay};
 use crate::{accept::header::ConnectionType, metrics};
 use crate::{config::Settings, server::connection::Connection};
 
 enum Assignment {
     Connection(Connection, Rc<Replay>),
     NewReplay(Rc<Replay>),
+    SavedReplay(Connection, Rc<SavedReplaySender>),
 }
 
 pub struct Replays {
     replays: WeakValueHashMap<u64, Weak<Replay>>,
     new_replay: Box<dyn Fn(u64) -> Replay>,
+    saved_replay_sender: Rc<SavedReplaySender>,
 }
 
 impl Replays {
     pub fn new(shutdown_token: CancellationToken, config: Settings, saver: ReplaySaver) -> Self {
+        let saved_replay_sender = Rc::new(SavedReplaySender::new(saver.clone(), shutdown_token.clone()));
         let replay_builder = move |rid| Replay::new(rid, shutdown_token.clone(), config.clone(), saver.clone());
         Self {
             replays: WeakValueHashMap::new(),
             new_replay: Box::new(replay_builder),
+            saved_replay_sender,
         }
     }
 
@@ -34,26 +37,28 @@ impl Replays {
         let mut replay_is_newly_created = false;
 
         let maybe_replay = match self.replays.get(&conn_header.id) {
-            Some(r) => Ok(r),
+            Some(r) => Some(r),
             None => {
                 if conn_header.type_ == ConnectionType::Reader {
-                    log::info!("{} asked for replay {}, which is not running", c, conn_header.id);
-                    Err(ConnectionError::CannotAssignToReplay)
+                    log::debug!("{} asked for replay {}, which is not running", c, conn_header.id);
+                    None
                 } else {
                     let r = Rc::new((self.new_replay)(conn_header.id));
                     self.replays.insert(conn_header.id, r.clone());
                     replay_is_newly_created = true;
-                    Ok(r)
+                    Some(r)
                 }
             }
         };
+        let saved_replay_sender = self.saved_replay_sender.clone();
         stream! {
             let replay = match maybe_replay {
-                Err(e) => {
-                    metrics::inc_served_conns::<()>(&Err(e));
+                None => {
+                    // Maybe the replay already ended. Try sending it from the vault.
+                    yield Assignment::SavedReplay(c, saved_replay_sender);
                     return;
                 },
-                Ok(r) => r,
+                Some(r) => r,
             };
             if replay_is_newly_created {
                 yield Assignment::NewReplay(replay.clone());
@@ -69,6 +74,10 @@ impl Replays {
                 let res = r.handle_connection(c).await;
                 metrics::inc_served_conns(&res);
             }
+            Assignment::SavedReplay(mut c, s) => {
+                let res = s.handle_connection(&mut c).await;
+                metrics::inc_served_conns(&res);
+            }
         }
     }
 
@@ -1,17 +1,80 @@
-use std::path::PathBuf;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{config::ExistingReplayPolicy, metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveOutcome {
+    Created,
+    KeptExisting,
+    Overwrote,
+    SavedAlongside,
+}
+
+impl SaveOutcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Created => "Created",
+            Self::KeptExisting => "Kept existing",
+            Self::Overwrote => "Overwrote existing",
+            Self::SavedAlongside => "Saved alongside existing",
+        }
+    }
+}
+
+// Where a committed replay ended up. If we kept the existing replay, that's the existing one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedReplayFile {
+    pub outcome: SaveOutcome,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+// A replay file being written. The same replay can be saved more than once at the same time, e.g.
+// when a pending replay is retried while a recovered one is saved, so each save gets its own temp
+// file, named "<id>.<pid>.<counter>".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TempReplayFile {
+    pub replay_id: u64,
+    key: String,
+}
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl TempReplayFile {
+    fn new(replay_id: u64) -> Self {
+        let n = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self {
+            replay_id,
+            key: format!("{}.{}.{}", replay_id, std::process::id(), n),
+        }
+    }
+
+    // Markers of replays saved by older versions are named after just the ID.
+    fn from_marker(key: &str) -> Option<Self> {
+        let replay_id = key.split('.').next()?.parse::<u64>().ok()?;
+        Some(Self {
+            replay_id,
+            key: key.into(),
+        })
+    }
+}
 
 #[cfg_attr(test, faux::create)]
 pub struct SavedReplayDirectory {
     root: PathBuf,
+    existing_replay_policy: ExistingReplayPolicy,
 }
 
 #[cfg_attr(test, faux::methods)]
 impl SavedReplayDirectory {
-    pub fn new(root: &str) -> Self {
+    pub fn new(root: &str, existing_replay_policy: ExistingReplayPolicy) -> Self {
         Self {
             root: PathBuf::from(root),
+            existing_replay_policy,
         }
     }
 
@@ -34,31 +97,432 @@ impl SavedReplayDirectory {
         })
     }
 
-    // Boxing so faux can work.
-    pub async fn touch_and_return_file(&self, replay_id: u64) -> std::io::Result<Box<dyn AsyncWrite + Unpin>> {
+    fn replay_file_path(&self, replay_id: u64) -> PathBuf {
         let mut target = self.replay_path(replay_id);
-        tokio::fs::create_dir_all(&target).await?;
+        target.push(format!("{}.fafreplay", replay_id));
+        target
+    }
+
+    // Replays are written to a temp file next to the target and renamed into place once written,
+    // so a crash or write error never leaves a truncated replay behind. While a replay is being
+    // written, there's a marker file named after its temp file in the "saving" directory, so we
+    // can clean up temp files after a crash without going through the whole vault.
+    fn temp_replay_file_path(&self, temp: &TempReplayFile) -> PathBuf {
+        let mut target = self.replay_path(temp.replay_id);
+        target.push(format!("{}.fafreplay.tmp", temp.key));
+        target
+    }
+
+    // Where a replay goes if one with the same ID already exists, e.g. "1234.1.fafreplay".
+    async fn free_alongside_file_path(&self, replay_id: u64) -> PathBuf {
+        let mut n = 1;
+        loop {
+            let mut target = self.replay_path(replay_id);
+            target.push(format!("{}.{}.fafreplay", replay_id, n));
+            if tokio::fs::metadata(&target).await.is_err() {
+                return target;
+            }
+            n += 1;
+        }
+    }
+
+    fn saving_markers_path(&self) -> PathBuf {
+        self.root.join(".saving")
+    }
+
+    fn saving_marker_path(&self, temp: &TempReplayFile) -> PathBuf {
+        self.saving_markers_path().join(&temp.key)
+    }
 
-        let basename = format!("{}.fafreplay", replay_id);
-        target.push(basename);
-        Ok(Box::new(
+    // Sidecar files hold extra data about a replay and sit next to it, e.g. "1234.timeline.json".
+    fn sidecar_file_path(&self, replay_id: u64, suffix: &str) -> PathBuf {
+        let mut target = self.replay_path(replay_id);
+        target.push(format!("{}.{}", replay_id, suffix));
+        target
+    }
+
+    // Files only needed while a replay is being saved. They're removed as soon as they're created,
+    // so they're gone once closed, even if we crash.
+    fn scratch_files_path(&self) -> PathBuf {
+        self.root.join(".scratch")
+    }
+
+    fn diverged_streams_path(&self) -> PathBuf {
+        self.root.join("diverged")
+    }
+
+    // Replays waiting for the database. Each has an info file, "<id>.json", and possibly its
+    // compressed data, "<id>.fafreplay". The info file is written last, so it's only there if the
+    // data is complete.
+    fn pending_replays_path(&self) -> PathBuf {
+        self.root.join(".pending")
+    }
+
+    fn pending_info_path(&self, replay_id: u64) -> PathBuf {
+        self.pending_replays_path().join(format!("{}.json", replay_id))
+    }
+
+    fn pending_replay_file_path(&self, replay_id: u64) -> PathBuf {
+        self.pending_replays_path().join(format!("{}.fafreplay", replay_id))
+    }
+
+    // Copies of running replays, so we can save them if we crash. Each has its header, "<id>.header",
+    // and the whole replay compressed, "<id>.zst", which is appended to as the replay grows.
+    fn live_spools_path(&self) -> PathBuf {
+        self.root.join(".live")
+    }
+
+    fn live_spool_header_path(&self, replay_id: u64) -> PathBuf {
+        self.live_spools_path().join(format!("{}.header", replay_id))
+    }
+
+    fn live_spool_file_path(&self, replay_id: u64) -> PathBuf {
+        self.live_spools_path().join(format!("{}.zst", replay_id))
+    }
+
+    // Boxing so faux can work. Cleans up after itself if it fails.
+    pub async fn create_temp_replay_file(
+        &self,
+        replay_id: u64,
+    ) -> std::io::Result<(TempReplayFile, Box<dyn AsyncWrite + Unpin + Send>)> {
+        let temp = TempReplayFile::new(replay_id);
+        let file = async {
+            tokio::fs::create_dir_all(self.saving_markers_path()).await?;
+            tokio::fs::write(self.saving_marker_path(&temp), b"").await?;
+            tokio::fs::create_dir_all(self.replay_path(replay_id)).await?;
             tokio::fs::OpenOptions::new()
                 .write(true)
                 .create_new(true)
-                .open(target)
-                .await?,
-        ))
+                .open(self.temp_replay_file_path(&temp))
+                .await
+        }
+        .await;
+        match file {
+            Ok(f) => Ok((temp, Box::new(f))),
+            Err(e) => {
+                self.discard_temp_replay_file(temp).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Call once the temp file is written and closed. What happens if a replay with this ID was
+    // already saved depends on the existing replay policy.
+    pub async fn commit_replay_file(&self, temp_replay: TempReplayFile) -> std::io::Result<SavedReplayFile> {
+        let replay_id = temp_replay.replay_id;
+        let temp = self.temp_replay_file_path(&temp_replay);
+        let target = self.replay_file_path(replay_id);
+        let temp_file = tokio::fs::File::open(&temp).await?;
+        temp_file.sync_all().await?;
+        let new_len = temp_file.metadata().await?.len();
+        drop(temp_file);
+
+        let outcome = match tokio::fs::metadata(&target).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SaveOutcome::Created,
+            Err(e) => return Err(e),
+            Ok(existing) => match self.existing_replay_policy {
+                ExistingReplayPolicy::Keep => SaveOutcome::KeptExisting,
+                ExistingReplayPolicy::Overwrite => SaveOutcome::Overwrote,
+                ExistingReplayPolicy::Larger => {
+                    if replaces_existing_replay(&temp, new_len, &target, existing.len()).await {
+                        SaveOutcome::Overwrote
+                    } else {
+                        SaveOutcome::KeptExisting
+                    }
+                }
+                ExistingReplayPolicy::Alongside => SaveOutcome::SavedAlongside,
+            },
+        };
+        let path = match outcome {
+            SaveOutcome::Created | SaveOutcome::Overwrote => {
+                tokio::fs::rename(&temp, &target).await?;
+                target
+            }
+            SaveOutcome::SavedAlongside => {
+                let alongside = self.free_alongside_file_path(replay_id).await;
+                tokio::fs::rename(&temp, &alongside).await?;
+                alongside
+            }
+            SaveOutcome::KeptExisting => {
+                tokio::fs::remove_file(&temp).await?;
+                target
+            }
+        };
+        let size = tokio::fs::metadata(&path).await?.len();
+        tokio::fs::File::open(self.replay_path(replay_id))
+            .await?
+            .sync_all()
+            .await?;
+        tokio::fs::remove_file(self.saving_marker_path(&temp_replay)).await?;
+        metrics::REPLAY_FILE_SAVE_OUTCOMES
+            .with_label_values(&[outcome.label()])
+            .inc();
+        Ok(SavedReplayFile { outcome, path, size })
+    }
+
+    pub async fn discard_temp_replay_file(&self, temp: TempReplayFile) {
+        for path in [self.temp_replay_file_path(&temp), self.saving_marker_path(&temp)] {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    // Removes temp files left over from replays we were saving when we crashed.
+    pub async fn remove_stale_temp_files(&self) -> std::io::Result<()> {
+        let mut markers = match tokio::fs::read_dir(self.saving_markers_path()).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            r => r?,
+        };
+        while let Some(marker) = markers.next_entry().await? {
+            let temp = marker.file_name().to_str().and_then(TempReplayFile::from_marker);
+            match temp {
+                Some(temp) => {
+                    log::info!("Removing temp file of replay {} that was not saved", temp.replay_id);
+                    self.discard_temp_replay_file(temp).await;
+                }
+                None => tokio::fs::remove_file(marker.path()).await?,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_sidecar_file(&self, replay_id: u64, suffix: &str, data: Vec<u8>) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(self.replay_path(replay_id)).await?;
+        tokio::fs::write(self.sidecar_file_path(replay_id, suffix), data).await
+    }
+
+    // Data is copied from the start of the file.
+    pub async fn write_diverged_stream(
+        &self,
+        replay_id: u64,
+        writer: u64,
+        json_header: Vec<u8>,
+        mut data: tokio::fs::File,
+    ) -> std::io::Result<()> {
+        let dir = self.diverged_streams_path();
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut target = tokio::fs::File::create(dir.join(format!("{}.{}.fafreplay", replay_id, writer))).await?;
+        data.seek(SeekFrom::Start(0)).await?;
+        target.write_all(&json_header).await?;
+        tokio::io::copy(&mut data, &mut target).await?;
+        target.flush().await
+    }
+
+    // Removes oldest diverged streams until at most max_count remain.
+    pub async fn prune_diverged_streams(&self, max_count: usize) -> std::io::Result<()> {
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.diverged_streams_path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            files.push((modified, entry.path()));
+        }
+        if files.len() <= max_count {
+            return Ok(());
+        }
+        files.sort();
+        let to_remove = files.len() - max_count;
+        for (_, path) in files.into_iter().take(to_remove) {
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn create_scratch_file(&self) -> std::io::Result<tokio::fs::File> {
+        tokio::fs::create_dir_all(self.scratch_files_path()).await?;
+        let n = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = self.scratch_files_path().join(format!("{}.{}", std::process::id(), n));
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(file)
+    }
+
+    // Boxing so faux can work.
+    pub async fn create_pending_replay_file(
+        &self,
+        replay_id: u64,
+    ) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        tokio::fs::create_dir_all(self.pending_replays_path()).await?;
+        let target = self.pending_replay_file_path(replay_id);
+        Ok(Box::new(tokio::fs::File::create(target).await?))
+    }
+
+    pub async fn open_pending_replay_file(&self, replay_id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let target = self.pending_replay_file_path(replay_id);
+        Ok(Box::new(tokio::fs::File::open(target).await?))
+    }
+
+    // The info file says the data file is complete, so the data has to be on disk before it.
+    pub async fn write_pending_info(&self, replay_id: u64, data: Vec<u8>) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(self.pending_replays_path()).await?;
+        match tokio::fs::File::open(self.pending_replay_file_path(replay_id)).await {
+            Ok(replay_file) => replay_file.sync_all().await?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let target = self.pending_info_path(replay_id);
+        let temp = target.with_extension("json.tmp");
+        let mut f = tokio::fs::File::create(&temp).await?;
+        f.write_all(&data).await?;
+        f.sync_all().await?;
+        drop(f);
+        tokio::fs::rename(temp, target).await?;
+        tokio::fs::File::open(self.pending_replays_path())
+            .await?
+            .sync_all()
+            .await
+    }
+
+    pub async fn read_pending_info(&self, replay_id: u64) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(self.pending_info_path(replay_id)).await
+    }
+
+    pub async fn list_pending_replays(&self) -> std::io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        let mut entries = match tokio::fs::read_dir(self.pending_replays_path()).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            r => r?,
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let id = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".json"))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(id) = id {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    pub async fn remove_pending_replay(&self, replay_id: u64) -> std::io::Result<()> {
+        // Info file goes first, so we never have an info file without its data.
+        for path in [
+            self.pending_info_path(replay_id),
+            self.pending_replay_file_path(replay_id),
+        ] {
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    // Boxing so faux can work.
+    pub async fn create_live_spool_file(
+        &self,
+        replay_id: u64,
+        header: Vec<u8>,
+    ) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        tokio::fs::create_dir_all(self.live_spools_path()).await?;
+        let file = tokio::fs::File::create(self.live_spool_file_path(replay_id)).await?;
+        tokio::fs::write(self.live_spool_header_path(replay_id), header).await?;
+        Ok(Box::new(file))
+    }
+
+    pub async fn open_live_spool_file(&self, replay_id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let target = self.live_spool_file_path(replay_id);
+        Ok(Box::new(tokio::fs::File::open(target).await?))
+    }
+
+    // Returns the header and the compressed replay.
+    pub async fn read_live_spool(&self, replay_id: u64) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+        let header = tokio::fs::read(self.live_spool_header_path(replay_id)).await?;
+        let data = match tokio::fs::read(self.live_spool_file_path(replay_id)).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            r => r?,
+        };
+        Ok((header, data))
+    }
+
+    pub async fn list_live_spools(&self) -> std::io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        let mut entries = match tokio::fs::read_dir(self.live_spools_path()).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            r => r?,
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let id = name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".header"))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(id) = id {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    pub async fn remove_live_spool(&self, replay_id: u64) -> std::io::Result<()> {
+        // Header goes last, so we never have a compressed replay without its header.
+        for path in [
+            self.live_spool_file_path(replay_id),
+            self.live_spool_header_path(replay_id),
+        ] {
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn open_replay_file(&self, replay_id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin>> {
+        let target = self.replay_file_path(replay_id);
+        Ok(Box::new(tokio::fs::File::open(target).await?))
+    }
+}
+
+// We don't read JSON headers longer than this and fall back to comparing sizes instead.
+const MAX_JSON_HEADER_LEN: u64 = 1024 * 1024;
+
+// What a saved replay's JSON header says about it being complete. None if we can't tell.
+async fn saved_replay_completeness(path: &Path) -> Option<bool> {
+    #[derive(serde::Deserialize)]
+    struct Completeness {
+        complete: bool,
+    }
+    let file = tokio::fs::File::open(path).await.ok()?;
+    let mut line = Vec::new();
+    BufReader::new(file)
+        .take(MAX_JSON_HEADER_LEN)
+        .read_until(b'\n', &mut line)
+        .await
+        .ok()?;
+    serde_json::from_slice::<Completeness>(&line).ok().map(|c| c.complete)
+}
+
+// A complete replay beats an incomplete one, otherwise the larger one wins.
+async fn replaces_existing_replay(new: &Path, new_len: u64, existing: &Path, existing_len: u64) -> bool {
+    match (
+        saved_replay_completeness(new).await,
+        saved_replay_completeness(existing).await,
+    ) {
+        (Some(new_complete), Some(existing_complete)) if new_complete != existing_complete => new_complete,
+        _ => new_len > existing_len,
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use tokio::io::sink;
+    use tokio::io::{empty, sink, AsyncReadExt, AsyncWriteExt};
 
     use super::*;
     #[test]
     fn test_replay_path_works_as_intended() {
-        let dir = SavedReplayDirectory::new("./");
+        let dir = SavedReplayDirectory::new("./", ExistingReplayPolicy::Keep);
         let path_1 = dir.replay_path(1234567);
         assert_eq!(path_1, PathBuf::from("./0/1/23/45"));
 
@@ -71,14 +535,303 @@ pub mod test {
 
     #[test]
     fn test_replay_path_uses_provided_directory() {
-        let dir = SavedReplayDirectory::new("/tmp/foo");
+        let dir = SavedReplayDirectory::new("/tmp/foo", ExistingReplayPolicy::Keep);
         let path_1 = dir.replay_path(1234567);
         assert_eq!(path_1, PathBuf::from("/tmp/foo/0/1/23/45"));
     }
 
+    #[tokio::test]
+    async fn test_open_saved_replay_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        dir.open_replay_file(1234567)
+            .await
+            .err()
+            .expect("Replay should not exist yet");
+
+        let (temp, mut f) = dir.create_temp_replay_file(1234567).await.unwrap();
+        f.write_all(b"foo").await.unwrap();
+        f.shutdown().await.unwrap();
+        drop(f);
+        dir.open_replay_file(1234567)
+            .await
+            .err()
+            .expect("Replay should not be visible before commit");
+        dir.commit_replay_file(temp).await.unwrap();
+
+        let mut data = Vec::new();
+        let mut f = dir.open_replay_file(1234567).await.unwrap();
+        f.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"foo");
+    }
+
+    async fn save_replay_file(dir: &SavedReplayDirectory, data: &[u8]) -> SaveOutcome {
+        let (temp, mut f) = dir.create_temp_replay_file(1234567).await.unwrap();
+        f.write_all(data).await.unwrap();
+        f.shutdown().await.unwrap();
+        drop(f);
+        dir.commit_replay_file(temp).await.unwrap().outcome
+    }
+
+    // Saves "foo", then "foobar", then "baz" under the same ID.
+    async fn save_three_times(policy: ExistingReplayPolicy) -> (tempfile::TempDir, Vec<SaveOutcome>) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), policy);
+        let mut outcomes = Vec::new();
+        for data in [&b"foo"[..], b"foobar", b"baz"] {
+            outcomes.push(save_replay_file(&dir, data).await);
+        }
+        assert_eq!(temp_files(&tmp_dir), 0);
+        (tmp_dir, outcomes)
+    }
+
+    fn temp_files(tmp_dir: &tempfile::TempDir) -> usize {
+        std::fs::read_dir(tmp_dir.path().join("0/1/23/45"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_str().unwrap().ends_with(".tmp"))
+            .count()
+    }
+
+    fn saved_file(tmp_dir: &tempfile::TempDir, name: &str) -> Vec<u8> {
+        std::fs::read(tmp_dir.path().join("0/1/23/45").join(name)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_existing_replay_is_kept() {
+        use SaveOutcome::*;
+        let (tmp_dir, outcomes) = save_three_times(ExistingReplayPolicy::Keep).await;
+        assert_eq!(outcomes, vec![Created, KeptExisting, KeptExisting]);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), b"foo");
+    }
+
+    #[tokio::test]
+    async fn test_existing_replay_is_overwritten() {
+        use SaveOutcome::*;
+        let (tmp_dir, outcomes) = save_three_times(ExistingReplayPolicy::Overwrite).await;
+        assert_eq!(outcomes, vec![Created, Overwrote, Overwrote]);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), b"baz");
+    }
+
+    #[tokio::test]
+    async fn test_existing_replay_is_replaced_by_larger() {
+        use SaveOutcome::*;
+        let (tmp_dir, outcomes) = save_three_times(ExistingReplayPolicy::Larger).await;
+        assert_eq!(outcomes, vec![Created, Overwrote, KeptExisting]);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), b"foobar");
+    }
+
+    #[tokio::test]
+    async fn test_complete_replay_beats_larger_incomplete_one() {
+        use SaveOutcome::*;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Larger);
+        let complete = b"{\"complete\":true}\nfoo";
+        let incomplete = b"{\"complete\":false}\nfoobarbaz";
+        let incomplete_larger = b"{\"complete\":false}\nfoobarbazquux";
+        assert_eq!(save_replay_file(&dir, incomplete).await, Created);
+        assert_eq!(save_replay_file(&dir, incomplete_larger).await, Overwrote);
+        assert_eq!(save_replay_file(&dir, complete).await, Overwrote);
+        assert_eq!(save_replay_file(&dir, incomplete_larger).await, KeptExisting);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), complete);
+    }
+
+    #[tokio::test]
+    async fn test_replay_is_saved_alongside_existing() {
+        use SaveOutcome::*;
+        let (tmp_dir, outcomes) = save_three_times(ExistingReplayPolicy::Alongside).await;
+        assert_eq!(outcomes, vec![Created, SavedAlongside, SavedAlongside]);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), b"foo");
+        assert_eq!(saved_file(&tmp_dir, "1234567.1.fafreplay"), b"foobar");
+        assert_eq!(saved_file(&tmp_dir, "1234567.2.fafreplay"), b"baz");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves_of_same_replay() {
+        use SaveOutcome::*;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Alongside);
+        let (temp1, mut f1) = dir.create_temp_replay_file(1234567).await.unwrap();
+        let (temp2, mut f2) = dir.create_temp_replay_file(1234567).await.unwrap();
+        assert_ne!(dir.temp_replay_file_path(&temp1), dir.temp_replay_file_path(&temp2));
+        f1.write_all(b"foo").await.unwrap();
+        f2.write_all(b"barbaz").await.unwrap();
+        for f in [&mut f1, &mut f2] {
+            f.shutdown().await.unwrap();
+        }
+        drop((f1, f2));
+        assert_eq!(dir.commit_replay_file(temp2).await.unwrap().outcome, Created);
+        assert_eq!(dir.commit_replay_file(temp1).await.unwrap().outcome, SavedAlongside);
+        assert_eq!(saved_file(&tmp_dir, "1234567.fafreplay"), b"barbaz");
+        assert_eq!(saved_file(&tmp_dir, "1234567.1.fafreplay"), b"foo");
+        assert_eq!(temp_files(&tmp_dir), 0);
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".saving")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_committed_replay_file_path_and_size() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Alongside);
+        for (data, name) in [(&b"foo"[..], "1234567.fafreplay"), (b"foobar", "1234567.1.fafreplay")] {
+            let (temp, mut f) = dir.create_temp_replay_file(1234567).await.unwrap();
+            f.write_all(data).await.unwrap();
+            f.shutdown().await.unwrap();
+            drop(f);
+            let saved = dir.commit_replay_file(temp).await.unwrap();
+            assert_eq!(saved.path, tmp_dir.path().join("0/1/23/45").join(name));
+            assert_eq!(saved.size, data.len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_stale_temp_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let (temp, mut f) = dir.create_temp_replay_file(1234567).await.unwrap();
+        f.write_all(b"foo").await.unwrap();
+        f.shutdown().await.unwrap();
+        drop(f);
+        let temp_path = dir.temp_replay_file_path(&temp);
+        assert!(temp_path.exists());
+
+        // Simulating a restart after a crash.
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        dir.remove_stale_temp_files().await.unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".saving")).unwrap().count(), 0);
+        dir.open_replay_file(1234567)
+            .await
+            .err()
+            .expect("Replay should not exist");
+    }
+
+    #[tokio::test]
+    async fn test_write_sidecar_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        dir.write_sidecar_file(1234567, "timeline.json", b"foo".to_vec())
+            .await
+            .unwrap();
+        let data = std::fs::read(tmp_dir.path().join("0/1/23/45/1234567.timeline.json")).unwrap();
+        assert_eq!(data, b"foo");
+    }
+
+    #[tokio::test]
+    async fn test_prune_diverged_streams() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        for writer in 0..4 {
+            let mut data = tokio::fs::File::from_std(tempfile::tempfile().unwrap());
+            data.write_all(b"bar").await.unwrap();
+            dir.write_diverged_stream(1234, writer, b"foo\n".to_vec(), data)
+                .await
+                .unwrap();
+            // Make sure modification times differ.
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        dir.prune_diverged_streams(2).await.unwrap();
+
+        let mut left: Vec<String> = std::fs::read_dir(tmp_dir.path().join("diverged"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["1234.2.fafreplay", "1234.3.fafreplay"]);
+        let data = std::fs::read(tmp_dir.path().join("diverged/1234.3.fafreplay")).unwrap();
+        assert_eq!(data, b"foo\nbar");
+    }
+
+    #[tokio::test]
+    async fn test_pending_replays() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        assert!(dir.list_pending_replays().await.unwrap().is_empty());
+
+        let mut f = dir.create_pending_replay_file(1234).await.unwrap();
+        f.write_all(b"foo").await.unwrap();
+        f.shutdown().await.unwrap();
+        drop(f);
+        // No info file yet.
+        assert!(dir.list_pending_replays().await.unwrap().is_empty());
+        dir.write_pending_info(1234, b"bar".to_vec()).await.unwrap();
+        dir.write_pending_info(12, b"baz".to_vec()).await.unwrap();
+        assert_eq!(dir.list_pending_replays().await.unwrap(), vec![12, 1234]);
+        assert_eq!(dir.read_pending_info(1234).await.unwrap(), b"bar");
+
+        let mut data = Vec::new();
+        let mut f = dir.open_pending_replay_file(1234).await.unwrap();
+        f.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"foo");
+
+        dir.remove_pending_replay(1234).await.unwrap();
+        dir.remove_pending_replay(12).await.unwrap();
+        assert!(dir.list_pending_replays().await.unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".pending")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_live_spools() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        assert!(dir.list_live_spools().await.unwrap().is_empty());
+
+        let mut f = dir.create_live_spool_file(1234, b"header".to_vec()).await.unwrap();
+        assert_eq!(
+            dir.read_live_spool(1234).await.unwrap(),
+            (b"header".to_vec(), Vec::new())
+        );
+        f.write_all(b"foo").await.unwrap();
+        f.write_all(b"bar").await.unwrap();
+        f.shutdown().await.unwrap();
+        drop(f);
+        dir.create_live_spool_file(12, b"other".to_vec()).await.unwrap();
+        assert_eq!(dir.list_live_spools().await.unwrap(), vec![12, 1234]);
+        assert_eq!(
+            dir.read_live_spool(1234).await.unwrap(),
+            (b"header".to_vec(), b"foobar".to_vec())
+        );
+        let mut data = Vec::new();
+        let mut f = dir.open_live_spool_file(1234).await.unwrap();
+        f.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"foobar");
+
+        dir.remove_live_spool(1234).await.unwrap();
+        dir.remove_live_spool(12).await.unwrap();
+        assert!(dir.list_live_spools().await.unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".live")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_scratch_files_are_removed_right_away() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut f = dir.create_scratch_file().await.unwrap();
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".scratch")).unwrap().count(), 0);
+
+        f.write_all(b"foo").await.unwrap();
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut data = Vec::new();
+        f.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"foo");
+    }
+
     pub fn test_directory() -> SavedReplayDirectory {
         let mut f = SavedReplayDirectory::faux();
-        faux::when!(f.touch_and_return_file).then(|_| Ok(Box::new(sink())));
+        faux::when!(f.create_temp_replay_file).then(|id| Ok((TempReplayFile::new(id), Box::new(sink()))));
+        faux::when!(f.commit_replay_file).then(|_| {
+            Ok(SavedReplayFile {
+                outcome: SaveOutcome::Created,
+                path: PathBuf::from("/tmp/foo/1.fafreplay"),
+                size: 0,
+            })
+        });
+        faux::when!(f.discard_temp_replay_file).then(|_| ());
+        faux::when!(f.write_sidecar_file).then(|_| Ok(()));
+        faux::when!(f.list_pending_replays).then(|_| Ok(Vec::new()));
+        faux::when!(f.list_live_spools).then(|_| Ok(Vec::new()));
+        faux::when!(f.create_live_spool_file).then(|_| Ok(Box::new(sink())));
+        faux::when!(f.open_live_spool_file).then(|_| Ok(Box::new(empty())));
+        faux::when!(f.remove_live_spool).then(|_| Ok(()));
+        faux::when!(f.create_scratch_file).then(|_| Ok(tokio::fs::File::from_std(tempfile::tempfile().unwrap())));
         f
     }
 }
@@ -0,0 +1,199 @@
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use async_compression::tokio::write::ZstdEncoder;
+use futures::ready;
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{config::Settings, replay::receive::divergence::DivergenceRecord};
+
+// Copies of writer streams that diverged from the merged replay, kept as evidence for
+// moderators. We can't tell if a writer will diverge until the replay is merged, so in capture
+// mode we compress everything each writer sends to a file and only save the diverged ones.
+// Capture files are deleted as soon as they're created, same as spill files.
+//
+// Saved files look like regular saved replays - a JSON header line followed by zstd-compressed
+// replay header and data.
+
+static NEXT_CAPTURE_FILE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct CaptureSettings {
+    compression_level: u32,
+    capture_dir: PathBuf,
+}
+
+impl CaptureSettings {
+    pub fn new(compression_level: u32, capture_dir: PathBuf) -> Self {
+        Self {
+            compression_level,
+            capture_dir,
+        }
+    }
+
+    // None if we don't capture writer streams.
+    pub fn from_config(config: &Settings) -> Option<Self> {
+        config.storage.capture_diverged_streams.then(|| {
+            Self::new(
+                config.storage.compression_level,
+                PathBuf::from(&config.storage.vault_path).join(".capture"),
+            )
+        })
+    }
+
+    async fn create_capture_file(&self) -> std::io::Result<File> {
+        tokio::fs::create_dir_all(&self.capture_dir).await?;
+        let n = NEXT_CAPTURE_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = self.capture_dir.join(format!("{}.{}", std::process::id(), n));
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(file)
+    }
+
+    pub async fn start_capture(&self) -> std::io::Result<StreamCapture> {
+        let file = self.create_capture_file().await?;
+        let clevel = async_compression::Level::Precise(self.compression_level);
+        Ok(StreamCapture {
+            encoder: Some(ZstdEncoder::with_quality(file, clevel)),
+        })
+    }
+}
+
+// Capturing is best effort, so writing to a capture never fails. If writing to the file fails,
+// we stop capturing and the capture can't be finished.
+pub struct StreamCapture {
+    encoder: Option<ZstdEncoder<File>>,
+}
+
+impl StreamCapture {
+    pub async fn finish(self, divergence: DivergenceRecord) -> std::io::Result<DivergedStream> {
+        let mut encoder = self
+            .encoder
+            .ok_or_else(|| std::io::Error::other("capture failed earlier"))?;
+        encoder.shutdown().await?;
+        Ok(DivergedStream {
+            divergence,
+            data: encoder.into_inner(),
+        })
+    }
+}
+
+impl AsyncWrite for StreamCapture {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let me = &mut *self;
+        if let Some(encoder) = me.encoder.as_mut() {
+            match ready!(Pin::new(encoder).poll_write(cx, buf)) {
+                Ok(n) => return Poll::Ready(Ok(n)),
+                Err(e) => {
+                    log::warn!("Failed to capture writer stream, giving up on it: {}", e);
+                    me.encoder = None;
+                }
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DivergedStreamJsonHeader<'a> {
+    uid: u64,
+    #[serde(flatten)]
+    divergence: &'a DivergenceRecord,
+    compression: &'static str,
+}
+
+pub struct DivergedStream {
+    divergence: DivergenceRecord,
+    data: File, // Compressed.
+}
+
+impl DivergedStream {
+    pub fn writer(&self) -> u64 {
+        self.divergence.writer
+    }
+
+    // The saved file is this line followed by data.
+    pub fn json_header_line(&self, uid: u64) -> serde_json::Result<Vec<u8>> {
+        let json_header = DivergedStreamJsonHeader {
+            uid,
+            divergence: &self.divergence,
+            compression: "zstd",
+        };
+        let mut line = serde_json::to_vec(&json_header)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+
+    pub fn into_data(self) -> File {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::receive::divergence::DivergenceReason;
+    use crate::replay::save::test::unpack_replay;
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn divergence() -> DivergenceRecord {
+        DivergenceRecord {
+            writer: 1,
+            name: "foo".into(),
+            reason: DivergenceReason::Data,
+            offset: Some(2),
+            tick: Some(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diverged_stream_file_contents() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let settings = CaptureSettings::new(10, tmp_dir.path().join(".capture"));
+        let mut capture = settings.start_capture().await.unwrap();
+        capture.write_all(b"header").await.unwrap();
+        capture.write_all(b"data").await.unwrap();
+        let stream = capture.finish(divergence()).await.unwrap();
+
+        let mut contents = stream.json_header_line(1234).unwrap();
+        let mut data = stream.into_data();
+        data.seek(SeekFrom::Start(0)).await.unwrap();
+        data.read_to_end(&mut contents).await.unwrap();
+        let (json, data) = unpack_replay(&contents[..]).await.unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"uid\":1234,\"writer\":1,\"name\":\"foo\",\"reason\":\"data\",\"offset\":2,\"tick\":0,\"compression\":\"zstd\"}\n"
+        );
+        assert_eq!(data, b"headerdata");
+    }
+
+    #[tokio::test]
+    async fn test_captures_are_not_kept_in_vault() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let settings = CaptureSettings::new(10, tmp_dir.path().join(".capture"));
+        let mut capture = settings.start_capture().await.unwrap();
+        capture.write_all(b"headerdata").await.unwrap();
+        assert_eq!(std::fs::read_dir(tmp_dir.path().join(".capture")).unwrap().count(), 0);
+    }
+}
@@ -1,14 +1,38 @@
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
+    config::JsonHeaderFormat,
     database::queries::GameTeams,
-    database::queries::{ModVersions, Queries},
+    database::queries::{GamePlayer, ModVersions, Queries},
     error::SaveError,
+    replay::end_reason::EndReason,
+    replay::receive::divergence::DivergenceRecord,
 };
 
-// Saved replay's json header. Some fields are weird / redundant, that's legacy. TODO: new format.
-#[derive(serde::Serialize)]
-pub struct ReplayJsonHeader {
+// Saved replay's json header. Version 2 is the legacy one, version 3 cleans it up and describes
+// the replay body. Version 3's schema is in docs/replay_header_v3.schema.json, keep it in sync.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ReplayJsonHeader {
+    V2(ReplayJsonHeaderV2),
+    V3(ReplayJsonHeaderV3),
+}
+
+// What we know about the replay itself, as opposed to the game in the database. Only v3 has all
+// of it, v2 only gets the checksum.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReplayInfo {
+    pub body_size: usize,
+    pub body_sha256: String,
+    pub writers: usize,
+    pub merge_quorum: Option<usize>,
+}
+
+// Some fields are weird / redundant, that's legacy.
+#[derive(Serialize)]
+pub struct ReplayJsonHeaderV2 {
     complete: bool,
     featured_mod: Option<String>,
     featured_mod_versions: ModVersions,
@@ -19,15 +43,43 @@ pub struct ReplayJsonHeader {
     mapname: String,
     num_players: i64,
     recorder: String, // Same as host. This used to only be in local replays. I accidentally added it server-side. Not harmful.
-    state: String,
+    state: EndReason,
     teams: HashMap<String, Vec<String>>,
     title: String,
     uid: u64,
     compression: String,
     version: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    divergences: Option<Vec<DivergenceRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_sha256: Option<String>,
 }
 
-impl ReplayJsonHeader {
+#[derive(Serialize)]
+pub struct ReplayJsonHeaderV3 {
+    version: i64,
+    uid: u64,
+    server_version: String,
+    complete: bool,
+    state: EndReason,
+    title: String,
+    game_type: String,
+    featured_mod: Option<String>,
+    featured_mod_versions: ModVersions,
+    mapname: String,
+    host: String,
+    launched_at: i64,
+    game_end: i64,
+    players: Vec<GamePlayer>,
+    ticks: Option<u32>,
+    #[serde(flatten)]
+    info: ReplayInfo,
+    compression: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    divergences: Option<Vec<DivergenceRecord>>,
+}
+
+impl ReplayJsonHeaderV2 {
     fn fixup_team_dict(mut d: GameTeams) -> HashMap<String, Vec<String>> {
         let mut out = HashMap::new();
         for (k, v) in d.drain() {
@@ -36,7 +88,7 @@ impl ReplayJsonHeader {
         out
     }
 
-    pub async fn from_id_and_db(db: &Queries, uid: u64) -> Result<ReplayJsonHeader, SaveError> {
+    async fn from_id_and_db(db: &Queries, uid: u64) -> Result<Self, SaveError> {
         let game_stats = db.get_game_stats(uid).await?;
         let teams = Self::fixup_team_dict(db.get_teams_in_game(uid).await?);
         let featured_mod_versions = match &game_stats.featured_mod {
@@ -55,24 +107,99 @@ impl ReplayJsonHeader {
             mapname: game_stats.mapname,
             num_players: game_stats.num_players,
             recorder: game_stats.host,
-            state: "PLAYING".into(),
+            state: EndReason::Ended,
             teams,
             title: game_stats.title,
             uid,
             compression: "zstd".into(),
             version: 2,
+            divergences: None,
+            body_sha256: None,
         })
     }
 }
 
+impl ReplayJsonHeaderV3 {
+    async fn from_id_and_db(db: &Queries, uid: u64) -> Result<Self, SaveError> {
+        let game_stats = db.get_game_stats(uid).await?;
+        let players = db.get_players_in_game(uid).await?;
+        let featured_mod_versions = match &game_stats.featured_mod {
+            None => HashMap::new(),
+            Some(v) => db.get_mod_versions(v).await?,
+        };
+
+        Ok(Self {
+            version: 3,
+            uid,
+            server_version: env!("CARGO_PKG_VERSION").into(),
+            complete: true,
+            state: EndReason::Ended,
+            title: game_stats.title,
+            game_type: game_stats.game_type,
+            featured_mod: game_stats.featured_mod,
+            featured_mod_versions,
+            mapname: game_stats.mapname,
+            host: game_stats.host,
+            launched_at: game_stats.launched_at,
+            game_end: game_stats.game_end,
+            players,
+            ticks: None,
+            info: ReplayInfo::default(),
+            compression: "zstd".into(),
+            divergences: None,
+        })
+    }
+}
+
+impl ReplayJsonHeader {
+    pub async fn from_id_and_db(db: &Queries, uid: u64, format: JsonHeaderFormat) -> Result<Self, SaveError> {
+        Ok(match format {
+            JsonHeaderFormat::V2 => Self::V2(ReplayJsonHeaderV2::from_id_and_db(db, uid).await?),
+            JsonHeaderFormat::V3 => Self::V3(ReplayJsonHeaderV3::from_id_and_db(db, uid).await?),
+        })
+    }
+
+    pub fn set_end_reason(&mut self, reason: EndReason) {
+        match self {
+            Self::V2(h) => {
+                h.complete = reason.is_complete();
+                h.state = reason;
+            }
+            Self::V3(h) => {
+                h.complete = reason.is_complete();
+                h.state = reason;
+            }
+        }
+    }
+
+    pub fn set_divergences(&mut self, divergences: Vec<DivergenceRecord>) {
+        match self {
+            Self::V2(h) => h.divergences = Some(divergences),
+            Self::V3(h) => h.divergences = Some(divergences),
+        }
+    }
+
+    // V2 only has ticks in the database.
+    pub fn set_replay_info(&mut self, ticks: Option<u32>, info: ReplayInfo) {
+        match self {
+            Self::V2(h) => h.body_sha256 = Some(info.body_sha256).filter(|c| !c.is_empty()),
+            Self::V3(h) => {
+                h.ticks = ticks;
+                h.info = info;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::database::database::test::mock_database;
 
     #[test]
     fn serialized_header_looks_as_expected() {
         // This was generated BY this test, but was also compared by hand to an existing header. It looks like it should.
-        let expected = r#"{"complete":true,"featured_mod":"faf","featured_mod_versions":{"faf":3718},"game_end":10000,"game_type":"unknown","host":"Sheeo","launched_at":9000,"mapname":"adaptive_syrtis_major.v0001","num_players":10,"recorder":"MazorNoob","state":"PLAYING","teams":{"1":["Mephi","Sheeo","MazorNoob","Downlord"]},"title":"100k+","uid":9999999,"compression":"zstd","version":2}"#;
+        let expected = r#"{"complete":true,"featured_mod":"faf","featured_mod_versions":{"faf":3718},"game_end":10000,"game_type":"unknown","host":"Sheeo","launched_at":9000,"mapname":"adaptive_syrtis_major.v0001","num_players":10,"recorder":"MazorNoob","state":"ENDED","teams":{"1":["Mephi","Sheeo","MazorNoob","Downlord"]},"title":"100k+","uid":9999999,"compression":"zstd","version":2}"#;
         /* A bit paranoid, but let's make sure. */
         let mut mod_versions = HashMap::new();
         mod_versions.insert("faf".into(), 3718);
@@ -87,7 +214,7 @@ mod test {
                 "Downlord".into(),
             ],
         );
-        let header = ReplayJsonHeader {
+        let header = ReplayJsonHeader::V2(ReplayJsonHeaderV2 {
             complete: true,
             featured_mod: Some("faf".into()),
             featured_mod_versions: mod_versions,
@@ -98,13 +225,63 @@ mod test {
             mapname: "adaptive_syrtis_major.v0001".into(),
             num_players: 10,
             recorder: "MazorNoob".into(),
-            state: "PLAYING".into(),
+            state: EndReason::Ended,
             teams,
             title: "100k+".into(),
             uid: 9999999,
             compression: "zstd".into(),
             version: 2,
-        };
+            divergences: None,
+            body_sha256: None,
+        });
         assert_eq!(serde_json::to_string(&header).unwrap(), expected);
+
+        let mut header = header;
+        header.set_end_reason(EndReason::TimedOut);
+        let json: serde_json::Value = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["complete"], false);
+        assert_eq!(json["state"], "TIMED_OUT");
+    }
+
+    #[tokio::test]
+    async fn test_v3_header_matches_schema() {
+        let db = Queries::new(std::sync::Arc::new(mock_database()));
+        let mut header = ReplayJsonHeader::from_id_and_db(&db, 1, JsonHeaderFormat::V3)
+            .await
+            .unwrap();
+        header.set_replay_info(
+            Some(100),
+            ReplayInfo {
+                body_size: 10,
+                body_sha256: "00".into(),
+                writers: 2,
+                merge_quorum: Some(2),
+            },
+        );
+        let json = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["version"], 3);
+        assert_eq!(json["ticks"], 100);
+        assert_eq!(json["writers"], 2);
+        assert_eq!(json["players"][2]["login"], "user3");
+        assert_eq!(json["players"][2]["id"], 3);
+        assert_eq!(json["players"][2]["team"], 2);
+
+        let schema: serde_json::Value =
+            serde_json::from_str(include_str!("../../../docs/replay_header_v3.schema.json")).unwrap();
+        let mut fields: Vec<&String> = json.as_object().unwrap().keys().collect();
+        let mut required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_str().unwrap())
+            .collect();
+        fields.sort();
+        required.sort();
+        assert_eq!(fields, required);
+        let player = json["players"][0].as_object().unwrap();
+        let player_schema = schema["properties"]["players"]["items"]["properties"]
+            .as_object()
+            .unwrap();
+        assert!(player.keys().eq(player_schema.keys()));
     }
 }
@@ -1,10 +1,16 @@
 pub mod directory;
+mod diverged;
 mod json_header;
+mod pending;
+mod reader;
 mod saver;
+mod summary;
 mod writer;
-pub use directory::SavedReplayDirectory;
-pub use json_header::ReplayJsonHeader;
+pub use directory::{SaveOutcome, SavedReplayDirectory, SavedReplayFile};
+pub use diverged::{CaptureSettings, DivergedStream, StreamCapture};
+pub use json_header::{ReplayInfo, ReplayJsonHeader};
 pub use saver::{InnerReplaySaver, ReplaySaver};
+pub use writer::BodyDigest;
 
 #[cfg(test)]
 pub use writer::test;
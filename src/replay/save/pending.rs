@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use super::ReplayInfo;
+use crate::notify::ReplaySavedEvent;
+use crate::replay::{end_reason::EndReason, receive::divergence::DivergenceRecord};
+
+// Replays we couldn't finish saving because the database was unavailable. We need the database
+// both for the replay's JSON header and for marking the replay as available, so until it comes
+// back, the compressed replay and whatever we'll need to finish saving it are kept on disk.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PendingReplay {
+    pub ticks: Option<u32>,
+    // Whether there's spooled replay data that still needs a JSON header and saving.
+    pub has_replay: bool,
+    // What to set in game stats. Only meaningful once has_replay is false.
+    pub replay_available: bool,
+    pub divergences: Option<Vec<DivergenceRecord>>,
+    #[serde(default)]
+    pub end_reason: EndReason,
+    #[serde(default)]
+    pub info: ReplayInfo,
+    // Set once the replay file is written, sent out once game stats are updated.
+    #[serde(default)]
+    pub saved: Option<ReplaySavedEvent>,
+    // Failed attempts to save it while the database was reachable.
+    #[serde(default)]
+    pub failures: u32,
+}
+
+impl PendingReplay {
+    pub fn replay(
+        ticks: Option<u32>,
+        divergences: Option<Vec<DivergenceRecord>>,
+        end_reason: EndReason,
+        info: ReplayInfo,
+    ) -> Self {
+        Self {
+            ticks,
+            has_replay: true,
+            replay_available: false,
+            divergences,
+            end_reason,
+            info,
+            saved: None,
+            failures: 0,
+        }
+    }
+
+    // For replays recovered after a crash.
+    pub fn crashed_replay(ticks: Option<u32>, info: ReplayInfo) -> Self {
+        Self::replay(ticks, None, EndReason::Crashed, info)
+    }
+
+    pub fn game_stats(ticks: Option<u32>, replay_available: bool) -> Self {
+        Self {
+            ticks,
+            has_replay: false,
+            replay_available,
+            divergences: None,
+            end_reason: EndReason::Ended,
+            info: ReplayInfo::default(),
+            saved: None,
+            failures: 0,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+
+    pub fn from_json(data: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(data)
+    }
+}
@@ -0,0 +1,68 @@
+use async_compression::tokio::bufread::ZstdDecoder;
+use tokio::io::{AsyncRead, BufReader};
+
+use crate::server::connection::read_until_exact;
+
+// Reverse of write_replay_file_from_compressed. Skips the json header and returns decompressed replay data, which
+// is exactly what a reader would've received from a live replay.
+pub async fn read_replay_file(from: impl AsyncRead + Unpin) -> std::io::Result<impl AsyncRead + Unpin> {
+    let mut read = BufReader::new(from);
+    let mut json = Vec::new();
+    read_until_exact(&mut read, b'\n', &mut json).await?;
+    Ok(ZstdDecoder::new(read))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use sha2::Digest;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::replay::save::writer::{write_compressed_replay, write_replay_file_from_compressed};
+    use crate::replay::streams::{MergedReplay, ReplayHeader, WriterReplay};
+    use crate::util::buf_traits::DiscontiguousBuf;
+    use crate::util::test::{compare_bufs, get_file};
+
+    #[tokio::test]
+    async fn test_read_written_replay_file() {
+        let example_header = get_file("example_header");
+        let example_body = get_file("example_body");
+        let example_replay = get_file("example");
+
+        let mut writer_replay = WriterReplay::new();
+        writer_replay.add_data(&example_body);
+        let mut merged_replay = MergedReplay::new();
+        merged_replay.add_header(ReplayHeader::new(example_header));
+        merged_replay.add_data(&writer_replay, writer_replay.get_data().len());
+        merged_replay.advance_delayed_data(writer_replay.get_data().len());
+        merged_replay.finish();
+
+        let mut compressed = Vec::new();
+        let digest = write_compressed_replay(&mut compressed, Rc::new(RefCell::new(merged_replay)), 10)
+            .await
+            .unwrap();
+        assert_eq!(digest.size, example_body.len());
+        assert_eq!(digest.sha256, hex::encode(sha2::Sha256::digest(&example_body)));
+        let mut file = Vec::new();
+        let checksum = write_replay_file_from_compressed(&mut file, "{}", &compressed[..])
+            .await
+            .unwrap();
+        assert_eq!(checksum, hex::encode(sha2::Sha256::digest(&compressed)));
+
+        let mut data = Vec::new();
+        let mut reader = read_replay_file(&file[..]).await.unwrap();
+        reader.read_to_end(&mut data).await.unwrap();
+        compare_bufs(example_replay, data);
+    }
+
+    #[tokio::test]
+    async fn test_read_replay_file_without_json_header() {
+        let file: &[u8] = b"not a replay";
+        read_replay_file(file)
+            .await
+            .err()
+            .expect("Replay without json header should be rejected");
+    }
+}
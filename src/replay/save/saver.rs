@@ -1,103 +1,1003 @@
-use std::{io::Read, sync::Arc};
+use std::{
+    io::{Read, SeekFrom},
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    config::Settings, database::database::Database, database::queries::Queries, metrics, replay::streams::MReplayRef,
-    util::buf_traits::ReadAtExt,
+    config::{JsonHeaderFormat, MergeStrategyKind, Settings},
+    database::database::Database,
+    database::queries::Queries,
+    error::SaveError,
+    metrics,
+    notify::{Notifier, ReplaySavedEvent},
+    replay::end_reason::EndReason,
+    replay::receive::divergence::DivergenceRecord,
+    replay::streams::MReplayRef,
+    replay::timeline::Timeline,
+    util::timeout::{cancellable, timeout},
 };
 
-use super::{writer::write_replay_file, ReplayJsonHeader, SavedReplayDirectory};
+use super::{
+    directory::TempReplayFile,
+    pending::PendingReplay,
+    reader::read_replay_file,
+    summary::summarize_game,
+    writer::{
+        write_compressed_data, write_compressed_replay, write_replay_file_from_compressed, BodyDigest,
+        IncrementalReplayWriter,
+    },
+    DivergedStream, ReplayInfo, ReplayJsonHeader, SaveOutcome, SavedReplayDirectory,
+};
 use faf_replay_parser::scfa;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 
 pub type ReplaySaver = Arc<InnerReplaySaver>;
 
+// Live copies of replays we crashed on were never finished, so we take as much as we can.
+fn decompress_truncated(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Ok(mut decoder) = zstd::stream::read::Decoder::new(data) {
+        let mut buf = [0; 4096];
+        while let Ok(n @ 1..) = decoder.read(&mut buf) {
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+    out
+}
+
+fn get_ticks(mut data: impl Read, id: u64) -> Option<u32> {
+    let ticks = scfa::parser::parse_body_ticks(&mut data);
+    if let Err(e) = &ticks {
+        log::info!("Failed to parse tick count for replay {}: {}", id, e);
+    }
+    ticks.ok()
+}
+
+fn replay_info(mut body: impl Read, writers: usize, merge_quorum: Option<usize>) -> std::io::Result<ReplayInfo> {
+    let mut hasher = Sha256::new();
+    let body_size = std::io::copy(&mut body, &mut hasher)? as usize;
+    Ok(ReplayInfo {
+        body_size,
+        body_sha256: hex::encode(hasher.finalize()),
+        writers,
+        merge_quorum,
+    })
+}
+
 #[cfg_attr(test, faux::create)]
 pub struct InnerReplaySaver {
     db: Queries,
     save_dir: SavedReplayDirectory,
     compression_level: u32,
+    divergences_in_json_header: bool,
+    max_diverged_streams: usize,
+    pending_retry_interval: Duration,
+    pending_retry_max_interval: Duration,
+    pending_max_failures: u32,
+    live_spool_interval: Duration,
+    json_header_format: JsonHeaderFormat,
+    merge_quorum: Option<usize>,
+    checksums_in_database: bool,
+    game_summary: bool,
+    notifier: Notifier,
 }
 
 impl InnerReplaySaver {
-    pub fn new(db: Database, save_dir: SavedReplayDirectory, config: &Settings) -> Arc<Self> {
+    pub fn new(db: Arc<Database>, save_dir: SavedReplayDirectory, config: &Settings) -> Arc<Self> {
         Arc::new(Self::new_inner(db, save_dir, config))
     }
 }
 
 #[cfg_attr(test, faux::methods)]
 impl InnerReplaySaver {
-    fn new_inner(db: Database, save_dir: SavedReplayDirectory, config: &Settings) -> Self {
+    fn new_inner(db: Arc<Database>, save_dir: SavedReplayDirectory, config: &Settings) -> Self {
         let compression_level = config.storage.compression_level;
         Self {
             db: Queries::new(db),
             save_dir,
             compression_level,
+            divergences_in_json_header: config.storage.divergences_in_json_header,
+            max_diverged_streams: config.storage.max_diverged_streams,
+            pending_retry_interval: config.storage.pending_retry_interval_s,
+            pending_retry_max_interval: config.storage.pending_retry_max_interval_s,
+            pending_max_failures: config.storage.pending_max_failures,
+            live_spool_interval: config.storage.live_spool_interval_s,
+            json_header_format: config.storage.json_header_format,
+            checksums_in_database: config.storage.checksums_in_database,
+            game_summary: config.storage.game_summary,
+            merge_quorum: (config.replay.merge_strategy == MergeStrategyKind::Quorum)
+                .then_some(config.replay.merge_quorum_size),
+            notifier: Notifier::new(&config.notify),
+        }
+    }
+
+    async fn count_ticks(&self, replay: &MReplayRef, id: u64) -> Option<u32> {
+        replay.borrow().get_header()?;
+        let count = replay
+            .borrow()
+            .get_data()
+            .read_with(usize::MAX, move |body| get_ticks(body, id));
+        count.await.unwrap_or_else(|e| {
+            log::warn!("Failed to read replay {} data: {}", id, e);
+            None
+        })
+    }
+
+    fn merged_replay_info(&self, body: Option<&BodyDigest>, timeline: &Timeline) -> ReplayInfo {
+        let body = body.cloned().unwrap_or_default();
+        ReplayInfo {
+            body_size: body.size,
+            body_sha256: body.sha256,
+            writers: timeline.writer_count(),
+            merge_quorum: self.merge_quorum,
         }
     }
 
-    fn get_ticks(&self, mut data: impl Read, id: u64) -> Option<u32> {
-        let ticks = scfa::parser::parse_body_ticks(&mut data);
-        if let Err(e) = &ticks {
-            log::info!("Failed to parse tick count for replay {}: {}", id, e);
+    // The JSON header holds the body digest, so the replay is compressed before its file is
+    // written. If it was spooled, the live copy already is.
+    async fn compress_replay(
+        &self,
+        replay: &MReplayRef,
+        id: u64,
+        spooled: Option<BodyDigest>,
+    ) -> std::io::Result<(Box<dyn AsyncRead + Unpin + Send>, BodyDigest)> {
+        if let Some(digest) = spooled {
+            return Ok((self.save_dir.open_live_spool_file(id).await?, digest));
         }
-        ticks.ok()
+        let mut file = self.save_dir.create_scratch_file().await?;
+        let digest = write_compressed_replay(&mut file, replay.clone(), self.compression_level).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        Ok((Box::new(file), digest))
     }
 
-    fn count_ticks(&self, replay: MReplayRef, id: u64) -> Option<u32> {
-        replay.borrow().get_header()?;
-        let header_len = replay.borrow().header_len();
-        self.get_ticks(&mut replay.reader_from(header_len), id)
+    async fn json_header(&self, id: u64, pending: &PendingReplay) -> Result<ReplayJsonHeader, SaveError> {
+        let mut json_header = ReplayJsonHeader::from_id_and_db(&self.db, id, self.json_header_format).await?;
+        if let Some(d) = pending.divergences.clone() {
+            json_header.set_divergences(d);
+        }
+        json_header.set_end_reason(pending.end_reason);
+        json_header.set_replay_info(pending.ticks, pending.info.clone());
+        Ok(json_header)
+    }
+
+    fn json_header_divergences(&self, timeline: &Timeline) -> Option<Vec<DivergenceRecord>> {
+        self.divergences_in_json_header.then(|| {
+            timeline
+                .divergences()
+                .into_iter()
+                .filter(|d| d.is_suspicious())
+                .collect()
+        })
     }
 
-    async fn save_replay_to_disk(&self, replay: MReplayRef, id: u64) -> bool {
+    // Fails only if we couldn't fetch the JSON header from the database.
+    async fn save_replay_to_disk(
+        &self,
+        replay: &MReplayRef,
+        compressed: std::io::Result<Box<dyn AsyncRead + Unpin + Send>>,
+        id: u64,
+        pending: &mut PendingReplay,
+    ) -> Result<bool, SaveError> {
         if replay.borrow().get_header().is_none() {
             log::info!("Replay {} is empty, not saving.", id);
-            return false;
+            return Ok(false);
         }
-        let json_header = match ReplayJsonHeader::from_id_and_db(&self.db, id).await {
+        let json_header = self.json_header(id, pending).await?;
+        let (temp, target_file) = match self.save_dir.create_temp_replay_file(id).await {
             Err(e) => {
-                log::info!("Failed to fetch game {} stats from database: {}", id, e);
-                return false;
+                log::warn!("Failed to create file for replay {}: {}", id, e);
+                return Ok(false);
+            }
+            Ok(f) => f,
+        };
+        let written = match compressed {
+            Ok(from) => write_replay_file_from_compressed(target_file, json_header, from).await,
+            Err(e) => Err(e),
+        };
+        let file_sha256 = match written {
+            Err(e) => {
+                log::warn!("Failed to write out replay {}: {}", id, e);
+                self.save_dir.discard_temp_replay_file(temp).await;
+                return Ok(false);
             }
-            Ok(r) => r,
+            Ok(h) => h,
         };
-        let target_file = match self.save_dir.touch_and_return_file(id).await {
+        Ok(self.commit_replay_file(temp, pending, &file_sha256).await)
+    }
+
+    async fn store_checksums(&self, id: u64, body_sha256: &str, file_sha256: &str) {
+        if !self.checksums_in_database || body_sha256.is_empty() {
+            return;
+        }
+        if let Err(e) = self.db.update_replay_checksums(id, body_sha256, file_sha256).await {
+            log::info!("Failed to save checksums of replay {}: {}", id, e);
+        }
+    }
+
+    async fn commit_replay_file(&self, temp: TempReplayFile, pending: &mut PendingReplay, file_sha256: &str) -> bool {
+        let id = temp.replay_id;
+        let saved = match self.save_dir.commit_replay_file(temp.clone()).await {
             Err(e) => {
-                log::warn!("Failed to create file for replay {}: {}", id, e);
+                log::warn!("Failed to move replay {} into place: {}", id, e);
+                self.save_dir.discard_temp_replay_file(temp).await;
                 return false;
             }
-            Ok(f) => f,
+            Ok(s) => s,
         };
-        if let Err(e) = write_replay_file(target_file, json_header, replay, self.compression_level).await {
-            log::warn!("Failed to write out replay {}: {}", id, e);
-            return false;
+        let outcome = saved.outcome;
+        if outcome != SaveOutcome::Created {
+            log::info!("Replay {} was already saved. Outcome: {}", id, outcome.label());
+        }
+        // The replay is available either way, but we only count files we actually wrote.
+        if outcome != SaveOutcome::KeptExisting {
+            metrics::SAVED_REPLAYS.inc();
+            self.store_checksums(id, &pending.info.body_sha256, file_sha256).await;
+            pending.saved = Some(ReplaySavedEvent {
+                game_id: id,
+                path: saved.path,
+                size: saved.size,
+                ticks: pending.ticks,
+                body_sha256: pending.info.body_sha256.clone(),
+                file_sha256: file_sha256.into(),
+                complete: pending.end_reason.is_complete(),
+                state: pending.end_reason,
+            });
         }
-        metrics::SAVED_REPLAYS.inc();
         return true;
     }
 
-    pub async fn save_replay(&self, replay: MReplayRef, id: u64) {
-        let replay_saved = self.save_replay_to_disk(replay.clone(), id).await;
-        let ticks = self.count_ticks(replay, id);
-        if let Err(e) = self.db.update_game_stats(id, ticks, replay_saved).await {
-            log::info!("Failed to update game stats for replay {}: {}", id, e);
+    async fn notify_saved(&self, pending: &PendingReplay) {
+        if let Some(event) = &pending.saved {
+            self.notifier.notify(event).await;
+        }
+    }
+
+    async fn save_timeline(&self, timeline: Timeline, id: u64) {
+        let data = match timeline.to_json() {
+            Ok(d) => d,
+            Err(e) => {
+                log::warn!("Failed to serialize timeline of replay {}: {}", id, e);
+                return;
+            }
+        };
+        if let Err(e) = self.save_dir.write_sidecar_file(id, "timeline.json", data).await {
+            log::warn!("Failed to write out timeline of replay {}: {}", id, e);
+        }
+    }
+
+    async fn save_seek_index(&self, replay: &MReplayRef, id: u64) {
+        let data = match replay.borrow().seek_index().map(|i| i.to_json()) {
+            None => return,
+            Some(Ok(d)) => d,
+            Some(Err(e)) => {
+                log::warn!("Failed to serialize seek index of replay {}: {}", id, e);
+                return;
+            }
+        };
+        if let Err(e) = self.save_dir.write_sidecar_file(id, "seek.json", data).await {
+            log::warn!("Failed to write out seek index of replay {}: {}", id, e);
+        }
+    }
+
+    async fn save_summary(&self, replay: &MReplayRef, id: u64) {
+        let header = match replay.borrow().get_header() {
+            Some(h) => h.data.clone(),
+            None => return,
+        };
+        let summarize = replay
+            .borrow()
+            .get_data()
+            .read_with(usize::MAX, move |body| summarize_game(&header, body));
+        let summary = match summarize.await {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("Failed to read replay {} data: {}", id, e);
+                return;
+            }
+        };
+        if !summary.parsed_whole_body {
+            log::info!("Replay {} body is malformed, its summary is partial", id);
+        }
+        let data = match serde_json::to_vec(&summary) {
+            Ok(d) => d,
+            Err(e) => {
+                log::warn!("Failed to serialize summary of replay {}: {}", id, e);
+                return;
+            }
+        };
+        if let Err(e) = self.save_dir.write_sidecar_file(id, "summary.json", data).await {
+            log::warn!("Failed to write out summary of replay {}: {}", id, e);
+        }
+    }
+
+    // If the replay was spooled, it's saved from the live copy instead of compressing it again.
+    pub async fn save_replay(
+        &self,
+        replay: MReplayRef,
+        id: u64,
+        timeline: Timeline,
+        end_reason: EndReason,
+        spooled: Option<BodyDigest>,
+    ) {
+        let ticks = self.count_ticks(&replay, id).await;
+        let divergences = self.json_header_divergences(&timeline);
+        let was_spooled = spooled.is_some();
+        let compressed = self.compress_replay(&replay, id, spooled).await;
+        let info = self.merged_replay_info(compressed.as_ref().ok().map(|(_, d)| d), &timeline);
+        let mut pending = PendingReplay::replay(ticks, divergences, end_reason, info);
+        let saved = self
+            .save_replay_to_disk(&replay, compressed.map(|(body, _)| body), id, &mut pending)
+            .await;
+        // Save it even if the replay wasn't saved, it might tell us why.
+        self.save_timeline(timeline, id).await;
+        if self.game_summary {
+            self.save_summary(&replay, id).await;
+        }
+        self.save_seek_index(&replay, id).await;
+        let replay_saved = match saved {
+            Ok(s) => s,
+            Err(e) if e.is_game_missing() => {
+                log::info!("Game {} is not in the database, not saving its replay", id);
+                false
+            }
+            Err(e) => {
+                log::info!(
+                    "Failed to fetch game {} stats from database, will retry later: {}",
+                    id,
+                    e
+                );
+                self.spool_replay(replay, id, pending, was_spooled).await;
+                self.remove_live_spool(id).await;
+                return;
+            }
+        };
+        match self.db.update_game_stats(id, ticks, replay_saved).await {
+            Ok(()) => self.notify_saved(&pending).await,
+            Err(e) => {
+                log::info!("Failed to update game stats for replay {}, will retry later: {}", id, e);
+                pending.has_replay = false;
+                pending.replay_available = replay_saved;
+                self.spool(id, &pending).await;
+            }
+        }
+        self.remove_live_spool(id).await;
+    }
+
+    async fn remove_live_spool(&self, id: u64) {
+        if let Err(e) = self.save_dir.remove_live_spool(id).await {
+            log::warn!("Failed to remove live copy of replay {}: {}", id, e);
+        }
+    }
+
+    async fn start_live_spool(
+        &self,
+        replay: &MReplayRef,
+        id: u64,
+    ) -> std::io::Result<Option<IncrementalReplayWriter<Box<dyn AsyncWrite + Unpin + Send>>>> {
+        let header = match replay.borrow().get_header() {
+            Some(h) => h.data.clone(),
+            None => return Ok(None),
+        };
+        let file = self.save_dir.create_live_spool_file(id, header.clone()).await?;
+        Ok(Some(
+            IncrementalReplayWriter::new(file, &header, self.compression_level).await?,
+        ))
+    }
+
+    async fn do_spool_live_replay(&self, replay: &MReplayRef, id: u64) -> std::io::Result<Option<BodyDigest>> {
+        let mut writer = None;
+        loop {
+            let finished = replay.borrow().is_finished();
+            if writer.is_none() {
+                writer = self.start_live_spool(replay, id).await?;
+            }
+            if let Some(w) = writer.as_mut() {
+                w.write_new_data(replay).await?;
+            }
+            if finished {
+                break;
+            }
+            let more_data = replay.borrow().wait_for_more_data();
+            timeout(more_data, self.live_spool_interval).await;
+        }
+        match writer {
+            Some(w) => w.finish().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Compresses the merged replay to disk as it grows, so we don't have to do it all at the end,
+    // and so we can still save it if we crash. Returns once the replay is finished, with the body
+    // digest if the whole replay was written out. The copy is removed once the replay is saved.
+    pub async fn spool_live_replay(&self, replay: MReplayRef, id: u64) -> Option<BodyDigest> {
+        self.do_spool_live_replay(&replay, id).await.unwrap_or_else(|e| {
+            log::warn!("Failed to write out live copy of replay {}: {}", id, e);
+            None
+        })
+    }
+
+    async fn finalize_orphaned_live_spool(&self, id: u64) -> std::io::Result<()> {
+        let (header, compressed) = self.save_dir.read_live_spool(id).await?;
+        let decompressed = decompress_truncated(&compressed);
+        let body = decompressed.get(header.len()..).unwrap_or_default();
+        let ticks = get_ticks(body, id);
+        let info = replay_info(body, 0, self.merge_quorum)?;
+        let mut data = header;
+        data.extend_from_slice(body);
+        let file = self.save_dir.create_pending_replay_file(id).await?;
+        write_compressed_data(file, &data, self.compression_level).await?;
+        let info = PendingReplay::crashed_replay(ticks, info).to_json()?;
+        self.save_dir.write_pending_info(id, info).await
+    }
+
+    // Live copies left over from replays that were running when we crashed. They become pending
+    // replays, so they're saved once we can reach the database.
+    pub async fn finalize_orphaned_live_spools(&self) {
+        let ids = match self.save_dir.list_live_spools().await {
+            Ok(ids) => ids,
+            Err(e) => {
+                log::warn!("Failed to list live replay copies: {}", e);
+                return;
+            }
+        };
+        for id in ids {
+            log::info!("Replay {} was not saved before shutdown, saving it as incomplete", id);
+            match self.finalize_orphaned_live_spool(id).await {
+                Ok(()) => self.remove_live_spool(id).await,
+                Err(e) => log::warn!("Failed to recover live copy of replay {}: {}", id, e),
+            }
+        }
+    }
+
+    async fn spool(&self, id: u64, pending: &PendingReplay) {
+        let res = match pending.to_json() {
+            Ok(data) => self.save_dir.write_pending_info(id, data).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            log::warn!("Failed to write out pending info of replay {}: {}", id, e);
+        }
+    }
+
+    async fn spool_replay(&self, replay: MReplayRef, id: u64, pending: PendingReplay, spooled: bool) {
+        let res = async {
+            let mut to = self.save_dir.create_pending_replay_file(id).await?;
+            if spooled {
+                let mut from = self.save_dir.open_live_spool_file(id).await?;
+                tokio::io::copy(&mut from, &mut to).await?;
+                to.shutdown().await
+            } else {
+                write_compressed_replay(to, replay, self.compression_level)
+                    .await
+                    .map(|_| ())
+            }
+        }
+        .await;
+        if let Err(e) = res {
+            log::warn!("Failed to spool replay {}, it will not be saved: {}", id, e);
+            self.spool(id, &PendingReplay::game_stats(pending.ticks, false)).await;
+            return;
+        }
+        self.spool(id, &pending).await;
+    }
+
+    async fn save_pending_replay_to_disk(
+        &self,
+        id: u64,
+        json_header: ReplayJsonHeader,
+        pending: &mut PendingReplay,
+    ) -> bool {
+        let res = async {
+            let data = self.save_dir.open_pending_replay_file(id).await?;
+            let (temp, target_file) = self.save_dir.create_temp_replay_file(id).await?;
+            match write_replay_file_from_compressed(target_file, json_header, data).await {
+                Ok(h) => Ok((temp, h)),
+                Err(e) => {
+                    self.save_dir.discard_temp_replay_file(temp).await;
+                    Err(e)
+                }
+            }
+        }
+        .await;
+        let (temp, file_sha256) = match res {
+            Err(e) => {
+                log::warn!("Failed to write out pending replay {}: {}", id, e);
+                return false;
+            }
+            Ok(h) => h,
+        };
+        self.commit_replay_file(temp, pending, &file_sha256).await
+    }
+
+    async fn finish_pending_replay(&self, id: u64, pending: &mut PendingReplay) -> Result<(), SaveError> {
+        if pending.has_replay {
+            let json_header = self.json_header(id, pending).await?;
+            pending.replay_available = self.save_pending_replay_to_disk(id, json_header, pending).await;
+            pending.has_replay = false;
+            // So we don't save the replay again if updating game stats fails.
+            self.spool(id, pending).await;
+        }
+        self.db
+            .update_game_stats(id, pending.ticks, pending.replay_available)
+            .await?;
+        Ok(())
+    }
+
+    // Returns whether the replay is no longer pending, or an error if the database is unreachable.
+    // Replays that keep failing otherwise are eventually dropped, so they don't stay around forever.
+    async fn retry_pending_replay(&self, id: u64) -> Result<bool, SaveError> {
+        let info = self.save_dir.read_pending_info(id).await;
+        let mut pending = match info.map(|i| PendingReplay::from_json(&i)) {
+            Ok(Ok(p)) => p,
+            e => {
+                log::warn!(
+                    "Failed to read pending info of replay {}, dropping it: {:?}",
+                    id,
+                    e.err()
+                );
+                self.remove_pending_replay(id).await;
+                return Ok(true);
+            }
+        };
+        match self.finish_pending_replay(id, &mut pending).await {
+            Ok(()) => {
+                log::info!("Saved pending replay {}", id);
+                self.remove_pending_replay(id).await;
+                self.notify_saved(&pending).await;
+                Ok(true)
+            }
+            Err(e) if e.is_game_missing() => {
+                log::info!("Game {} is not in the database, dropping its pending replay", id);
+                self.remove_pending_replay(id).await;
+                Ok(true)
+            }
+            Err(e) if e.is_database_unreachable() => Err(e),
+            Err(e) => {
+                pending.failures += 1;
+                if pending.failures >= self.pending_max_failures {
+                    log::warn!(
+                        "Failed to save pending replay {} too many times, dropping it: {}",
+                        id,
+                        e
+                    );
+                    self.remove_pending_replay(id).await;
+                    return Ok(true);
+                }
+                log::info!("Failed to save pending replay {}, will retry later: {}", id, e);
+                self.spool(id, &pending).await;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn remove_pending_replay(&self, id: u64) {
+        if let Err(e) = self.save_dir.remove_pending_replay(id).await {
+            log::warn!("Failed to remove pending replay {}: {}", id, e);
+        }
+    }
+
+    // Returns false if we should back off. Only stops early if the database is unreachable, since
+    // then no other replay will save either.
+    async fn retry_pending_replays_once(&self) -> bool {
+        let ids = match self.save_dir.list_pending_replays().await {
+            Ok(ids) => ids,
+            Err(e) => {
+                log::warn!("Failed to list pending replays: {}", e);
+                return false;
+            }
+        };
+        metrics::PENDING_REPLAYS.set(ids.len() as i64);
+        for id in ids {
+            match self.retry_pending_replay(id).await {
+                Ok(true) => metrics::PENDING_REPLAYS.dec(),
+                Ok(false) => (),
+                Err(e) => {
+                    log::info!("Database is unreachable, will retry pending replays later: {}", e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub async fn retry_pending_replays(&self, shutdown_token: CancellationToken) {
+        let mut interval = self.pending_retry_interval;
+        loop {
+            interval = if self.retry_pending_replays_once().await {
+                self.pending_retry_interval
+            } else {
+                std::cmp::min(interval * 2, self.pending_retry_max_interval)
+            };
+            if cancellable(tokio::time::sleep(interval), &shutdown_token)
+                .await
+                .is_none()
+            {
+                return;
+            }
+        }
+    }
+
+    pub async fn save_diverged_streams(&self, streams: Vec<DivergedStream>, id: u64) {
+        for stream in streams.into_iter() {
+            let json_header = match stream.json_header_line(id) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::warn!("Failed to serialize diverged stream of replay {}: {}", id, e);
+                    continue;
+                }
+            };
+            let writer = stream.writer();
+            let data = stream.into_data();
+            if let Err(e) = self.save_dir.write_diverged_stream(id, writer, json_header, data).await {
+                log::warn!("Failed to write out diverged stream of replay {}: {}", id, e);
+            }
+        }
+        if let Err(e) = self.save_dir.prune_diverged_streams(self.max_diverged_streams).await {
+            log::warn!("Failed to remove old diverged streams: {}", e);
         }
     }
+
+    // Boxing so faux can work.
+    pub async fn open_saved_replay(&self, id: u64) -> std::io::Result<Box<dyn AsyncRead + Unpin>> {
+        let file = self.save_dir.open_replay_file(id).await?;
+        Ok(Box::new(read_replay_file(file).await?))
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::atomic::{AtomicBool, Ordering},
+        sync::Mutex,
+    };
+
     use super::*;
-    use crate::config::test::default_config;
+    use crate::config::{test::default_config, ExistingReplayPolicy};
+    use crate::database::database::test::{default_game_stats, mock_database};
+    use crate::notify::test::webhook_stand_in;
+    use crate::replay::save::test::unpack_replay;
+    use crate::replay::streams::{
+        replay_with_budget, MemoryBudget, MergedReplay, ReplayHeader, SeekIndex, WriterReplay,
+    };
     use crate::util::test::get_file;
 
     #[test]
     fn saver_can_read_example_replay_ticks() {
-        let config = Arc::new(default_config());
         let example_replay = get_file("example_body");
-        let mock_db = Database::faux();
-        let mock_dir = SavedReplayDirectory::faux();
-        let saver = InnerReplaySaver::new_inner(mock_db, mock_dir, &config);
-        let ticks = saver.get_ticks(&example_replay[..], 1);
+        let ticks = get_ticks(&example_replay[..], 1);
         assert!(ticks.is_some());
     }
+
+    fn test_replay() -> MReplayRef {
+        let mut writer = WriterReplay::new();
+        writer.add_data(b"data");
+        let mut replay = MergedReplay::new();
+        replay.add_header(ReplayHeader::new(b"header".to_vec()));
+        replay.add_data(&writer, 4);
+        replay.advance_delayed_data(4);
+        replay.finish();
+        Rc::new(RefCell::new(replay))
+    }
+
+    #[tokio::test]
+    async fn test_replay_is_saved_once_database_is_back() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = || SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut config = default_config();
+        config.storage.json_header_format = JsonHeaderFormat::V3;
+        let config = Arc::new(config);
+
+        let db_up = Arc::new(AtomicBool::new(false));
+        let stats_updates = Arc::new(Mutex::new(Vec::new()));
+        let mut mock_db = mock_database();
+        let up = db_up.clone();
+        faux::when!(mock_db.get_game_stat_row).then(move |_| match up.load(Ordering::SeqCst) {
+            true => Ok(default_game_stats()),
+            false => Err(sqlx::Error::PoolTimedOut.into()),
+        });
+        let (up, updates) = (db_up.clone(), stats_updates.clone());
+        faux::when!(mock_db.update_game_stats).then(move |update| match up.load(Ordering::SeqCst) {
+            true => {
+                updates.lock().unwrap().push(update);
+                Ok(())
+            }
+            false => Err(sqlx::Error::PoolTimedOut.into()),
+        });
+        let db = Arc::new(mock_db);
+
+        let saver = InnerReplaySaver::new_inner(db.clone(), dir(), &config);
+        saver
+            .save_replay(test_replay(), 1, Timeline::new(), EndReason::TimedOut, None)
+            .await;
+        assert!(!saver.retry_pending_replays_once().await);
+        assert!(stats_updates.lock().unwrap().is_empty());
+
+        // Simulating a restart.
+        let saver = InnerReplaySaver::new_inner(db, dir(), &config);
+        db_up.store(true, Ordering::SeqCst);
+        assert!(saver.retry_pending_replays_once().await);
+        assert_eq!(*stats_updates.lock().unwrap(), vec![(1, None, true)]);
+        assert!(dir().list_pending_replays().await.unwrap().is_empty());
+
+        let saved = std::fs::read(tmp_dir.path().join("0/0/0/0/1.fafreplay")).unwrap();
+        let (json, data) = unpack_replay(&saved[..]).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["uid"], 1);
+        assert_eq!(json["state"], "TIMED_OUT");
+        assert_eq!(json["version"], 3);
+        assert_eq!(json["body_size"], 4);
+        assert_eq!(
+            json["body_sha256"],
+            "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7"
+        );
+        assert_eq!(data, b"headerdata");
+    }
+
+    #[tokio::test]
+    async fn test_pending_replays_that_cannot_be_saved_are_dropped() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = || SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut config = default_config();
+        config.storage.pending_max_failures = 2;
+        let config = Arc::new(config);
+
+        // Game 1 does not exist, game 3 fails for some other reason.
+        let db_up = Arc::new(AtomicBool::new(false));
+        let mut mock_db = mock_database();
+        let up = db_up.clone();
+        faux::when!(mock_db.get_game_stat_row).then(move |id| match (up.load(Ordering::SeqCst), id) {
+            (false, _) => Err(sqlx::Error::PoolTimedOut.into()),
+            (true, 1) => Err(sqlx::Error::RowNotFound.into()),
+            (true, 3) => Err(sqlx::Error::Protocol("oops".into()).into()),
+            (true, _) => Ok(default_game_stats()),
+        });
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_db), dir(), &config);
+        for id in 1..=3 {
+            saver
+                .save_replay(test_replay(), id, Timeline::new(), EndReason::Ended, None)
+                .await;
+        }
+        assert!(!saver.retry_pending_replays_once().await);
+        assert_eq!(dir().list_pending_replays().await.unwrap(), vec![1, 2, 3]);
+
+        db_up.store(true, Ordering::SeqCst);
+        assert!(saver.retry_pending_replays_once().await);
+        assert_eq!(dir().list_pending_replays().await.unwrap(), vec![3]);
+        assert!(tmp_dir.path().join("0/0/0/0/2.fafreplay").exists());
+        assert!(saver.retry_pending_replays_once().await);
+        assert!(dir().list_pending_replays().await.unwrap().is_empty());
+        assert!(!tmp_dir.path().join("0/0/0/0/1.fafreplay").exists());
+    }
+
+    #[tokio::test]
+    async fn test_replay_checksums_are_saved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut config = default_config();
+        config.storage.checksums_in_database = true;
+
+        let checksums = Arc::new(Mutex::new(Vec::new()));
+        let saved_checksums = checksums.clone();
+        let mut mock_db = mock_database();
+        faux::when!(mock_db.update_replay_checksums).then(move |(_, body, file)| {
+            saved_checksums
+                .lock()
+                .unwrap()
+                .push((body.to_string(), file.to_string()));
+            Ok(())
+        });
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_db), dir, &Arc::new(config));
+        saver
+            .save_replay(test_replay(), 1, Timeline::new(), EndReason::Ended, None)
+            .await;
+
+        let saved = std::fs::read(tmp_dir.path().join("0/0/0/0/1.fafreplay")).unwrap();
+        let newline = saved.iter().position(|b| *b == b'\n').unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&saved[..newline]).unwrap();
+        let body_sha256 = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7";
+        assert_eq!(json["body_sha256"], body_sha256);
+        let file_sha256 = hex::encode(Sha256::digest(&saved[newline + 1..]));
+        assert_eq!(*checksums.lock().unwrap(), vec![(body_sha256.to_string(), file_sha256)]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_saved_event_is_sent_once_game_stats_are_updated() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = || SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let (url, webhook) = webhook_stand_in(0).await;
+        let mut config = default_config();
+        config.notify.webhook_url = url;
+        let config = Arc::new(config);
+
+        let db_up = Arc::new(AtomicBool::new(false));
+        let mut mock_db = mock_database();
+        let up = db_up.clone();
+        faux::when!(mock_db.update_game_stats).then(move |_| match up.load(Ordering::SeqCst) {
+            true => Ok(()),
+            false => Err(sqlx::Error::PoolTimedOut.into()),
+        });
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_db), dir(), &config);
+        saver
+            .save_replay(test_replay(), 1, Timeline::new(), EndReason::Ended, None)
+            .await;
+        let pending = PendingReplay::from_json(&dir().read_pending_info(1).await.unwrap()).unwrap();
+        assert!(pending.saved.is_some());
+
+        db_up.store(true, Ordering::SeqCst);
+        assert!(saver.retry_pending_replays_once().await);
+        let bodies = webhook.await.unwrap();
+        let event: ReplaySavedEvent = serde_json::from_str(&bodies[0]).unwrap();
+        let path = tmp_dir.path().join("0/0/0/0/1.fafreplay");
+        assert_eq!(event.path, path);
+        assert_eq!(event.size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(event.game_id, 1);
+        assert_eq!(event.state, EndReason::Ended);
+        assert!(event.complete);
+        assert_eq!(
+            event.body_sha256,
+            "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7"
+        );
+        assert_eq!(Some(event), pending.saved);
+    }
+
+    #[tokio::test]
+    async fn test_game_summary_is_saved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut config = default_config();
+        config.storage.game_summary = true;
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_database()), dir, &Arc::new(config));
+
+        let body = get_file("example_body");
+        let mut writer = WriterReplay::new();
+        writer.add_data(&body);
+        let mut replay = MergedReplay::new();
+        replay.add_header(ReplayHeader::new(get_file("example_header")));
+        replay.add_data(&writer, body.len());
+        replay.advance_delayed_data(body.len());
+        replay.finish();
+        saver
+            .save_replay(
+                Rc::new(RefCell::new(replay)),
+                1,
+                Timeline::new(),
+                EndReason::Ended,
+                None,
+            )
+            .await;
+
+        let summary = std::fs::read(tmp_dir.path().join("0/0/0/0/1.summary.json")).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&summary).unwrap();
+        assert_eq!(summary["ticks"], get_ticks(&body[..], 1).unwrap());
+        assert_eq!(summary["parsed_whole_body"], true);
+        assert_eq!(summary["players"][0]["name"], "MazorNoob");
+    }
+
+    #[tokio::test]
+    async fn test_spilled_replay_is_saved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let mut config = default_config();
+        config.storage.game_summary = true;
+        config.storage.json_header_format = JsonHeaderFormat::V3;
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_database()), dir, &Arc::new(config));
+
+        let body = get_file("example_body");
+        let header = get_file("example_header");
+        let budget = MemoryBudget::new(Some(1), None, tmp_dir.path().join(".spill"));
+        let mut replay = replay_with_budget(budget, &header, &body).await;
+        assert!(replay.get_data().spilled_len() > 0);
+        replay.finish();
+        saver
+            .save_replay(
+                Rc::new(RefCell::new(replay)),
+                1,
+                Timeline::new(),
+                EndReason::Ended,
+                None,
+            )
+            .await;
+
+        let saved = std::fs::File::open(tmp_dir.path().join("0/0/0/0/1.fafreplay")).unwrap();
+        let (json, data) = unpack_replay(tokio::fs::File::from_std(saved)).await.unwrap();
+        assert_eq!(data, [&header[..], &body[..]].concat());
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let ticks = get_ticks(&body[..], 1).unwrap();
+        assert_eq!(json["ticks"], ticks);
+        assert_eq!(json["body_size"], body.len());
+        assert_eq!(json["body_sha256"], hex::encode(Sha256::digest(&body)));
+        let summary = std::fs::read(tmp_dir.path().join("0/0/0/0/1.summary.json")).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&summary).unwrap();
+        assert_eq!(summary["ticks"], ticks);
+        assert_eq!(summary["parsed_whole_body"], true);
+    }
+
+    #[tokio::test]
+    async fn test_seek_index_is_saved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_database()), dir, &Arc::new(default_config()));
+
+        let body = get_file("example_body");
+        let mut writer = WriterReplay::new();
+        writer.add_data(&body);
+        let mut replay = MergedReplay::new();
+        replay.set_seek_index(SeekIndex::new(100));
+        replay.add_header(ReplayHeader::new(get_file("example_header")));
+        replay.add_data(&writer, body.len());
+        replay.advance_delayed_data(body.len());
+        replay.finish();
+        saver
+            .save_replay(
+                Rc::new(RefCell::new(replay)),
+                1,
+                Timeline::new(),
+                EndReason::Ended,
+                None,
+            )
+            .await;
+
+        let index = std::fs::read(tmp_dir.path().join("0/0/0/0/1.seek.json")).unwrap();
+        let index: serde_json::Value = serde_json::from_slice(&index).unwrap();
+        assert_eq!(index["interval"], 100);
+        let points = index["points"].as_array().unwrap();
+        let last = &points[points.len() - 1];
+        let offset = last["offset"].as_u64().unwrap() as usize;
+        assert_eq!(last["tick"], get_ticks(&body[..offset], 1).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_live_replay_is_spooled_until_saved() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = || SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let config = Arc::new(default_config());
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_database()), dir(), &config);
+
+        let replay = test_replay();
+        let digest = saver.spool_live_replay(replay.clone(), 1).await.unwrap();
+        assert_eq!(digest.size, 4);
+        let (header, compressed) = dir().read_live_spool(1).await.unwrap();
+        assert_eq!(header, b"header");
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), b"headerdata");
+        saver
+            .save_replay(replay, 1, Timeline::new(), EndReason::Ended, Some(digest.clone()))
+            .await;
+        assert!(dir().list_live_spools().await.unwrap().is_empty());
+
+        let saved = std::fs::read(tmp_dir.path().join("0/0/0/0/1.fafreplay")).unwrap();
+        let (json, data) = unpack_replay(&saved[..]).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["body_sha256"], digest.sha256);
+        assert_eq!(json["body_sha256"], hex::encode(Sha256::digest(b"data")));
+        assert_eq!(data, b"headerdata");
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_live_replay_is_saved_as_incomplete() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = || SavedReplayDirectory::new(tmp_dir.path().to_str().unwrap(), ExistingReplayPolicy::Keep);
+        let config = Arc::new(default_config());
+        // Simulating a crash while the replay was being written.
+        let file = dir().create_live_spool_file(1, b"header".to_vec()).await.unwrap();
+        let mut writer = IncrementalReplayWriter::new(file, b"header", 10).await.unwrap();
+        writer.write_new_data(&test_replay()).await.unwrap();
+        drop(writer);
+
+        let saver = InnerReplaySaver::new_inner(Arc::new(mock_database()), dir(), &config);
+        saver.finalize_orphaned_live_spools().await;
+        assert!(dir().list_live_spools().await.unwrap().is_empty());
+        assert!(saver.retry_pending_replays_once().await);
+
+        let saved = std::fs::read(tmp_dir.path().join("0/0/0/0/1.fafreplay")).unwrap();
+        let (json, data) = unpack_replay(&saved[..]).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["complete"], false);
+        assert_eq!(json["state"], "CRASHED");
+        assert_eq!(data, b"headerdata");
+    }
 }
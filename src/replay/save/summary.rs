@@ -0,0 +1,350 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+};
+
+use faf_replay_parser::{
+    lua::LuaObject,
+    scfa::{parser::parse_command, replay_command, Parser, ParserOptions, ReplayCommand},
+    ReplayReadError,
+};
+use serde::Serialize;
+
+use crate::replay::streams::MAX_ADVANCE_TICKS;
+
+// Statistics about a game, taken from the replay body when we save it, so the vault can show
+// them without downloading and parsing the replay. Players are identified by their command
+// source, named after the replay header if we can parse it.
+
+const TICKS_PER_MINUTE: u32 = 600;
+// A desynced game has a desync every checksum, we don't need all of them.
+const MAX_DESYNC_TICKS: usize = 100;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PlayerSummary {
+    pub source: u8,
+    pub name: Option<String>,
+    // Commands that came from the player, as opposed to ones that just keep the game going.
+    pub actions: u64,
+    pub commands: BTreeMap<&'static str, u64>,
+    // Actions in each minute of game time.
+    pub apm: Vec<u32>,
+    pub last_active_tick: Option<u32>,
+    pub left_at_tick: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub tick: u32,
+    pub sender: String,
+    pub to: String,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct GameSummary {
+    pub ticks: u32,
+    pub players: Vec<PlayerSummary>,
+    pub chat: Vec<ChatMessage>,
+    pub desyncs: u32,
+    pub desync_ticks: Vec<u32>,
+    // False if the body was malformed past some point, the summary only covers data before it.
+    pub parsed_whole_body: bool,
+}
+
+fn is_action(command: &ReplayCommand) -> bool {
+    use ReplayCommand::*;
+    !matches!(
+        command,
+        Advance { .. } | SetCommandSource { .. } | CommandSourceTerminated | VerifyChecksum { .. } | EndGame
+    )
+}
+
+fn command_name(command: &ReplayCommand) -> &'static str {
+    use ReplayCommand::*;
+    let id = match command {
+        Advance { .. } => replay_command::ADVANCE,
+        SetCommandSource { .. } => replay_command::SET_COMMAND_SOURCE,
+        CommandSourceTerminated => replay_command::COMMAND_SOURCE_TERMINATED,
+        VerifyChecksum { .. } => replay_command::VERIFY_CHECKSUM,
+        RequestPause => replay_command::REQUEST_PAUSE,
+        Resume => replay_command::RESUME,
+        SingleStep => replay_command::SINGLE_STEP,
+        CreateUnit { .. } => replay_command::CREATE_UNIT,
+        CreateProp { .. } => replay_command::CREATE_PROP,
+        DestroyEntity { .. } => replay_command::DESTROY_ENTITY,
+        WarpEntity { .. } => replay_command::WARP_ENTITY,
+        ProcessInfoPair { .. } => replay_command::PROCESS_INFO_PAIR,
+        IssueCommand(..) => replay_command::ISSUE_COMMAND,
+        IssueFactoryCommand(..) => replay_command::ISSUE_FACTORY_COMMAND,
+        IncreaseCommandCount { .. } => replay_command::INCREASE_COMMAND_COUNT,
+        DecreaseCommandCount { .. } => replay_command::DECREASE_COMMAND_COUNT,
+        SetCommandTarget { .. } => replay_command::SET_COMMAND_TARGET,
+        SetCommandType { .. } => replay_command::SET_COMMAND_TYPE,
+        SetCommandCells { .. } => replay_command::SET_COMMAND_CELLS,
+        RemoveCommandFromQueue { .. } => replay_command::REMOVE_COMMAND_FROM_QUEUE,
+        DebugCommand { .. } => replay_command::DEBUG_COMMAND,
+        ExecuteLuaInSim { .. } => replay_command::EXECUTE_LUA_IN_SIM,
+        LuaSimCallback { .. } => replay_command::LUA_SIM_CALLBACK,
+        EndGame => replay_command::END_GAME,
+    };
+    replay_command::NAMES[id as usize]
+}
+
+fn lua_field<'a>(object: &'a LuaObject, key: &str) -> Option<&'a LuaObject> {
+    object.as_hashmap().ok()?.get(&LuaObject::from(key))
+}
+
+fn lua_string(object: &LuaObject, key: &str) -> Option<String> {
+    lua_field(object, key)?.to_string_lossy().ok()
+}
+
+// The game sends chat as a sim callback, so it ends up in the replay.
+fn chat_message(tick: u32, func: &str, args: &LuaObject) -> Option<ChatMessage> {
+    if func != "GiveResourcesToPlayer" {
+        return None;
+    }
+    let msg = lua_field(args, "Msg")?;
+    Some(ChatMessage {
+        tick,
+        sender: lua_string(args, "Sender").unwrap_or_default(),
+        to: lua_string(msg, "to").unwrap_or_default(),
+        text: lua_string(msg, "text")?,
+    })
+}
+
+// Replays store the first checksum sent for each tick, the rest must match it.
+#[derive(Default)]
+struct ChecksumTracker {
+    tick: Option<u32>,
+    digest: Vec<u8>,
+}
+
+impl ChecksumTracker {
+    // Returns whether the checksum disagrees with the first one for its tick.
+    fn check(&mut self, digest: &[u8], tick: u32) -> bool {
+        if self.tick != Some(tick) {
+            self.tick = Some(tick);
+            self.digest = digest.to_vec();
+            return false;
+        }
+        self.digest != digest
+    }
+}
+
+#[derive(Default)]
+struct SummaryBuilder {
+    summary: GameSummary,
+    players: HashMap<u8, PlayerSummary>,
+    source: u8,
+    checksums: ChecksumTracker,
+}
+
+impl SummaryBuilder {
+    fn player(&mut self) -> &mut PlayerSummary {
+        let source = self.source;
+        self.players.entry(source).or_insert_with(|| PlayerSummary {
+            source,
+            ..Default::default()
+        })
+    }
+
+    fn verify_checksum(&mut self, digest: &[u8], tick: u32) {
+        if !self.checksums.check(digest, tick) {
+            return;
+        }
+        self.summary.desyncs += 1;
+        if self.summary.desync_ticks.len() < MAX_DESYNC_TICKS {
+            self.summary.desync_ticks.push(tick);
+        }
+    }
+
+    fn process(&mut self, command: ReplayCommand) {
+        let tick = self.summary.ticks;
+        match &command {
+            ReplayCommand::Advance { ticks } => self.summary.ticks = self.summary.ticks.saturating_add(*ticks),
+            ReplayCommand::SetCommandSource { id } => self.source = *id,
+            ReplayCommand::CommandSourceTerminated => self.player().left_at_tick = Some(tick),
+            ReplayCommand::VerifyChecksum { digest, tick } => self.verify_checksum(digest, *tick),
+            ReplayCommand::LuaSimCallback { func, args, .. } => {
+                if let Some(m) = chat_message(tick, func, args) {
+                    self.summary.chat.push(m);
+                }
+            }
+            _ => (),
+        }
+        if !is_action(&command) {
+            return;
+        }
+        let player = self.player();
+        player.actions += 1;
+        *player.commands.entry(command_name(&command)).or_default() += 1;
+        let minute = (tick / TICKS_PER_MINUTE) as usize;
+        if player.apm.len() <= minute {
+            player.apm.resize(minute + 1, 0);
+        }
+        player.apm[minute] += 1;
+        player.last_active_tick = Some(tick);
+    }
+
+    fn finish(mut self, names: HashMap<u32, String>, parsed_whole_body: bool) -> GameSummary {
+        let mut players: Vec<PlayerSummary> = self.players.into_values().collect();
+        players.sort_by_key(|p| p.source);
+        for p in players.iter_mut() {
+            p.name = names.get(&(p.source as u32)).cloned();
+        }
+        self.summary.players = players;
+        self.summary.parsed_whole_body = parsed_whole_body;
+        self.summary
+    }
+}
+
+// Command source IDs mapped to player names. Armies know their command source, the header's
+// list of sources doesn't keep its order once parsed. Observers have no army, so no name.
+fn player_names(mut header: &[u8]) -> HashMap<u32, String> {
+    let header = match Parser::new().parse_header(&mut header) {
+        Ok(h) => h,
+        Err(_) => return HashMap::new(),
+    };
+    header
+        .armies
+        .iter()
+        .filter_map(|(source, army)| Some((*source, lua_string(army, "PlayerName")?)))
+        .collect()
+}
+
+pub fn summarize_game(header: &[u8], mut body: impl Read) -> GameSummary {
+    let options = ParserOptions {
+        commands: (0..=replay_command::MAX).collect(),
+        limit: None,
+        save_commands: false,
+        stop_on_desync: false,
+    };
+    let mut builder = SummaryBuilder::default();
+    let mut buf = Vec::new();
+    let parsed_whole_body = loop {
+        match parse_command(&mut body, &options, &mut buf) {
+            // Stop at a huge advance, it's bogus and would make a huge APM table.
+            Ok(Some(ReplayCommand::Advance { ticks })) if ticks > MAX_ADVANCE_TICKS => break false,
+            Ok(Some(command)) => builder.process(command),
+            Ok(None) => (),
+            Err(ReplayReadError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break true,
+            Err(_) => break false,
+        }
+    };
+    builder.finish(player_names(header), parsed_whole_body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::get_file;
+
+    #[test]
+    fn test_example_replay_summary() {
+        let header = get_file("example_header");
+        let body = get_file("example_body");
+        let ticks = faf_replay_parser::scfa::parser::parse_body_ticks(&mut &body[..]).unwrap();
+        let summary = summarize_game(&header, &body[..]);
+        assert!(summary.parsed_whole_body);
+        assert_eq!(summary.ticks, ticks);
+        assert!(!summary.players.is_empty());
+        let names: Vec<_> = summary.players.iter().map(|p| p.name.clone().unwrap()).collect();
+        assert_eq!(names, vec!["MazorNoob", "dragonite"]);
+        for p in summary.players.iter() {
+            assert_eq!(p.apm.iter().map(|a| *a as u64).sum::<u64>(), p.actions);
+            assert_eq!(p.commands.values().sum::<u64>(), p.actions);
+            assert!(p.last_active_tick.unwrap_or(0) <= ticks);
+        }
+        assert!(summary.players.iter().any(|p| p.actions > 0));
+    }
+
+    #[test]
+    fn test_truncated_replay_summary() {
+        let header = get_file("example_header");
+        let body = get_file("example_body");
+        let summary = summarize_game(&header, &body[..body.len() / 2]);
+        assert!(summary.parsed_whole_body);
+        assert!(summary.ticks > 0);
+
+        // Garbage command ID.
+        let mut data = vec![0xFF, 0x03, 0x00];
+        data.extend_from_slice(&body);
+        let summary = summarize_game(b"garbage", &data[..]);
+        assert!(!summary.parsed_whole_body);
+        assert_eq!(summary.ticks, 0);
+        assert!(summary.players.iter().all(|p| p.name.is_none()));
+    }
+
+    #[test]
+    fn test_huge_advance_stops_summary() {
+        let body = get_file("example_body");
+        let mut data = body.clone();
+        data.extend_from_slice(&[0x00, 0x07, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        data.extend_from_slice(&body);
+        let summary = summarize_game(b"", &data[..]);
+        assert!(!summary.parsed_whole_body);
+        assert_eq!(
+            summary.ticks,
+            faf_replay_parser::scfa::parser::parse_body_ticks(&mut &body[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_chat_and_desyncs_are_found() {
+        let args = LuaObject::Table(
+            vec![
+                (LuaObject::from("Sender"), LuaObject::from("foo")),
+                (
+                    LuaObject::from("Msg"),
+                    LuaObject::Table(
+                        vec![
+                            (LuaObject::from("to"), LuaObject::from("all")),
+                            (LuaObject::from("text"), LuaObject::from("gl hf")),
+                        ]
+                        .into_iter()
+                        .collect(),
+                    ),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let mut builder = SummaryBuilder::default();
+        for _ in 0..7 {
+            builder.process(ReplayCommand::Advance { ticks: 100 });
+        }
+        builder.process(ReplayCommand::SetCommandSource { id: 1 });
+        builder.process(ReplayCommand::LuaSimCallback {
+            func: "GiveResourcesToPlayer".into(),
+            args,
+            selection: Vec::new(),
+        });
+        builder.process(ReplayCommand::VerifyChecksum {
+            digest: vec![0; 16],
+            tick: 650,
+        });
+        builder.process(ReplayCommand::SetCommandSource { id: 0 });
+        builder.process(ReplayCommand::VerifyChecksum {
+            digest: vec![1; 16],
+            tick: 650,
+        });
+        builder.process(ReplayCommand::CommandSourceTerminated);
+        let summary = builder.finish(HashMap::new(), true);
+
+        assert_eq!(
+            summary.chat,
+            vec![ChatMessage {
+                tick: 700,
+                sender: "foo".into(),
+                to: "all".into(),
+                text: "gl hf".into(),
+            }]
+        );
+        assert_eq!((summary.desyncs, summary.desync_ticks), (1, vec![650]));
+        assert_eq!(summary.players[0].left_at_tick, Some(700));
+        assert_eq!(summary.players[0].actions, 0);
+        assert_eq!(summary.players[1].apm, vec![0, 1]);
+        assert_eq!(summary.players[1].last_active_tick, Some(700));
+    }
+}
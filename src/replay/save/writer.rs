@@ -1,21 +1,172 @@
 use crate::replay::streams::write_replay_stream;
 use crate::replay::streams::MReplayRef;
+use crate::replay::streams::SPILLED_READ_SIZE;
+use crate::util::buf_traits::ReadAt;
 use async_compression::tokio::write::ZstdEncoder;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use sha2::{Digest, Sha256};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-pub async fn write_replay_file(
-    mut to: impl AsyncWrite + Unpin,
+// Size and SHA-256 of an uncompressed replay body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BodyDigest {
+    pub size: usize,
+    pub sha256: String,
+}
+
+// Hashes everything written through it, so we can checksum saved files without reading them back.
+pub struct HashingWriter<W: AsyncWrite + Unpin> {
+    inner: W,
+    hasher: Sha256,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    pub fn hex_digest(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+
+    pub fn digest(self) -> BodyDigest {
+        BodyDigest {
+            size: self.written,
+            sha256: self.hex_digest(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let me = &mut *self;
+        let res = Pin::new(&mut me.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            me.hasher.update(&buf[..n]);
+            me.written += n;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub async fn write_json_header(
+    to: &mut (impl AsyncWrite + Unpin),
     json_header: impl serde::Serialize,
+) -> std::io::Result<()> {
+    to.write_all(serde_json::to_string(&json_header)?.as_bytes()).await?;
+    to.write_all("\n".as_bytes()).await
+}
+
+pub async fn write_compressed_replay(
+    to: impl AsyncWrite + Unpin,
     replay: MReplayRef,
     compression_level: u32,
+) -> std::io::Result<BodyDigest> {
+    let clevel = async_compression::Level::Precise(compression_level);
+    let mut encoder = ZstdEncoder::with_quality(to, clevel);
+    // Only the body is hashed.
+    let header = replay.borrow().get_header().map(|h| h.data.clone()).unwrap_or_default();
+    encoder.write_all(&header).await?;
+    let mut hashed = HashingWriter::new(encoder);
+    write_replay_stream(&replay, &mut hashed, header.len(), None).await?;
+    hashed.shutdown().await?;
+    Ok(hashed.digest())
+}
+
+// The JSON header holds the body digest, so replays are compressed before their file is written,
+// e.g. by IncrementalReplayWriter. Returns the SHA-256 of everything after the JSON header.
+pub async fn write_replay_file_from_compressed(
+    mut to: impl AsyncWrite + Unpin,
+    json_header: impl serde::Serialize,
+    mut from: impl AsyncRead + Unpin,
+) -> std::io::Result<String> {
+    write_json_header(&mut to, json_header).await?;
+    let mut hashed = HashingWriter::new(to);
+    tokio::io::copy(&mut from, &mut hashed).await?;
+    hashed.shutdown().await?;
+    Ok(hashed.hex_digest())
+}
+
+// Compresses a replay as it grows, so the work doesn't all happen once the game ends. Data is
+// flushed after every write, so whatever was written can be decompressed even if we never finish.
+pub struct IncrementalReplayWriter<W: AsyncWrite + Unpin> {
+    encoder: HashingWriter<ZstdEncoder<W>>, // Hashes the replay body.
+    written: usize,                         // Of replay body.
+}
+
+impl<W: AsyncWrite + Unpin> IncrementalReplayWriter<W> {
+    pub async fn new(to: W, header: &[u8], compression_level: u32) -> std::io::Result<Self> {
+        let clevel = async_compression::Level::Precise(compression_level);
+        let mut encoder = ZstdEncoder::with_quality(to, clevel);
+        encoder.write_all(header).await?;
+        encoder.flush().await?;
+        Ok(Self {
+            encoder: HashingWriter::new(encoder),
+            written: 0,
+        })
+    }
+
+    pub async fn write_new_data(&mut self, replay: &MReplayRef) -> std::io::Result<()> {
+        let len = replay.borrow().get_data().len();
+        if len == self.written {
+            return Ok(());
+        }
+        let mut buf: Box<[u8]> = Box::new([0; 4096]);
+        while self.written < len {
+            let spilled = replay
+                .borrow()
+                .get_data()
+                .read_spilled_at(self.written, SPILLED_READ_SIZE);
+            let data_read = match spilled {
+                Some(read) => {
+                    let data = read.await?;
+                    self.encoder.write_all(&data).await?;
+                    data.len()
+                }
+                None => {
+                    let max = std::cmp::min(buf.len(), len - self.written);
+                    let data_read = replay.borrow().get_data().read_at(self.written, &mut buf[..max])?;
+                    self.encoder.write_all(&buf[..data_read]).await?;
+                    data_read
+                }
+            };
+            self.written += data_read;
+        }
+        self.encoder.flush().await?;
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> std::io::Result<BodyDigest> {
+        self.encoder.shutdown().await?;
+        Ok(self.encoder.digest())
+    }
+}
+
+pub async fn write_compressed_data(
+    to: impl AsyncWrite + Unpin,
+    data: &[u8],
+    compression_level: u32,
 ) -> std::io::Result<()> {
-    to.write_all(serde_json::to_string(&json_header)?.as_bytes()).await?;
-    to.write_all("\n".as_bytes()).await?;
     let clevel = async_compression::Level::Precise(compression_level);
     let mut encoder = ZstdEncoder::with_quality(to, clevel);
-    write_replay_stream(&replay, &mut encoder).await?;
-    encoder.shutdown().await?;
-    Ok(())
+    encoder.write_all(data).await?;
+    encoder.shutdown().await
 }
 
 #[cfg(test)]
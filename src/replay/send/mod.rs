@@ -1,2 +1,2 @@
 mod sender;
-pub use sender::ReplaySender;
+pub use sender::{ReplaySender, SavedReplaySender};
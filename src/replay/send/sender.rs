@@ -1,8 +1,14 @@
-use tokio::io::AsyncWriteExt;
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{sink, AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    replay::streams::write_replay_stream, replay::streams::MReplayRef, server::connection::Connection,
+    error::{ConnResult, ConnectionError},
+    replay::save::ReplaySaver,
+    replay::streams::write_replay_stream,
+    replay::streams::MReplayRef,
+    server::connection::Connection,
     util::timeout::cancellable,
 };
 
@@ -30,7 +36,62 @@ impl ReplaySender {
     }
 
     async fn do_send_replay_to_connection(&self, c: &mut Connection) -> std::io::Result<()> {
-        write_replay_stream(&self.merged_replay, c).await?;
+        let header = c.get_header();
+        if let Some(tier) = header.tier.as_deref() {
+            if !self.merged_replay.borrow().has_delay_tier(tier) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("No delay tier named {}", tier),
+                ));
+            }
+        }
+        write_replay_stream(&self.merged_replay, c, header.offset, header.tier.as_deref()).await?;
+        c.shutdown().await
+    }
+}
+
+// Sends replays that already ended and were saved, for readers that came too late.
+pub struct SavedReplaySender {
+    saver: ReplaySaver,
+    shutdown_token: CancellationToken,
+}
+
+impl SavedReplaySender {
+    pub fn new(saver: ReplaySaver, shutdown_token: CancellationToken) -> Self {
+        Self { saver, shutdown_token }
+    }
+
+    pub async fn handle_connection(&self, c: &mut Connection) -> ConnResult<()> {
+        let id = c.get_header().id;
+        let replay = match self.saver.open_saved_replay(id).await {
+            Err(e) => {
+                log::info!("{} asked for replay {}, which is not running or saved: {}", c, id, e);
+                return Err(ConnectionError::CannotAssignToReplay);
+            }
+            Ok(r) => r,
+        };
+        self.send_saved_replay(replay, c).await;
+        Ok(())
+    }
+
+    pub async fn send_saved_replay(&self, replay: impl AsyncRead + Unpin, c: &mut Connection) {
+        log::debug!("Sending saved replay {} to {}", c.get_header().id, c);
+        cancellable(Self::send_saved_replay_to_connection(replay, c), &self.shutdown_token).await;
+    }
+
+    async fn send_saved_replay_to_connection(replay: impl AsyncRead + Unpin, c: &mut Connection) {
+        if let Err(e) = Self::do_send_saved_replay_to_connection(replay, c).await {
+            log::info!("Saved replay send error: {}", e);
+        };
+    }
+
+    async fn do_send_saved_replay_to_connection(
+        mut replay: impl AsyncRead + Unpin,
+        c: &mut Connection,
+    ) -> std::io::Result<()> {
+        let offset = c.get_header().offset as u64;
+        tokio::io::copy(&mut (&mut replay).take(offset), &mut sink()).await?;
+        tokio::io::copy(&mut replay, c).await?;
         c.shutdown().await
     }
 }
@@ -2,10 +2,14 @@ use tokio::io::{AsyncBufRead, AsyncReadExt};
 
 use crate::{error::ConnResult, server::connection::read_until_exact};
 
+use super::lua::{read_lua_value, LuaValue};
+
 const MAX_SIZE: u64 = 1024 * 1024;
 
+#[derive(Clone)]
 pub struct ReplayHeader {
-    pub data: Vec<u8>,
+    pub data: Vec<u8>,                      // Raw header, forwarded as-is.
+    pub parsed: Option<ParsedReplayHeader>, // None if we failed to parse the Lua parts.
 }
 
 // Only show the very start / end
@@ -16,11 +20,143 @@ impl std::fmt::Debug for ReplayHeader {
         f.debug_struct("ReplayHeader")
             .field("data (start)", &format_args!("{:?}", &start))
             .field("data (end)", &format_args!("{:?}", &end))
+            .field("parsed", &self.parsed.is_some())
             .finish()
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerInfo {
+    pub name: String,
+    pub timeout_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArmyInfo {
+    pub info: LuaValue,
+    pub player_id: u8, // 255 for armies without a player, e.g. civilians.
+}
+
+// Header contents, with Lua objects parsed. Lua table key order differs between clients, but
+// parsed tables compare equal regardless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedReplayHeader {
+    pub game_version: String,
+    pub replay_version: String,
+    pub map_path: String,
+    pub mods: LuaValue,
+    pub scenario: LuaValue,
+    pub players: Vec<PlayerInfo>,
+    pub cheats_enabled: bool,
+    pub armies: Vec<ArmyInfo>,
+    pub random_seed: u32,
+}
+
+struct HeaderCursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> HeaderCursor<'a> {
+    fn take(&mut self, count: usize) -> std::io::Result<&'a [u8]> {
+        if count > self.data.len() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let (taken, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        let len = self
+            .data
+            .iter()
+            .position(|c| *c == 0)
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        let s = self.take(len + 1)?;
+        Ok(String::from_utf8_lossy(&s[..len]).into_owned())
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        let mut buf: [u8; 4] = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn lua(&mut self) -> std::io::Result<LuaValue> {
+        let size = self.u32()?;
+        let mut blob = self.take(size as usize)?;
+        read_lua_value(&mut blob)
+    }
+}
+
+impl ParsedReplayHeader {
+    // Same layout as in ReplayHeader::do_from_connection.
+    pub fn parse(data: &[u8]) -> std::io::Result<Self> {
+        let mut c = HeaderCursor { data };
+
+        let game_version = c.string()?;
+        c.take(3)?;
+        let replay_version_and_map = c.string()?;
+        let (replay_version, map_path) = replay_version_and_map
+            .split_once("\r\n")
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Replay header has no map path"))?;
+        c.take(4)?;
+
+        let mods = c.lua()?;
+        let scenario = c.lua()?;
+
+        let player_count = c.u8()?;
+        let mut players = Vec::new();
+        for _ in 0..player_count {
+            let name = c.string()?;
+            let timeout_count = c.u32()?;
+            players.push(PlayerInfo { name, timeout_count });
+        }
+
+        let cheats_enabled = c.u8()? != 0;
+
+        let army_count = c.u8()?;
+        let mut armies = Vec::new();
+        for _ in 0..army_count {
+            let info = c.lua()?;
+            let player_id = c.u8()?;
+            if player_id != 255 {
+                c.take(1)?;
+            }
+            armies.push(ArmyInfo { info, player_id });
+        }
+
+        let random_seed = c.u32()?;
+        Ok(Self {
+            game_version,
+            replay_version: replay_version.into(),
+            map_path: map_path.into(),
+            mods,
+            scenario,
+            players,
+            cheats_enabled,
+            armies,
+            random_seed,
+        })
+    }
+}
+
 impl ReplayHeader {
+    pub fn new(data: Vec<u8>) -> Self {
+        let parsed = match ParsedReplayHeader::parse(&data) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::debug!("Failed to parse replay header: {}", e);
+                None
+            }
+        };
+        Self { data, parsed }
+    }
+
     pub async fn from_connection<T: AsyncBufRead + Unpin>(c: &mut T) -> ConnResult<Self> {
         let limited = c.take(MAX_SIZE);
         Self::do_from_connection(limited).await.map_err(|x| x.into())
@@ -79,7 +215,7 @@ impl ReplayHeader {
         }
 
         let _random_seed = read_value!(read_u32_le);
-        Ok(ReplayHeader { data })
+        Ok(ReplayHeader::new(data))
     }
 }
 
@@ -130,6 +266,23 @@ mod test {
         .await;
     }
 
+    #[test]
+    fn example_header_parsed() {
+        let example_header = ReplayHeader::new(get_file("example_header"));
+        let parsed = example_header.parsed.unwrap();
+        assert_eq!(parsed.game_version, "Supreme Commander v1.50.3696");
+        assert_eq!(parsed.replay_version, "Replay v1.9");
+        assert_eq!(parsed.map_path, "/maps/SCMP_016/SCMP_016.scmap");
+        assert_eq!(parsed.armies.len(), 2);
+        assert_eq!(parsed.players.len(), 2);
+        assert!(!parsed.cheats_enabled);
+        assert_eq!(parsed, ReplayHeader::new(get_file("example_header")).parsed.unwrap());
+
+        let mut short_header = get_file("example_header");
+        short_header.pop();
+        assert!(ReplayHeader::new(short_header).parsed.is_none());
+    }
+
     #[tokio::test]
     async fn short_header() {
         let example_header = get_file("example_header");
@@ -0,0 +1,158 @@
+use std::{cmp::Ordering, io::BufRead, io::ErrorKind};
+
+// Parts of the replay header are serialized Lua objects. faf-replay-parser can read them, but it
+// panics on some malformed data (e.g. tables used as table keys) and we exit on panic, so we don't
+// let it near data from connections. This is a small reader that fails gracefully instead.
+
+const FLOAT_MARKER: u8 = 0;
+const STRING_MARKER: u8 = 1;
+const NIL_MARKER: u8 = 2;
+const BOOL_MARKER: u8 = 3;
+const TABLE_MARKER: u8 = 4;
+const TABLE_END_MARKER: u8 = 5;
+
+// Deeper tables are not something FA sends. Limited so we don't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum LuaValue {
+    Float(f32),
+    String(String),
+    Nil,
+    Bool(bool),
+    Table(Vec<(LuaValue, LuaValue)>), // Sorted by key, see below.
+}
+
+// Key order in serialized tables is not deterministic. We keep table entries sorted by key, so
+// tables with the same contents are equal.
+impl LuaValue {
+    fn type_order(&self) -> u8 {
+        match self {
+            Self::Float(..) => FLOAT_MARKER,
+            Self::String(..) => STRING_MARKER,
+            Self::Nil => NIL_MARKER,
+            Self::Bool(..) => BOOL_MARKER,
+            Self::Table(..) => TABLE_MARKER,
+        }
+    }
+
+    fn canonical_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Float(a), Self::Float(b)) => a.total_cmp(b),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (Self::Nil, Self::Nil) => Ordering::Equal,
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Table(a), Self::Table(b)) => {
+                for ((k1, v1), (k2, v2)) in a.iter().zip(b.iter()) {
+                    let ord = k1.canonical_cmp(k2).then_with(|| v1.canonical_cmp(v2));
+                    if ord != Ordering::Equal {
+                        return ord;
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            _ => self.type_order().cmp(&other.type_order()),
+        }
+    }
+}
+
+impl PartialEq for LuaValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.canonical_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for LuaValue {}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_u8(r: &mut impl BufRead) -> std::io::Result<u8> {
+    let mut buf: [u8; 1] = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_value(r: &mut impl BufRead, depth: usize) -> std::io::Result<LuaValue> {
+    let marker = read_u8(r)?;
+    read_value_as(r, marker, depth)
+}
+
+fn read_value_as(r: &mut impl BufRead, marker: u8, depth: usize) -> std::io::Result<LuaValue> {
+    match marker {
+        FLOAT_MARKER => {
+            let mut buf: [u8; 4] = [0; 4];
+            r.read_exact(&mut buf)?;
+            Ok(LuaValue::Float(f32::from_le_bytes(buf)))
+        }
+        STRING_MARKER => {
+            let mut buf = Vec::new();
+            r.read_until(0, &mut buf)?;
+            if buf.pop() != Some(0) {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            Ok(LuaValue::String(String::from_utf8_lossy(&buf).into_owned()))
+        }
+        NIL_MARKER => {
+            read_u8(r)?;
+            Ok(LuaValue::Nil)
+        }
+        BOOL_MARKER => Ok(LuaValue::Bool(read_u8(r)? != 0)),
+        TABLE_MARKER => {
+            if depth >= MAX_DEPTH {
+                return Err(invalid_data("Lua tables nested too deep"));
+            }
+            let mut entries = Vec::new();
+            loop {
+                let key_marker = read_u8(r)?;
+                if key_marker == TABLE_END_MARKER {
+                    break;
+                }
+                let key = read_value_as(r, key_marker, depth + 1)?;
+                let value = read_value(r, depth + 1)?;
+                entries.push((key, value));
+            }
+            entries.sort_by(|a, b| a.0.canonical_cmp(&b.0));
+            Ok(LuaValue::Table(entries))
+        }
+        _ => Err(invalid_data("Unknown Lua object type")),
+    }
+}
+
+pub fn read_lua_value(r: &mut impl BufRead) -> std::io::Result<LuaValue> {
+    read_value(r, 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lua_tables_compare_regardless_of_key_order() {
+        let t1: &[u8] = b"\x04\x01foo\x00\x01bar\x00\x00\x00\x00\x80\x3f\x03\x01\x05";
+        let t2: &[u8] = b"\x04\x00\x00\x00\x80\x3f\x03\x01\x01foo\x00\x01bar\x00\x05";
+        let t3: &[u8] = b"\x04\x00\x00\x00\x80\x3f\x03\x00\x01foo\x00\x01bar\x00\x05";
+        let v1 = read_lua_value(&mut &t1[..]).unwrap();
+        let v2 = read_lua_value(&mut &t2[..]).unwrap();
+        let v3 = read_lua_value(&mut &t3[..]).unwrap();
+        assert_eq!(v1, v2);
+        assert_ne!(v1, v3);
+    }
+
+    #[test]
+    fn test_lua_table_keys_can_be_tables() {
+        let data: &[u8] = b"\x04\x04\x05\x02\x00\x05";
+        let v = read_lua_value(&mut &data[..]).unwrap();
+        assert_eq!(v, LuaValue::Table(vec![(LuaValue::Table(vec![]), LuaValue::Nil)]));
+    }
+
+    #[test]
+    fn test_lua_invalid_data() {
+        let mut deep = vec![TABLE_MARKER; MAX_DEPTH + 1];
+        deep.extend(vec![TABLE_END_MARKER; MAX_DEPTH + 1]);
+        for data in [b"\x04\x01foo\x00" as &[u8], b"\x01foo", b"\x07", b"", &deep] {
+            read_lua_value(&mut &data[..]).expect_err("Lua value should be invalid");
+        }
+    }
+}
@@ -1,36 +1,108 @@
-use std::{cell::RefCell, io::Read, io::Write, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, io::Read, io::Write, rc::Rc};
 
 use futures::Future;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{
-    util::buf_traits::DiscontiguousBuf,
+    metrics,
     util::{
-        buf_deque::BufDeque,
-        buf_traits::{DiscontiguousBufExt, ReadAt, ReadAtExt},
+        buf_traits::{DiscontiguousBuf, DiscontiguousBufExt, ReadAt},
         event::Event,
     },
 };
 
-use super::{writer_replay::WriterReplay, ReplayHeader};
+use super::{
+    seek_index::SeekIndex,
+    spill::{MemoryBudget, SpillableData, TrackedMemory, SPILLED_READ_SIZE},
+    writer_replay::WriterReplay,
+    ReplayHeader,
+};
+
+// Readers in a delay tier can see more data than the public, but never less.
+struct DelayTier {
+    delayed_data_len: usize,
+    delayed_data_notification: Event,
+}
 
 pub struct MergedReplay {
-    data: BufDeque,
+    data: SpillableData,
     header: Option<ReplayHeader>,
     delayed_data_len: usize,
     finished: bool,
     delayed_data_notification: Event,
+    delay_tiers: HashMap<String, DelayTier>,
+    memory_budget: Option<MemoryBudget>,
+    tracked_memory: TrackedMemory,
+    seek_index: Option<SeekIndex>,
 }
 
 impl MergedReplay {
     pub fn new() -> Self {
         Self {
-            data: BufDeque::new(),
+            data: SpillableData::new(),
             header: None,
             delayed_data_len: 0,
             finished: false,
             delayed_data_notification: Event::new(),
+            delay_tiers: HashMap::new(),
+            memory_budget: None,
+            tracked_memory: TrackedMemory::default(),
+            seek_index: None,
+        }
+    }
+
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.memory_budget = Some(budget);
+    }
+
+    pub fn set_seek_index(&mut self, index: SeekIndex) {
+        debug_assert!(self.data.len() == 0);
+        self.seek_index = Some(index);
+    }
+
+    pub fn seek_index(&self) -> Option<&SeekIndex> {
+        self.seek_index.as_ref()
+    }
+
+    pub fn add_delay_tier(&mut self, name: String) {
+        debug_assert!(self.data.len() == 0);
+        let tier = DelayTier {
+            delayed_data_len: 0,
+            delayed_data_notification: Event::new(),
+        };
+        self.delay_tiers.insert(name, tier);
+    }
+
+    pub fn has_delay_tier(&self, name: &str) -> bool {
+        self.delay_tiers.contains_key(name)
+    }
+
+    fn stay_within_memory_budget(&mut self) {
+        let budget = match self.memory_budget.take() {
+            Some(b) => b,
+            None => return,
+        };
+        match self.spill(&budget) {
+            Ok(()) => self.memory_budget = Some(budget),
+            // Stop trying and keep the data in memory.
+            Err(e) => log::warn!("Failed to spill replay data to disk: {}", e),
         }
+        self.tracked_memory.set(self.data.memory_used());
+    }
+
+    fn spill(&mut self, budget: &MemoryBudget) -> std::io::Result<()> {
+        let spilled = self.data.poll_spill()?;
+        metrics::SPILLED_DATA.inc_by(spilled as u64);
+        self.tracked_memory.set(self.data.memory_used());
+        let excess = budget.excess(self.data.memory_used());
+        if excess == 0 || self.data.is_spilling() {
+            return Ok(());
+        }
+        if !self.data.has_spill_file() {
+            self.data.set_spill_file(budget.create_spill_file()?);
+        }
+        self.data.start_spill(excess, budget.unspilled_tail());
+        Ok(())
     }
 
     pub fn header_len(&self) -> usize {
@@ -45,16 +117,29 @@ impl MergedReplay {
         self.delayed_data_len + self.header_len()
     }
 
+    // Unknown tiers get the public delay.
+    fn tier(&self, tier: Option<&str>) -> Option<&DelayTier> {
+        tier.and_then(|t| self.delay_tiers.get(t))
+    }
+
+    pub fn tier_delayed_len(&self, tier: Option<&str>) -> usize {
+        let delayed_data_len = self.tier(tier).map_or(self.delayed_data_len, |t| t.delayed_data_len);
+        delayed_data_len + self.header_len()
+    }
+
     pub fn is_finished(&self) -> bool {
         self.finished
     }
 
     pub fn wait_for_more_data(&self) -> impl Future<Output = ()> {
-        let wait = if self.finished {
-            None
-        } else {
-            Some(self.delayed_data_notification.wait())
-        };
+        self.wait_for_more_tier_data(None)
+    }
+
+    pub fn wait_for_more_tier_data(&self, tier: Option<&str>) -> impl Future<Output = ()> {
+        let notification = self
+            .tier(tier)
+            .map_or(&self.delayed_data_notification, |t| &t.delayed_data_notification);
+        let wait = if self.finished { None } else { Some(notification.wait()) };
         async move {
             if let Some(w) = wait {
                 w.await;
@@ -66,7 +151,7 @@ impl MergedReplay {
         debug_assert!(!self.finished);
         debug_assert!(self.get_data().len() == 0);
         self.header = Some(header);
-        self.delayed_data_notification.notify();
+        self.notify_all();
     }
 
     pub fn get_header(&self) -> Option<&ReplayHeader> {
@@ -81,10 +166,14 @@ impl MergedReplay {
         let from = self.data.len();
         for chunk in writer_data.iter_chunks(from, until) {
             self.data.write_all(chunk).unwrap();
+            if let Some(index) = self.seek_index.as_mut() {
+                index.add_data(chunk);
+            }
         }
+        self.stay_within_memory_budget();
     }
 
-    pub fn get_data(&self) -> &impl DiscontiguousBuf {
+    pub fn get_data(&self) -> &SpillableData {
         &self.data
     }
 
@@ -93,17 +182,40 @@ impl MergedReplay {
         debug_assert!(!self.finished);
         self.delayed_data_len = len;
         self.delayed_data_notification.notify();
+        for tier in self.delay_tiers.values_mut() {
+            if tier.delayed_data_len < len {
+                tier.delayed_data_len = len;
+                tier.delayed_data_notification.notify();
+            }
+        }
+    }
+
+    pub fn advance_tier_delayed_data(&mut self, name: &str, len: usize) {
+        debug_assert!(!self.finished);
+        let len = std::cmp::min(std::cmp::max(len, self.delayed_data_len), self.data.len());
+        if let Some(tier) = self.delay_tiers.get_mut(name) {
+            if tier.delayed_data_len < len {
+                tier.delayed_data_len = len;
+                tier.delayed_data_notification.notify();
+            }
+        }
+    }
+
+    fn notify_all(&mut self) {
+        self.delayed_data_notification.notify();
+        for tier in self.delay_tiers.values_mut() {
+            tier.delayed_data_notification.notify();
+        }
     }
 
     pub fn finish(&mut self) {
         self.finished = true;
-        self.delayed_data_notification.notify();
+        self.notify_all();
     }
-}
 
-impl ReadAt for MergedReplay {
-    fn read_at(&self, mut start: usize, buf: &mut [u8]) -> std::io::Result<usize> {
-        if start >= self.delayed_len() {
+    pub fn read_tier_at(&self, tier: Option<&str>, mut start: usize, buf: &mut [u8]) -> std::io::Result<usize> {
+        let delayed_len = self.tier_delayed_len(tier);
+        if start >= delayed_len {
             return Ok(0);
         }
         debug_assert!(self.header.is_some());
@@ -111,32 +223,217 @@ impl ReadAt for MergedReplay {
             let mut data = &self.get_header().unwrap().data[start..];
             data.read(buf)
         } else {
+            let read_max = std::cmp::min(buf.len(), delayed_len - start);
             start -= self.header_len();
-            let read_max = std::cmp::min(buf.len(), self.delayed_data_len - start);
             self.data.read_at(start, &mut buf[..read_max])
         }
     }
+
+    // None if data at start is not spilled.
+    pub fn read_spilled_tier_at(
+        &self,
+        tier: Option<&str>,
+        start: usize,
+        len: usize,
+    ) -> Option<impl Future<Output = std::io::Result<Vec<u8>>> + 'static> {
+        let delayed_len = self.tier_delayed_len(tier);
+        if start < self.header_len() || start >= delayed_len {
+            return None;
+        }
+        let len = std::cmp::min(len, delayed_len - start);
+        self.data.read_spilled_at(start - self.header_len(), len)
+    }
+}
+
+impl ReadAt for MergedReplay {
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_tier_at(None, start, buf)
+    }
 }
 
 pub type MReplayRef = Rc<RefCell<MergedReplay>>;
 
-pub async fn write_replay_stream(replay: &MReplayRef, c: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+pub async fn write_replay_stream(
+    replay: &MReplayRef,
+    c: &mut (impl AsyncWrite + Unpin),
+    from: usize,
+    tier: Option<&str>,
+) -> std::io::Result<()> {
     let mut buf: Box<[u8]> = Box::new([0; 4096]);
-    let mut reader = replay.reader();
+    let mut position = from;
     loop {
         let r = replay.borrow();
-        if r.delayed_len() <= reader.position() && r.is_finished() {
+        if r.tier_delayed_len(tier) <= position && r.is_finished() {
             return Ok(());
         }
+        // Spilled data is read on a blocking thread, so we don't stall everyone else.
+        let spilled = r.read_spilled_tier_at(tier, position, SPILLED_READ_SIZE);
         drop(r);
 
-        let data_read = reader.read(&mut *buf).unwrap();
-        c.write_all(&buf[..data_read]).await?;
+        let data_read = match spilled {
+            Some(read) => {
+                let data = read.await?;
+                c.write_all(&data).await?;
+                data.len()
+            }
+            None => {
+                let data_read = replay.borrow().read_tier_at(tier, position, &mut buf)?;
+                c.write_all(&buf[..data_read]).await?;
+                data_read
+            }
+        };
+        position += data_read;
         if data_read == 0 {
-            let f = replay.borrow().wait_for_more_data();
+            let f = replay.borrow().wait_for_more_tier_data(tier);
             f.await;
         }
     }
 }
 
-// TODO tests
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::util::{buf_deque::CHUNK_SIZE, buf_traits::ReadAtExt};
+
+    pub async fn replay_with_budget(budget: MemoryBudget, header: &[u8], data: &[u8]) -> MergedReplay {
+        let mut writer = WriterReplay::new();
+        writer.add_data(data);
+        let mut replay = MergedReplay::new();
+        replay.set_memory_budget(budget);
+        replay.add_header(ReplayHeader::new(header.to_vec()));
+        // Add it in pieces, like the merge strategy would.
+        for until in (0..data.len()).step_by(1000).chain(std::iter::once(data.len())) {
+            replay.add_data(&writer, until);
+            // Let spills finish.
+            while replay.data.is_spilling() {
+                replay.data.wait_for_spill().await.unwrap();
+                replay.stay_within_memory_budget();
+            }
+        }
+        replay.advance_delayed_data(data.len());
+        replay
+    }
+
+    #[test]
+    fn test_delay_tiers_see_more_than_public() {
+        let mut writer = WriterReplay::new();
+        writer.add_data(b"0123456789");
+        let mut replay = MergedReplay::new();
+        replay.add_delay_tier("casters".into());
+        replay.add_header(ReplayHeader::new(b"header".to_vec()));
+        replay.add_data(&writer, 8);
+        replay.advance_delayed_data(2);
+        replay.advance_tier_delayed_data("casters", 5);
+        assert_eq!(replay.tier_delayed_len(None), 8);
+        assert_eq!(replay.tier_delayed_len(Some("casters")), 11);
+        assert_eq!(replay.tier_delayed_len(Some("unknown")), 8);
+
+        let mut read = Vec::new();
+        replay.reader().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"header01");
+        let mut buf = [0; 10];
+        assert_eq!(replay.read_tier_at(Some("casters"), 8, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"234");
+
+        // Tiers can't go past merged data, and catch up with the public delay.
+        replay.advance_tier_delayed_data("casters", 100);
+        assert_eq!(replay.tier_delayed_len(Some("casters")), 14);
+        replay.add_data(&writer, 10);
+        replay.advance_delayed_data(10);
+        assert_eq!(replay.tier_delayed_len(Some("casters")), 16);
+    }
+
+    #[tokio::test]
+    async fn test_write_replay_stream_of_tier() {
+        let mut writer = WriterReplay::new();
+        writer.add_data(b"0123456789");
+        let mut replay = MergedReplay::new();
+        replay.add_delay_tier("casters".into());
+        replay.add_header(ReplayHeader::new(b"header".to_vec()));
+        replay.add_data(&writer, 10);
+        replay.advance_tier_delayed_data("casters", 10);
+        replay.finish();
+        let replay = Rc::new(RefCell::new(replay));
+
+        let mut public = Vec::new();
+        write_replay_stream(&replay, &mut public, 0, None).await.unwrap();
+        assert_eq!(public, b"header");
+        let mut casters = Vec::new();
+        write_replay_stream(&replay, &mut casters, 3, Some("casters"))
+            .await
+            .unwrap();
+        assert_eq!(casters, b"der0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_replay_data_is_spilled_past_budget() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..CHUNK_SIZE * 10 + 10).map(|i| (i % 251) as u8).collect();
+
+        let per_replay = MemoryBudget::new(Some(CHUNK_SIZE * 2), None, tmp_dir.path().into());
+        let replay = replay_with_budget(per_replay, b"header", &data).await;
+        assert!(replay.data.memory_used() <= CHUNK_SIZE * 2);
+        let mut read = Vec::new();
+        replay.reader().read_to_end(&mut read).unwrap();
+        assert_eq!(read, [&b"header"[..], &data[..]].concat());
+
+        // Other tests can only add to total memory used, so this is deterministic.
+        let total = MemoryBudget::new(None, Some(1), tmp_dir.path().into());
+        let replay = replay_with_budget(total, b"header", &data).await;
+        assert_eq!(replay.data.memory_used(), CHUNK_SIZE);
+        let mut read = Vec::new();
+        replay.reader_from(6).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        // Spill files are removed right away.
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+        let mut replay = replay;
+        replay.finish();
+        let replay = Rc::new(RefCell::new(replay));
+        let mut streamed = Vec::new();
+        write_replay_stream(&replay, &mut streamed, 0, None).await.unwrap();
+        assert_eq!(streamed, [&b"header"[..], &data[..]].concat());
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_unspilled_tail_in_memory() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..CHUNK_SIZE * 10 + 10).map(|i| (i % 251) as u8).collect();
+
+        let budget = MemoryBudget::new(Some(1), None, tmp_dir.path().into()).with_unspilled_tail(CHUNK_SIZE * 2);
+        let replay = replay_with_budget(budget, b"header", &data).await;
+        assert!(replay.data.spilled_len() > 0);
+        assert!(replay.data.spilled_len() <= data.len() - CHUNK_SIZE * 2);
+        let mut read = Vec::new();
+        replay.reader_from(6).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn test_spilled_data_read_error_ends_stream() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("spill");
+        let write_only = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        let data = vec![1; CHUNK_SIZE * 4];
+
+        let mut replay = MergedReplay::new();
+        replay.set_memory_budget(MemoryBudget::new(Some(1), None, tmp_dir.path().into()));
+        replay.data.set_spill_file(write_only);
+        replay.add_header(ReplayHeader::new(b"header".to_vec()));
+        let mut writer = WriterReplay::new();
+        writer.add_data(&data);
+        replay.add_data(&writer, data.len());
+        replay.data.wait_for_spill().await.unwrap();
+        replay.advance_delayed_data(data.len());
+        replay.finish();
+        let replay = Rc::new(RefCell::new(replay));
+
+        let mut streamed = Vec::new();
+        assert!(write_replay_stream(&replay, &mut streamed, 0, None).await.is_err());
+        assert_eq!(streamed, b"header");
+    }
+}
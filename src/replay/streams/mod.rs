@@ -1,7 +1,16 @@
 mod header;
+mod lua;
 mod merged_replay;
+mod seek_index;
+mod spill;
+mod tick_parser;
 mod writer_replay;
 
 pub use self::header::ReplayHeader;
+#[cfg(test)]
+pub use self::merged_replay::test::replay_with_budget;
 pub use self::merged_replay::{write_replay_stream, MReplayRef, MergedReplay};
+pub use self::seek_index::SeekIndex;
+pub use self::spill::{MemoryBudget, SPILLED_READ_SIZE};
+pub use self::tick_parser::{TickParser, TickPoint, MAX_ADVANCE_TICKS};
 pub use self::writer_replay::{read_data, read_header, WReplayRef, WriterReplay};
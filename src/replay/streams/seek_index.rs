@@ -0,0 +1,94 @@
+use serde::Serialize;
+
+use super::tick_parser::{TickParser, TickPoint};
+
+// Maps game ticks to offsets in the replay body, so clients can seek without parsing the replay
+// from the start. It's built as merged data comes in, with a point every `interval` ticks, at
+// the first command after the tick.
+
+#[derive(Serialize)]
+struct SeekIndexJson<'a> {
+    interval: u32,
+    points: &'a [TickPoint],
+}
+
+pub struct SeekIndex {
+    interval: u32,
+    points: Vec<TickPoint>,
+    parser: TickParser,
+}
+
+impl SeekIndex {
+    pub fn new(interval: u32) -> Self {
+        debug_assert!(interval > 0);
+        Self {
+            interval,
+            points: vec![TickPoint {
+                tick: 0,
+                offset: 0,
+                source: 0,
+            }],
+            parser: TickParser::new(),
+        }
+    }
+
+    pub fn add_data(&mut self, data: &[u8]) {
+        let interval = self.interval;
+        let points = &mut self.points;
+        self.parser.add_data(data, |point| {
+            let last = points.last().unwrap();
+            if point.tick / interval > last.tick / interval {
+                points.push(point);
+            }
+        });
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&SeekIndexJson {
+            interval: self.interval,
+            points: &self.points,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::get_file;
+    use faf_replay_parser::scfa::parser::parse_body_ticks;
+
+    fn index_in_chunks(body: &[u8], chunk_size: usize) -> SeekIndex {
+        let mut index = SeekIndex::new(100);
+        for chunk in body.chunks(chunk_size) {
+            index.add_data(chunk);
+        }
+        index
+    }
+
+    #[test]
+    fn test_seek_index_points_at_ticks() {
+        let body = get_file("example_body");
+        let index = index_in_chunks(&body, body.len());
+        let ticks = parse_body_ticks(&mut &body[..]).unwrap();
+        assert_eq!(index.points.len() as u32, ticks / 100 + 1);
+        for point in index.points.iter() {
+            assert_eq!(parse_body_ticks(&mut &body[..point.offset]).unwrap(), point.tick);
+        }
+        for points in index.points.windows(2) {
+            assert_eq!(points[0].tick / 100 + 1, points[1].tick / 100);
+        }
+        assert!(index.points.iter().any(|p| p.source != 0));
+    }
+
+    #[test]
+    fn test_seek_index_stops_at_malformed_data() {
+        let body = get_file("example_body");
+        let cut = index_in_chunks(&body, body.len()).points[5].offset;
+        let mut index = SeekIndex::new(100);
+        index.add_data(&body[..cut]);
+        let points = index.points.clone();
+        index.add_data(&[0xFF, 0x03, 0x00]);
+        index.add_data(&body[cut..]);
+        assert_eq!(index.points, points);
+    }
+}
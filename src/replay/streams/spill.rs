@@ -0,0 +1,444 @@
+use std::{
+    cmp::{max, min},
+    fs::File,
+    io::{Read, Write},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    sync::Arc,
+};
+
+use futures::{
+    future::{ready, Either},
+    Future, FutureExt,
+};
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::{
+    config::Settings,
+    metrics,
+    util::buf_deque::{BufDeque, CHUNK_SIZE},
+    util::buf_traits::{DiscontiguousBuf, DiscontiguousBufExt, ReadAt, ReadAtExt},
+};
+
+// Limits on how much merged replay data we keep in memory. Past the limit, the oldest data of a
+// replay is spilled to a file. Spill files are deleted as soon as they're created, so they go away
+// with the replay, or with us if we crash.
+
+// Each read of spilled data goes to a blocking thread, so we read more at once.
+pub const SPILLED_READ_SIZE: usize = 64 * 1024;
+
+static MERGED_DATA_IN_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SPILL_FILE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct MemoryBudget {
+    per_replay: Option<usize>,
+    total: Option<usize>,
+    spill_dir: PathBuf,
+    unspilled_tail: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(per_replay: Option<usize>, total: Option<usize>, spill_dir: PathBuf) -> Self {
+        Self {
+            per_replay,
+            total,
+            spill_dir,
+            unspilled_tail: 0,
+        }
+    }
+
+    // Newest data that's never spilled. Writers are compared with it without blocking.
+    pub fn with_unspilled_tail(mut self, len: usize) -> Self {
+        self.unspilled_tail = len;
+        self
+    }
+
+    pub fn unspilled_tail(&self) -> usize {
+        self.unspilled_tail
+    }
+
+    // None if there's no limit.
+    pub fn from_config(config: &Settings) -> Option<Self> {
+        let limit = |b: usize| (b > 0).then_some(b);
+        let per_replay = limit(config.replay.memory_budget_per_replay_b);
+        let total = limit(config.replay.memory_budget_b);
+        if per_replay.is_none() && total.is_none() {
+            return None;
+        }
+        let spill_dir = PathBuf::from(&config.storage.vault_path).join(".spill");
+        let budget = Self::new(per_replay, total, spill_dir);
+        Some(budget.with_unspilled_tail(config.replay.stream_comparison_distance_b))
+    }
+
+    // How much memory we use over the budget.
+    pub fn excess(&self, replay_memory: usize) -> usize {
+        let over = |used: usize, limit: Option<usize>| limit.map_or(0, |m| used.saturating_sub(m));
+        max(
+            over(replay_memory, self.per_replay),
+            over(MERGED_DATA_IN_MEMORY.load(Ordering::Relaxed), self.total),
+        )
+    }
+
+    pub fn create_spill_file(&self) -> std::io::Result<File> {
+        std::fs::create_dir_all(&self.spill_dir)?;
+        let n = NEXT_SPILL_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = self.spill_dir.join(format!("{}.{}", std::process::id(), n));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+// Memory counted towards the total budget. Stops being counted when dropped.
+#[derive(Default)]
+pub struct TrackedMemory {
+    bytes: usize,
+}
+
+impl TrackedMemory {
+    pub fn set(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            MERGED_DATA_IN_MEMORY.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            MERGED_DATA_IN_MEMORY.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+        metrics::MERGED_DATA_IN_MEMORY.set(MERGED_DATA_IN_MEMORY.load(Ordering::Relaxed) as i64);
+    }
+}
+
+impl Drop for TrackedMemory {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/* Merged replay data, the oldest part of which can be spilled to a file. Data before 'spilled' is
+ * only in the file, at the same offset. Spill file I/O happens on a blocking thread, so data being
+ * spilled stays in memory until it's written out.
+ */
+pub struct SpillableData {
+    memory: BufDeque,
+    spilled: usize,
+    spill_file: Option<Arc<File>>,
+    spilling: Option<(usize, JoinHandle<std::io::Result<()>>)>, // Where data being spilled ends.
+}
+
+impl SpillableData {
+    pub fn new() -> Self {
+        Self {
+            memory: BufDeque::new(),
+            spilled: 0,
+            spill_file: None,
+            spilling: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory.memory_used()
+    }
+
+    pub fn set_spill_file(&mut self, file: File) {
+        debug_assert!(self.spill_file.is_none());
+        self.spill_file = Some(Arc::new(file));
+    }
+
+    pub fn has_spill_file(&self) -> bool {
+        self.spill_file.is_some()
+    }
+
+    pub fn is_spilling(&self) -> bool {
+        self.spilling.is_some()
+    }
+
+    pub fn spilled_len(&self) -> usize {
+        self.spilled
+    }
+
+    /* Start spilling at least len bytes of oldest data, if there's that much. Only whole chunks
+     * are spilled, so we never spill data that's still being written to, nor the newest keep
+     * bytes. Panics if there's no spill file or a spill is already in progress.
+     */
+    pub fn start_spill(&mut self, len: usize, keep: usize) {
+        assert!(!self.is_spilling());
+        let file = self.spill_file.clone().expect("No file to spill to");
+        let start = self.spilled;
+        let spillable_chunks = self.len().saturating_sub(keep).saturating_sub(start) / CHUNK_SIZE;
+        let end = start + min(len.div_ceil(CHUNK_SIZE), spillable_chunks) * CHUNK_SIZE;
+        if start == end {
+            return;
+        }
+        let mut data = Vec::with_capacity(end - start);
+        for chunk in self.memory.iter_chunks(start, end) {
+            data.extend_from_slice(chunk);
+        }
+        let write = tokio::task::spawn_blocking(move || file.write_all_at(&data, start as u64));
+        self.spilling = Some((end, write));
+    }
+
+    fn spill_finished(&mut self, end: usize, res: Result<std::io::Result<()>, JoinError>) -> std::io::Result<usize> {
+        res??;
+        self.memory.discard(end);
+        let spilled = end - self.spilled;
+        self.spilled = end;
+        Ok(spilled)
+    }
+
+    // If a spill finished, drop its data from memory. Returns how much was spilled.
+    pub fn poll_spill(&mut self) -> std::io::Result<usize> {
+        let res = match self.spilling.as_mut().and_then(|(_, write)| write.now_or_never()) {
+            Some(r) => r,
+            None => return Ok(0),
+        };
+        let (end, _) = self.spilling.take().unwrap();
+        self.spill_finished(end, res)
+    }
+
+    #[cfg(test)]
+    pub async fn wait_for_spill(&mut self) -> std::io::Result<usize> {
+        match self.spilling.take() {
+            Some((end, write)) => {
+                let res = write.await;
+                self.spill_finished(end, res)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Like DiscontiguousBufExt::common_prefix_from. Reading spilled data would block, so start
+    // has to be past it.
+    pub fn common_prefix_from(&self, other: &impl DiscontiguousBuf, start: usize) -> usize {
+        assert!(start >= self.spilled, "Comparing spilled data");
+        self.memory.common_prefix_from(other, start)
+    }
+
+    // Read spilled data without blocking. None if data at start is in memory.
+    pub fn read_spilled_at(
+        &self,
+        start: usize,
+        len: usize,
+    ) -> Option<impl Future<Output = std::io::Result<Vec<u8>>> + 'static> {
+        if start >= self.spilled {
+            return None;
+        }
+        let file = self.spill_file.clone().unwrap();
+        let len = min(len, self.spilled - start);
+        Some(async move {
+            let read = tokio::task::spawn_blocking(move || {
+                let mut data = vec![0; len];
+                file.read_exact_at(&mut data, start as u64)?;
+                Ok(data)
+            });
+            read.await?
+        })
+    }
+
+    // Data up to end that can be read on another thread. In-memory data is copied.
+    fn detach(&self, end: usize) -> DetachedData {
+        let end = min(end, self.len());
+        let spilled = min(self.spilled, end);
+        let mut memory = Vec::with_capacity(end - spilled);
+        for chunk in self.memory.iter_chunks(spilled, end) {
+            memory.extend_from_slice(chunk);
+        }
+        DetachedData {
+            spill_file: self.spill_file.clone(),
+            spilled,
+            memory,
+        }
+    }
+
+    // Run a pass over data up to end. If some of it is spilled, reading it blocks, so the pass
+    // runs on a blocking thread.
+    pub fn read_with<T: Send + 'static>(
+        &self,
+        end: usize,
+        pass: impl FnOnce(&mut dyn Read) -> T + Send + 'static,
+    ) -> impl Future<Output = std::io::Result<T>> + 'static {
+        if self.spilled == 0 {
+            let mut data = self.reader().take(end as u64);
+            return Either::Left(ready(Ok(pass(&mut data))));
+        }
+        let data = self.detach(end);
+        let read = tokio::task::spawn_blocking(move || pass(&mut data.reader()));
+        Either::Right(async move { Ok(read.await?) })
+    }
+}
+
+struct DetachedData {
+    spill_file: Option<Arc<File>>,
+    spilled: usize,
+    memory: Vec<u8>,
+}
+
+impl ReadAt for DetachedData {
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> std::io::Result<usize> {
+        if start >= self.spilled {
+            let mut data = self.memory.get(start - self.spilled..).unwrap_or_default();
+            return data.read(buf);
+        }
+        let file = self.spill_file.as_ref().unwrap();
+        let len = min(buf.len(), self.spilled - start);
+        file.read_exact_at(&mut buf[..len], start as u64)?;
+        Ok(len)
+    }
+}
+
+impl ReadAt for SpillableData {
+    // Blocks when reading spilled data. Anything that can wait should use read_spilled_at or
+    // read_with.
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> std::io::Result<usize> {
+        if start >= self.spilled {
+            return self.memory.read_at(start, buf);
+        }
+        let file = self.spill_file.as_ref().unwrap();
+        let len = min(buf.len(), self.spilled - start);
+        file.read_exact_at(&mut buf[..len], start as u64)?;
+        Ok(len)
+    }
+}
+
+impl Write for SpillableData {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.memory.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::test::default_config;
+    use crate::util::buf_traits::ReadAtExt;
+    use std::io::Read;
+
+    fn test_data() -> Vec<u8> {
+        (0..CHUNK_SIZE * 5 + 10).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_all(data: &SpillableData) -> std::io::Result<Vec<u8>> {
+        let mut read = Vec::new();
+        data.reader().read_to_end(&mut read)?;
+        Ok(read)
+    }
+
+    #[test]
+    fn test_no_budget_without_limits() {
+        assert!(MemoryBudget::from_config(&Arc::new(default_config())).is_none());
+        let mut config = default_config();
+        config.replay.memory_budget_b = 1000;
+        assert!(MemoryBudget::from_config(&Arc::new(config)).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_spilled_data_is_readable() {
+        let data = test_data();
+        let mut spillable = SpillableData::new();
+        spillable.write_all(&data).unwrap();
+        spillable.set_spill_file(tempfile::tempfile().unwrap());
+        spillable.start_spill(usize::MAX, 0);
+        // Data being spilled is still in memory.
+        assert_eq!(spillable.memory_used(), CHUNK_SIZE * 6);
+        assert_eq!(read_all(&spillable).unwrap(), data);
+
+        assert_eq!(spillable.wait_for_spill().await.unwrap(), CHUNK_SIZE * 5);
+        assert_eq!(spillable.memory_used(), CHUNK_SIZE);
+        assert_eq!(read_all(&spillable).unwrap(), data);
+        let read = spillable.read_spilled_at(CHUNK_SIZE + 5, usize::MAX).unwrap().await;
+        assert_eq!(read.unwrap(), &data[CHUNK_SIZE + 5..CHUNK_SIZE * 5]);
+        assert!(spillable.read_spilled_at(CHUNK_SIZE * 5, 10).is_none());
+
+        // Spill more after writing more.
+        spillable.write_all(&data).unwrap();
+        spillable.start_spill(1, 0);
+        assert_eq!(spillable.wait_for_spill().await.unwrap(), CHUNK_SIZE);
+        assert_eq!(read_all(&spillable).unwrap(), [&data[..], &data[..]].concat());
+
+        let mut other = BufDeque::new();
+        other.write_all(&data).unwrap();
+        other.write_all(&data[..CHUNK_SIZE * 3]).unwrap();
+        other.write_all(&[255]).unwrap();
+        let mismatch = data.len() + CHUNK_SIZE * 3;
+        for start in [CHUNK_SIZE * 6, CHUNK_SIZE * 6 + 3, mismatch] {
+            assert_eq!(spillable.common_prefix_from(&other, start), mismatch);
+        }
+        other.discard(CHUNK_SIZE * 6);
+        assert_eq!(spillable.common_prefix_from(&other, CHUNK_SIZE * 6), mismatch);
+    }
+
+    #[tokio::test]
+    async fn test_newest_data_is_not_spilled() {
+        let data = test_data();
+        let mut spillable = SpillableData::new();
+        spillable.write_all(&data).unwrap();
+        spillable.set_spill_file(tempfile::tempfile().unwrap());
+        spillable.start_spill(usize::MAX, CHUNK_SIZE + 20);
+        assert_eq!(spillable.wait_for_spill().await.unwrap(), CHUNK_SIZE * 3);
+        assert_eq!(spillable.spilled_len(), CHUNK_SIZE * 3);
+        assert_eq!(read_all(&spillable).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_passes_over_spilled_data() {
+        let data = test_data();
+        let mut spillable = SpillableData::new();
+        spillable.write_all(&data).unwrap();
+        let read_to_end = |d: &mut dyn Read| {
+            let mut read = Vec::new();
+            d.read_to_end(&mut read).map(|_| read)
+        };
+        let read = spillable.read_with(CHUNK_SIZE + 5, read_to_end).await.unwrap();
+        assert_eq!(read.unwrap(), &data[..CHUNK_SIZE + 5]);
+
+        spillable.set_spill_file(tempfile::tempfile().unwrap());
+        spillable.start_spill(CHUNK_SIZE * 2, 0);
+        spillable.wait_for_spill().await.unwrap();
+        assert_eq!(spillable.spilled_len(), CHUNK_SIZE * 2);
+        for end in [0, CHUNK_SIZE + 5, CHUNK_SIZE * 3 + 5, usize::MAX] {
+            let read = spillable.read_with(end, read_to_end).await.unwrap();
+            assert_eq!(read.unwrap(), &data[..min(end, data.len())]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spill_errors_are_returned() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("spill");
+        std::fs::write(&path, b"").unwrap();
+        let data = test_data();
+
+        // Spilling fails, data stays in memory.
+        let mut spillable = SpillableData::new();
+        spillable.write_all(&data).unwrap();
+        spillable.set_spill_file(File::open(&path).unwrap());
+        spillable.start_spill(usize::MAX, 0);
+        assert!(spillable.wait_for_spill().await.is_err());
+        assert_eq!(spillable.memory_used(), CHUNK_SIZE * 6);
+        assert_eq!(read_all(&spillable).unwrap(), data);
+
+        // Reading back fails.
+        let mut spillable = SpillableData::new();
+        spillable.write_all(&data).unwrap();
+        let write_only = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        spillable.set_spill_file(write_only);
+        spillable.start_spill(usize::MAX, 0);
+        spillable.wait_for_spill().await.unwrap();
+        assert!(read_all(&spillable).is_err());
+        let pass = spillable.read_with(usize::MAX, |d| d.read_to_end(&mut Vec::new()));
+        assert!(pass.await.unwrap().is_err());
+        assert!(spillable.read_spilled_at(0, 10).unwrap().await.is_err());
+    }
+}
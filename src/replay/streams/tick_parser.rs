@@ -0,0 +1,183 @@
+use std::convert::TryInto;
+
+use faf_replay_parser::scfa::{self, parser::parse_command, replay_command, ParserOptions, ReplayCommand};
+use serde::Serialize;
+
+// Follows game ticks in a replay body as it comes in, chunk by chunk. Each tick advance ends at
+// an offset where the next tick's commands start. Commands belong to the last command source set
+// before them, so we keep track of that too.
+
+// The game advances one tick at a time, so a huge advance means the data is malformed. This alone
+// doesn't stop a writer from skipping the delay with lots of advances, see TickHistory for that.
+pub const MAX_ADVANCE_TICKS: u32 = 100;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickPoint {
+    pub tick: u32,
+    pub offset: usize,
+    pub source: u8,
+}
+
+pub struct TickParser {
+    options: ParserOptions,
+    buf: Vec<u8>,
+    // Data after the last whole command.
+    unparsed: Vec<u8>,
+    unparsed_offset: usize,
+    tick: u32,
+    source: u8,
+    // If the body is malformed, we stop at the last good command.
+    malformed: bool,
+}
+
+impl TickParser {
+    pub fn new() -> Self {
+        Self {
+            options: ParserOptions {
+                commands: [replay_command::ADVANCE, replay_command::SET_COMMAND_SOURCE]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                limit: None,
+                save_commands: false,
+                stop_on_desync: false,
+            },
+            buf: Vec::new(),
+            unparsed: Vec::new(),
+            unparsed_offset: 0,
+            tick: 0,
+            source: 0,
+            malformed: false,
+        }
+    }
+
+    // Returns the size of the command at the start of data, if it's all there.
+    fn next_command(data: &[u8]) -> Result<Option<usize>, &'static str> {
+        match scfa::has_frame(data) {
+            Ok(true) => Ok(Some(u16::from_le_bytes(data[1..3].try_into().unwrap()) as usize)),
+            Ok(false) => Ok(None),
+            Err(_) => Err("invalid command header"),
+        }
+    }
+
+    fn process(&mut self, mut command: &[u8], end: usize) -> Result<Option<TickPoint>, &'static str> {
+        match parse_command(&mut command, &self.options, &mut self.buf) {
+            Ok(Some(ReplayCommand::Advance { ticks })) if ticks > MAX_ADVANCE_TICKS => {
+                return Err("implausibly long tick advance")
+            }
+            Ok(Some(ReplayCommand::Advance { ticks })) => {
+                self.tick = self.tick.saturating_add(ticks);
+                return Ok(Some(TickPoint {
+                    tick: self.tick,
+                    offset: end,
+                    source: self.source,
+                }));
+            }
+            Ok(Some(ReplayCommand::SetCommandSource { id })) => self.source = id,
+            Ok(_) => (),
+            Err(_) => return Err("invalid command"),
+        }
+        Ok(None)
+    }
+
+    // Calls on_advance for each tick advance in data.
+    pub fn add_data(&mut self, data: &[u8], mut on_advance: impl FnMut(TickPoint)) {
+        if self.malformed {
+            return;
+        }
+        let mut unparsed = std::mem::take(&mut self.unparsed);
+        unparsed.extend_from_slice(data);
+        let mut pos = 0;
+        let res = loop {
+            let size = match Self::next_command(&unparsed[pos..]) {
+                Ok(Some(s)) => s,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let end = self.unparsed_offset + pos + size;
+            match self.process(&unparsed[pos..pos + size], end) {
+                Ok(Some(point)) => on_advance(point),
+                Ok(None) => (),
+                Err(e) => break Err(e),
+            }
+            pos += size;
+        };
+        if let Err(e) = res {
+            log::debug!(
+                "Replay body has {} at offset {}, not parsing further",
+                e,
+                self.unparsed_offset + pos
+            );
+            self.malformed = true;
+            return;
+        }
+        unparsed.drain(..pos);
+        self.unparsed = unparsed;
+        self.unparsed_offset += pos;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::get_file;
+    use faf_replay_parser::scfa::parser::parse_body_ticks;
+
+    fn advances_in_chunks(body: &[u8], chunk_size: usize) -> Vec<TickPoint> {
+        let mut parser = TickParser::new();
+        let mut points = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            parser.add_data(chunk, |p| points.push(p));
+        }
+        assert_eq!(parser.unparsed_offset + parser.unparsed.len(), body.len());
+        points
+    }
+
+    #[test]
+    fn test_tick_parser_finds_advances() {
+        let body = get_file("example_body");
+        let points = advances_in_chunks(&body, body.len());
+        assert_eq!(points.last().unwrap().tick, parse_body_ticks(&mut &body[..]).unwrap());
+        for point in points.iter() {
+            assert_eq!(parse_body_ticks(&mut &body[..point.offset]).unwrap(), point.tick);
+        }
+        assert!(points.iter().any(|p| p.source != 0));
+    }
+
+    #[test]
+    fn test_tick_parser_is_the_same_for_any_chunking() {
+        let body = get_file("example_body");
+        let whole = advances_in_chunks(&body, body.len());
+        for chunk_size in [1, 2, 7, 1000] {
+            assert_eq!(advances_in_chunks(&body, chunk_size), whole);
+        }
+    }
+
+    #[test]
+    fn test_tick_parser_stops_at_malformed_data() {
+        let body = get_file("example_body");
+        let last_good = advances_in_chunks(&body, body.len())[500];
+        let cut = last_good.offset;
+        let mut parser = TickParser::new();
+        let mut points = 0;
+        parser.add_data(&body[..cut], |_| points += 1);
+        parser.add_data(&[0xFF, 0x03, 0x00], |_| points += 1);
+        parser.add_data(&body[cut..], |_| points += 1);
+        assert!(parser.malformed);
+        assert_eq!(points, 501);
+        assert_eq!(parser.tick, last_good.tick);
+    }
+
+    #[test]
+    fn test_tick_parser_rejects_huge_advances() {
+        let mut parser = TickParser::new();
+        let mut points = Vec::new();
+        let advance = |ticks: u32| [&[0x00, 0x07, 0x00][..], &ticks.to_le_bytes()].concat();
+        parser.add_data(&advance(1), |p| points.push(p.tick));
+        parser.add_data(&advance(MAX_ADVANCE_TICKS), |p| points.push(p.tick));
+        parser.add_data(&advance(u32::MAX), |p| points.push(p.tick));
+        parser.add_data(&advance(1), |p| points.push(p.tick));
+        assert!(parser.malformed);
+        assert_eq!(points, vec![1, MAX_ADVANCE_TICKS + 1]);
+    }
+}
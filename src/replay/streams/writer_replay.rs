@@ -1,18 +1,22 @@
-use std::{cell::RefCell, io::Write, rc::Rc};
+use std::{cell::RefCell, collections::VecDeque, io::Write, rc::Rc};
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     error::ConnResult, server::connection::Connection, util::buf_deque::BufDeque, util::buf_traits::DiscontiguousBuf,
 };
 
-use super::ReplayHeader;
+use super::{ReplayHeader, TickParser, TickPoint};
 
 pub struct WriterReplay {
     header: Option<ReplayHeader>,
     data: BufDeque,
     delayed_data_len: usize,
     finished: bool,
+    // Set if we follow game ticks. Data can be discarded right after it arrives, so we parse it
+    // then and keep tick advances until someone takes them.
+    tick_parser: Option<TickParser>,
+    tick_points: VecDeque<TickPoint>,
 }
 
 impl WriterReplay {
@@ -22,19 +26,38 @@ impl WriterReplay {
             data: BufDeque::new(),
             delayed_data_len: 0,
             finished: false,
+            tick_parser: None,
+            tick_points: VecDeque::new(),
         }
     }
 
+    pub fn track_ticks(&mut self) {
+        debug_assert!(self.data.len() == 0);
+        self.tick_parser = Some(TickParser::new());
+    }
+
+    pub fn take_tick_points(&mut self) -> VecDeque<TickPoint> {
+        std::mem::take(&mut self.tick_points)
+    }
+
     pub fn add_header(&mut self, h: ReplayHeader) {
         self.header = Some(h);
     }
 
+    pub fn get_header(&self) -> Option<&ReplayHeader> {
+        self.header.as_ref()
+    }
+
     pub fn take_header(&mut self) -> ReplayHeader {
         std::mem::replace(&mut self.header, None).expect("Cannot take header")
     }
 
     pub fn add_data(&mut self, buf: &[u8]) {
         self.data.write_all(buf).unwrap();
+        if let Some(parser) = self.tick_parser.as_mut() {
+            let points = &mut self.tick_points;
+            parser.add_data(buf, |p| points.push_back(p));
+        }
     }
 
     pub fn get_data(&self) -> &impl DiscontiguousBuf {
@@ -75,7 +98,8 @@ pub async fn read_header(me: WReplayRef, c: &mut Connection) -> ConnResult<()> {
     Ok(())
 }
 
-pub async fn read_data(me: WReplayRef, c: &mut Connection) -> ConnResult<()> {
+// Everything read is also copied to copy_to.
+pub async fn read_data(me: WReplayRef, c: &mut Connection, copy_to: &mut (dyn AsyncWrite + Unpin)) -> ConnResult<()> {
     let mut buf: Box<[u8]> = Box::new([0; 4096]);
     loop {
         let read = c.read(&mut *buf).await?;
@@ -83,6 +107,7 @@ pub async fn read_data(me: WReplayRef, c: &mut Connection) -> ConnResult<()> {
             break;
         }
         me.borrow_mut().add_data(&buf[0..read]);
+        copy_to.write_all(&buf[0..read]).await?;
     }
     Ok(())
 }
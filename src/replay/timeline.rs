@@ -0,0 +1,133 @@
+use std::{cell::RefCell, rc::Rc};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use super::receive::divergence::DivergenceRecord;
+
+// A record of what happened to a replay while it was running, saved next to the replay. Useful
+// for figuring out after the fact why a replay ended up short or broken.
+//
+// Writers are identified by the ID the merge strategy gave them.
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    WriterConnected { writer: u64, name: String },
+    WriterDisconnected { writer: u64, name: String, data_len: usize },
+    ReaderConnected { name: String },
+    ReaderDisconnected { name: String },
+    HeaderReceived { writer: u64 },
+    WriterDiverged(DivergenceRecord),
+    QuorumReached { canonical_len: usize },
+    StalemateEntered { canonical_len: usize },
+    ForcedTimeout,
+    MergeFinished { header_len: usize, data_len: usize },
+}
+
+#[derive(Serialize)]
+struct TimelineEntry {
+    time_s: f64, // Since replay start.
+    #[serde(flatten)]
+    event: TimelineEvent,
+}
+
+#[derive(Serialize)]
+struct TimelineEntries<'a> {
+    events: &'a [TimelineEntry],
+}
+
+// Cheap to clone, all clones record to the same timeline.
+#[derive(Clone)]
+pub struct Timeline {
+    start: Instant,
+    events: Rc<RefCell<Vec<TimelineEntry>>>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn record(&self, event: TimelineEvent) {
+        let time_s = self.start.elapsed().as_secs_f64();
+        self.events.borrow_mut().push(TimelineEntry { time_s, event });
+    }
+
+    pub fn divergences(&self) -> Vec<DivergenceRecord> {
+        self.events
+            .borrow()
+            .iter()
+            .filter_map(|e| match &e.event {
+                TimelineEvent::WriterDiverged(r) => Some(r.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn set_divergence_tick(&self, writer: u64, offset: usize, tick: u32) {
+        for e in self.events.borrow_mut().iter_mut() {
+            match &mut e.event {
+                TimelineEvent::WriterDiverged(r) if r.writer == writer && r.offset == Some(offset) => {
+                    r.tick = Some(tick);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    pub fn writer_count(&self) -> usize {
+        self.events
+            .borrow()
+            .iter()
+            .filter(|e| matches!(e.event, TimelineEvent::WriterConnected { .. }))
+            .count()
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&TimelineEntries {
+            events: &self.events.borrow(),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn events(&self) -> Vec<TimelineEvent> {
+        self.events.borrow().iter().map(|e| e.event.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timeline_json() {
+        tokio::time::pause();
+        let timeline = Timeline::new();
+        timeline.record(TimelineEvent::WriterConnected {
+            writer: 0,
+            name: "foo".into(),
+        });
+        tokio::time::advance(std::time::Duration::from_millis(1500)).await;
+        timeline.clone().record(TimelineEvent::ForcedTimeout);
+
+        let json: serde_json::Value = serde_json::from_slice(&timeline.to_json().unwrap()).unwrap();
+        let events = json["events"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "writer_connected");
+        assert_eq!(events[0]["writer"], 0);
+        assert_eq!(events[0]["name"], "foo");
+        assert_eq!(events[0]["time_s"], 0.0);
+        assert_eq!(events[1]["event"], "forced_timeout");
+        assert!(events[1]["time_s"].as_f64().unwrap() >= 1.5);
+    }
+}
@@ -1,5 +1,6 @@
 use rand::Rng;
 use std::fmt::Display;
+use std::net::SocketAddr;
 
 use crate::{
     accept::header::{ConnectionHeader, ConnectionType},
@@ -21,14 +22,18 @@ pub struct Connection {
     writer: WriterType,
     header: Option<ConnectionHeader>,
     id: String,
+    peer_addr: Option<SocketAddr>,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
         let (r, w) = stream.into_split();
         let reader = Box::new(BufReader::new(r));
         let writer = Box::new(w);
-        Self::new_from(reader, writer)
+        let mut s = Self::new_from(reader, writer);
+        s.peer_addr = peer_addr;
+        s
     }
 
     // Used for tests. Connection is just a wrapper for a few things, so I think it's justified.
@@ -42,6 +47,7 @@ impl Connection {
             writer,
             header: None,
             id,
+            peer_addr: None,
         };
         s.set_metric();
         log::debug!("New {}", s);
@@ -75,6 +81,16 @@ impl Connection {
         self.header.clone().unwrap()
     }
 
+    // Used when we learn the real peer address from a proxy.
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        log::debug!("{} is proxied for {}", self, addr);
+        self.peer_addr = Some(addr);
+    }
+
+    pub fn get_peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
     fn get_buf_reader(&mut self) -> &mut dyn AsyncBufRead {
         &mut *self.reader
     }
@@ -85,12 +101,15 @@ impl Connection {
 
 impl Display for Connection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection {}", self.id)?;
+        if let Some(addr) = &self.peer_addr {
+            write!(f, " from {}", addr)?;
+        }
         match &self.header {
-            None => write!(f, "Connection {}", self.id),
+            None => Ok(()),
             Some(h) => write!(
                 f,
-                "Connection {}, {} '{}' for replay {}",
-                self.id,
+                ", {} '{}' for replay {}",
                 match h.type_ {
                     ConnectionType::Reader => "reader",
                     ConnectionType::Writer => "writer",
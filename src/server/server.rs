@@ -1,11 +1,18 @@
+use std::sync::Arc;
+
 use super::connection::Connection;
 use crate::accept::header::read_initial_header;
+use crate::accept::token::TokenVerifier;
+use crate::accept::writer_auth::WriterAuthenticator;
 use crate::database::database::Database;
+use crate::error::{bad_data, ConnResult};
 use crate::replay::runner::ReplayRunner;
-use crate::util::timeout::cancellable;
+use crate::util::timeout::{cancellable, timeout};
 use crate::{accept::producer::tcp_listen, config::Settings, replay::save::InnerReplaySaver};
 use crate::{metrics, replay::save::SavedReplayDirectory};
 use futures::{stream::StreamExt, Stream};
+use tokio::join;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
 struct Server<C: Stream<Item = Connection>> {
@@ -34,24 +41,57 @@ impl<C: Stream<Item = Connection>> Server<C> {
     }
 
     async fn run(self) {
-        let saver = InnerReplaySaver::new(self.db, self.dir, &self.config);
-        let runner = ReplayRunner::new(self.config.clone(), self.shutdown_token.clone(), saver);
-
+        let db = Arc::new(self.db);
+        let saver = InnerReplaySaver::new(db.clone(), self.dir, &self.config);
+        let runner = ReplayRunner::new(self.config.clone(), self.shutdown_token.clone(), saver.clone());
+        let writer_auth = self
+            .config
+            .server
+            .authenticate_writers
+            .then(|| WriterAuthenticator::new(db));
+
+        saver.finalize_orphaned_live_spools().await;
+
+        let tokens = TokenVerifier::from_config(&self.config.server);
         let initial_timeout = self.config.server.connection_accept_timeout_s;
+        let proxy_protocol = self.config.server.proxy_protocol;
         let accept_connections = self.connections.for_each_concurrent(None, |mut c| async {
-            match read_initial_header(&mut c, initial_timeout).await {
+            let accept = async {
+                let started = Instant::now();
+                read_initial_header(&mut c, initial_timeout, proxy_protocol, &tokens).await?;
+                if let Some(auth) = &writer_auth {
+                    // Authenticating shares the accept timeout, so a slow database can't keep connections around.
+                    let time_left = initial_timeout.saturating_sub(started.elapsed());
+                    match timeout(auth.authenticate(&c.get_header()), time_left).await {
+                        Some(res) => res?,
+                        None => return Err(bad_data("Timed out while accepting connection")),
+                    }
+                }
+                ConnResult::Ok(())
+            };
+            match accept.await {
                 Err(e) => {
-                    log::info!("Could not accept connection: {}", e);
+                    log::info!("Could not accept {}: {}", c, e);
                     metrics::inc_served_conns::<()>(&Err(e));
                 }
                 Ok(_) => runner.dispatch_connection(c).await,
             }
         });
 
-        match cancellable(accept_connections, &self.shutdown_token).await {
-            Some(_) => log::warn!("Server stopped accepting connections for some reason!"),
-            None => log::info!("Server shutting down"),
-        }
+        // Stop retrying pending replays once we stop accepting connections for any reason.
+        let shutdown_token = self.shutdown_token;
+        let stop_retrying = shutdown_token.child_token();
+        let accept_until_shutdown = async {
+            match cancellable(accept_connections, &shutdown_token).await {
+                Some(_) => log::warn!("Server stopped accepting connections for some reason!"),
+                None => log::info!("Server shutting down"),
+            }
+            stop_retrying.cancel();
+        };
+        join!(
+            accept_until_shutdown,
+            saver.retry_pending_replays(stop_retrying.clone())
+        );
 
         runner.shutdown();
     }
@@ -63,7 +103,13 @@ async fn server_with_real_deps(
 ) -> Server<impl Stream<Item = Connection>> {
     let connections = tcp_listen(format!("0.0.0.0:{}", config.server.port)).await;
     let db = Database::new(&config.database);
-    let dir = SavedReplayDirectory::new(config.storage.vault_path.as_ref());
+    let dir = SavedReplayDirectory::new(
+        config.storage.vault_path.as_ref(),
+        config.storage.existing_replay_policy,
+    );
+    if let Err(e) = dir.remove_stale_temp_files().await {
+        log::warn!("Failed to remove stale temporary replay files: {}", e);
+    }
     Server::new(config, shutdown_token, connections, db, dir)
 }
 
@@ -74,7 +120,7 @@ pub async fn run_server(config: Settings, shutdown_token: CancellationToken) {
 #[cfg(test)]
 mod test {
     use crate::{
-        config::test::default_config,
+        config::{test::default_config, ExistingReplayPolicy},
         database::database::test::mock_database,
         server::connection::test::test_connection,
         util::test::{get_file, setup_logging, sleep_s},
@@ -103,7 +149,7 @@ mod test {
     fn temp_replay_dir() -> (TempDir, SavedReplayDirectory) {
         let tmp_dir = tempdir().unwrap();
         let dir_str = tmp_dir.path().to_str().unwrap().into();
-        let dir = SavedReplayDirectory::new(dir_str);
+        let dir = SavedReplayDirectory::new(dir_str, ExistingReplayPolicy::Keep);
         (tmp_dir, dir)
     }
 
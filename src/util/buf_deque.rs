@@ -3,7 +3,7 @@ use std::{cmp::min, collections::VecDeque};
 
 use super::buf_traits::DiscontiguousBuf;
 
-const CHUNK_SIZE: usize = 4096;
+pub const CHUNK_SIZE: usize = 4096;
 
 /* A deque of buffers that acts as a "sliding window" of replay data. We can append data to the
  * front and discard it from the back. We don't use a vector to avoid reallocating and moving
@@ -25,6 +25,10 @@ impl BufDeque {
         }
     }
 
+    pub fn memory_used(&self) -> usize {
+        self.chunks.len() * CHUNK_SIZE
+    }
+
     fn window_start(&self) -> usize {
         self.discarded_chunks * CHUNK_SIZE
     }